@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{self, doc, Bson, Document},
+    Database,
+};
+
+use crate::{fences, geo, telemetry, trips};
+
+// the timezone each vehicle's days are counted in. Vehicles that haven't picked one use the default
+#[derive(Clone)]
+pub struct Timezones {
+    known: Arc<RwLock<HashMap<String, Tz>>>,
+    default: Tz,
+}
+
+impl Timezones {
+    pub fn new(default: Tz) -> Timezones {
+        Timezones {
+            known: Arc::new(RwLock::new(HashMap::new())),
+            default,
+        }
+    }
+
+    // IANA names that don't parse fall back to the default too
+    pub fn resolve(&self, name: Option<&str>) -> Tz {
+        name.and_then(|name| name.parse().ok()).unwrap_or(self.default)
+    }
+
+    pub fn get(&self, vid: &str) -> Tz {
+        self.known.read().unwrap().get(vid).copied().unwrap_or(self.default)
+    }
+
+    pub fn set(&self, vid: &str, name: Option<&str>) {
+        let timezone = self.resolve(name);
+        self.known.write().unwrap().insert(vid.to_string(), timezone);
+    }
+}
+
+// the local day a moment falls on
+pub fn day(at: &DateTime<Utc>, timezone: Tz) -> NaiveDate {
+    at.with_timezone(&timezone).date_naive()
+}
+
+// daily logs are keyed on the moment their day starts, stored in UTC. Zones that skip midnight
+// when DST starts begin that day at the first moment that does exist
+pub fn midnight(day: NaiveDate, timezone: Tz) -> bson::DateTime {
+    let start = (0..24)
+        .filter_map(|hour| timezone.from_local_datetime(&day.and_time(NaiveTime::from_hms_opt(hour, 0, 0)?)).earliest())
+        .next()
+        .unwrap_or_else(|| timezone.from_utc_datetime(&day.and_time(NaiveTime::MIN)));
+    bson::DateTime::from_millis(start.timestamp_millis())
+}
+
+// the days from `start` through `end`, as the moments they start & stop. Either side can be left
+// open. `from` is included, `until` isn't
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub from: Option<bson::DateTime>,
+    pub until: Option<bson::DateTime>,
+}
+
+impl Span {
+    pub fn new(start: Option<NaiveDate>, end: Option<NaiveDate>, timezone: Tz) -> Span {
+        Span {
+            from: start.map(|start| midnight(start, timezone)),
+            until: end.and_then(|end| end.checked_add_days(Days::new(1))).map(|end| midnight(end, timezone)),
+        }
+    }
+
+    // the span as a MongoDB filter on `field`
+    pub(crate) fn filter(&self, field: &str) -> Document {
+        let mut range = Document::new();
+        if let Some(from) = self.from {
+            range.insert("$gte", from);
+        }
+        if let Some(until) = self.until {
+            range.insert("$lt", until);
+        }
+        match range.is_empty() {
+            true => Document::new(),
+            false => doc! {field: range},
+        }
+    }
+
+    pub(crate) fn contains(&self, at: &bson::DateTime) -> bool {
+        self.from.is_none_or(|from| *at >= from) && self.until.is_none_or(|until| *at < until)
+    }
+}
+
+// times of day are stored as full dates & only shown in the vehicle's timezone. Days logged before
+// that still have the time as text, those are passed along as they are
+pub fn clock(value: &Bson, timezone: Tz) -> String {
+    match value {
+        Bson::DateTime(at) => match Utc.timestamp_millis_opt(at.timestamp_millis()).single() {
+            Some(at) => at.with_timezone(&timezone).format("%I:%M %p").to_string(),
+            None => String::new(),
+        },
+        Bson::String(text) => text.clone(),
+        _ => String::new(),
+    }
+}
+
+// daily logs used to be keyed on "D-M-YYYY" strings in the server's local time. Those are turned
+// into dates, taking them to be in the default timezone
+pub async fn migrate(database: &Database, timezone: Tz) -> mongodb::error::Result<()> {
+    for name in database.list_collection_names(None).await? {
+        let shared = [telemetry::COLLECTION, trips::COLLECTION, geo::ROUTES, geo::LOCATIONS, fences::COLLECTION, fences::EVENTS];
+        if shared.contains(&name.as_str()) || name.starts_with("system.") {
+            continue;
+        }
+        let collection = database.collection::<Document>(&name);
+        let mut cursor = collection.find(doc! {"date": {"$type": "string"}}, None).await?;
+        while let Some(log) = cursor.next().await {
+            let log = log?;
+            let parsed = log.get_str("date").ok().and_then(|date| NaiveDate::parse_from_str(date, "%d-%m-%Y").ok());
+            match parsed {
+                Some(date) => {
+                    collection
+                        .update_one(doc! {"_id": log.get("_id")}, doc! {"$set": {"date": midnight(date, timezone)}}, None)
+                        .await?;
+                }
+                None => eprintln!("Skipping the daily log {:?} in {}, its date can't be read", log.get("_id"), name),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_start_at_local_midnight() {
+        let day = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+        assert_eq!(midnight(day, chrono_tz::Asia::Kolkata).try_to_rfc3339_string().unwrap(), "2023-02-28T18:30:00Z");
+        assert_eq!(midnight(day, chrono_tz::UTC).try_to_rfc3339_string().unwrap(), "2023-03-01T00:00:00Z");
+        // Santiago skips from midnight to 1 AM when DST starts
+        let skipped = NaiveDate::from_ymd_opt(2022, 9, 11).unwrap();
+        assert_eq!(midnight(skipped, chrono_tz::America::Santiago).try_to_rfc3339_string().unwrap(), "2022-09-11T04:00:00Z");
+    }
+
+    #[test]
+    fn late_evenings_belong_to_the_local_day() {
+        let at = Utc.with_ymd_and_hms(2022, 12, 31, 20, 0, 0).unwrap();
+        assert_eq!(day(&at, chrono_tz::Asia::Kolkata), NaiveDate::from_ymd_opt(2023, 1, 1).unwrap());
+        assert_eq!(day(&at, chrono_tz::America::New_York), NaiveDate::from_ymd_opt(2022, 12, 31).unwrap());
+    }
+}
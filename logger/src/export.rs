@@ -0,0 +1,257 @@
+use std::str::FromStr;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::Result,
+    options::FindOptions,
+    Database,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{days::{self, Span}, telemetry, Conditions, Log};
+
+// rows that are already in from the database go out together, up to this many at a time
+const CHUNK: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+        }
+    }
+
+    // CSV files start with the names of their columns
+    fn header(&self, columns: &[&str]) -> Option<String> {
+        match self {
+            Format::Csv => Some(columns.join(",") + "\n"),
+            Format::Ndjson => None,
+        }
+    }
+
+    pub(crate) fn line(&self, columns: &[&str], values: Vec<Value>) -> String {
+        match self {
+            Format::Csv => values.iter().map(cell).collect::<Vec<_>>().join(",") + "\n",
+            Format::Ndjson => {
+                let row: Map<String, Value> = columns.iter().map(|column| column.to_string()).zip(values).collect();
+                Value::Object(row).to_string() + "\n"
+            }
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(()),
+        }
+    }
+}
+
+// what gets exported, a row per daily log or a row per raw reading
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Data {
+    Daily,
+    Telemetry,
+}
+
+impl Data {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Data::Daily => "daily",
+            Data::Telemetry => "telemetry",
+        }
+    }
+
+    pub(crate) fn columns(&self) -> &'static [&'static str] {
+        match self {
+            Data::Daily => &[
+                "date",
+                "distance",
+                "average_speed",
+                "max_speed",
+                "max_speed_at",
+                "stress_count",
+                "first_odometer",
+                "last_odometer",
+                "message_count",
+                "min_temp",
+                "average_temp",
+                "max_temp",
+                "fuel_consumed",
+                "refuels",
+                "refuelled",
+                "high_rpm_seconds",
+                "overheat_seconds",
+            ],
+            Data::Telemetry => &["ts", "gear", "rpm", "speed", "latitude", "longitude", "temp", "fuel", "odo", "stressed"],
+        }
+    }
+}
+
+impl FromStr for Data {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "daily" => Ok(Data::Daily),
+            "telemetry" => Ok(Data::Telemetry),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct Export {
+    pub vid: String,
+    pub data: Data,
+    pub format: Format,
+    // both days are included & counted in `timezone`. Leaving one out doesn't limit that side
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    pub timezone: Tz,
+}
+
+impl Export {
+    pub(crate) fn span(&self) -> Span {
+        Span::new(self.start, self.end, self.timezone)
+    }
+}
+
+// the export in chunks of text, header first. An error ends it
+pub type Rows = BoxStream<'static, std::result::Result<String, String>>;
+
+// quoted when it has to be, per RFC 4180
+fn cell(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    match text.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text,
+    }
+}
+
+fn rfc3339(value: &Bson) -> Value {
+    match value {
+        Bson::DateTime(at) => json!(at.try_to_rfc3339_string().ok()),
+        // days logged before hit times were dates only have the time as text
+        Bson::String(text) => json!(text),
+        _ => Value::Null,
+    }
+}
+
+pub(crate) fn daily(log: &Log, timezone: Tz) -> Vec<Value> {
+    let mut conditions = Conditions::default();
+    conditions.add(log);
+    let temperature = conditions.temperature();
+    let date = Utc.timestamp_millis_opt(log.date.timestamp_millis()).single().map(|date| days::day(&date, timezone));
+    vec![
+        json!(date.map(|date| date.format("%Y-%m-%d").to_string())),
+        json!(log.distance),
+        json!(log.average_speed),
+        json!(log.max_speed.0),
+        rfc3339(&log.max_speed.1),
+        json!(log.stress),
+        json!(log.first_odometer),
+        json!(log.last_odometer),
+        json!(log.message_count),
+        json!(log.min_temp),
+        temperature["average"].clone(),
+        json!(log.max_temp),
+        json!(log.fuel_consumed),
+        json!(log.refuels),
+        json!(log.refuelled),
+        json!(log.high_rpm_ms / 1000),
+        json!(log.overheat_ms / 1000),
+    ]
+}
+
+// readings are passed along as they were stored, coordinates included
+pub(crate) fn reading(point: &Document) -> Vec<Value> {
+    let number = |value: Option<&Bson>| match value {
+        Some(Bson::Int32(value)) => json!(value),
+        Some(Bson::Int64(value)) => json!(value),
+        Some(Bson::Double(value)) => json!(value),
+        _ => Value::Null,
+    };
+    let location = point.get_document("location").ok();
+    vec![
+        point.get("ts").map(rfc3339).unwrap_or_default(),
+        number(point.get("gear")),
+        number(point.get("rpm")),
+        number(point.get("speed")),
+        number(location.and_then(|location| location.get("latitude"))),
+        number(location.and_then(|location| location.get("longitude"))),
+        number(point.get("temp")),
+        number(point.get("fuel")),
+        number(point.get("odo")),
+        json!(point.get_bool("stressed").unwrap_or(false)),
+    ]
+}
+
+// the cursor is read as the rows are sent, so nothing past the chunk being sent is held in memory
+pub async fn rows(database: &Database, export: Export) -> Result<Rows> {
+    let (columns, format, timezone) = (export.data.columns(), export.format, export.timezone);
+    let lines: BoxStream<'static, Result<String>> = match export.data {
+        Data::Daily => {
+            let options = FindOptions::builder().sort(doc! {"date": 1}).build();
+            let cursor = database.collection::<Log>(&export.vid).find(export.span().filter("date"), options).await?;
+            cursor.map(move |log| log.map(|log| format.line(columns, daily(&log, timezone)))).boxed()
+        }
+        Data::Telemetry => {
+            let mut filter = export.span().filter("ts");
+            filter.insert("vid", &export.vid);
+            let options = FindOptions::builder().sort(doc! {"ts": 1}).build();
+            let cursor = database.collection::<Document>(telemetry::COLLECTION).find(filter, options).await?;
+            cursor.map(move |point| point.map(|point| format.line(columns, reading(&point)))).boxed()
+        }
+    };
+    Ok(stream(format, export.data, lines.map(|line| line.map_err(|e| format!("{:?}", e))).boxed()))
+}
+
+// puts the header in front of the lines & sends them in chunks
+pub(crate) fn stream(format: Format, data: Data, lines: BoxStream<'static, std::result::Result<String, String>>) -> Rows {
+    let chunks = lines
+        .ready_chunks(CHUNK)
+        .map(|lines| lines.into_iter().collect::<std::result::Result<String, String>>());
+    stream::iter(format.header(data.columns()).map(Ok)).chain(chunks).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_are_quoted_when_they_have_to_be() {
+        let columns = ["a", "b", "c", "d"];
+        let values = vec![json!("plain"), json!("7,5"), json!("say \"hi\""), Value::Null];
+        assert_eq!(Format::Csv.line(&columns, values.clone()), "plain,\"7,5\",\"say \"\"hi\"\"\",\n");
+        let row: Value = serde_json::from_str(&Format::Ndjson.line(&columns, values)).unwrap();
+        assert_eq!(row["b"], "7,5");
+        assert_eq!(row["d"], Value::Null);
+        assert_eq!(Format::Csv.header(&columns).unwrap(), "a,b,c,d\n");
+        assert!(Format::Ndjson.header(&columns).is_none());
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    error::Result,
+    options::FindOptions,
+    Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{geo, Location};
+
+pub const COLLECTION: &str = "geofences";
+// every time a vehicle entered or left one of its geofences
+pub const EVENTS: &str = "geofence_events";
+
+// the area a geofence covers
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Shape {
+    // radius is in meters
+    Circle { center: Location, radius: f64 },
+    // corners in order, the last one connects back to the first
+    Polygon { corners: Vec<Location> },
+}
+
+impl Shape {
+    pub fn contains(&self, location: &Location) -> bool {
+        match self {
+            Shape::Circle { center, radius } => geo::distance(center, location) <= *radius,
+            // casts a ray east from the location & counts the edges it crosses. Corners are taken as
+            // points on a flat map, which holds up for fences that don't span the antimeridian
+            Shape::Polygon { corners } => {
+                let mut inside = false;
+                for (i, from) in corners.iter().enumerate() {
+                    let to = &corners[(i + 1) % corners.len()];
+                    if (from.latitude > location.latitude) != (to.latitude > location.latitude) {
+                        let crossing = from.longitude
+                            + (location.latitude - from.latitude) / (to.latitude - from.latitude) * (to.longitude - from.longitude);
+                        if location.longitude < crossing {
+                            inside = !inside;
+                        }
+                    }
+                }
+                inside
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Fence {
+    pub(crate) _id: ObjectId,
+    pub(crate) vid: String,
+    name: String,
+    shape: Shape,
+}
+
+impl Fence {
+    pub fn new(vid: &str, name: String, shape: Shape) -> Fence {
+        Fence {
+            _id: ObjectId::new(),
+            vid: vid.to_string(),
+            name,
+            shape,
+        }
+    }
+
+    pub(crate) fn json(&self) -> Value {
+        json!({
+            "id": self._id.to_hex(),
+            "vid": self.vid,
+            "name": self.name,
+            "shape": self.shape,
+        })
+    }
+}
+
+// a vehicle entering or leaving one of its geofences
+#[derive(Clone)]
+pub struct Crossing {
+    fence: ObjectId,
+    name: String,
+    pub(crate) vid: String,
+    entered: bool,
+    pub(crate) at: DateTime,
+    location: Location,
+}
+
+impl Crossing {
+    fn transition(&self) -> &'static str {
+        match self.entered {
+            true => "enter",
+            false => "exit",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // false when the vehicle left the fence
+    pub fn entered(&self) -> bool {
+        self.entered
+    }
+
+    pub fn json(&self) -> Value {
+        json!({
+            "id": self.fence.to_hex(),
+            "name": self.name,
+            "vid": self.vid,
+            "transition": self.transition(),
+            "at": self.at.try_to_rfc3339_string().ok(),
+            "location": {"latitude": self.location.latitude, "longitude": self.location.longitude},
+        })
+    }
+
+    fn document(&self) -> Document {
+        doc! {
+            "vid": &self.vid,
+            "fence": self.fence,
+            "name": &self.name,
+            "transition": self.transition(),
+            "at": self.at,
+            "location": geo::point(&self.location),
+        }
+    }
+}
+
+// a fence & whether the vehicle was inside it the last time it said where it was
+struct Watch {
+    fence: Fence,
+    inside: Option<bool>,
+}
+
+// the geofences of every vehicle that's online. Checked against each location a vehicle
+// broadcasts, so they're kept in memory while it's connected
+#[derive(Clone, Default)]
+pub struct Geofences {
+    watched: Arc<RwLock<HashMap<String, Vec<Watch>>>>,
+}
+
+impl Geofences {
+    // `last` is where the vehicle was before it connected, so leaving a fence while it was offline
+    // still shows up with its first reading
+    pub fn watch(&self, vid: &str, fences: Vec<Fence>, last: Option<&Location>) {
+        let watches = fences
+            .into_iter()
+            .map(|fence| Watch {
+                inside: last.map(|last| fence.shape.contains(last)),
+                fence,
+            })
+            .collect();
+        self.watched.write().unwrap().insert(vid.to_string(), watches);
+    }
+
+    pub fn forget(&self, vid: &str) {
+        self.watched.write().unwrap().remove(vid);
+    }
+
+    // fences added while the vehicle is online start being checked with its next reading
+    pub fn add(&self, fence: Fence) {
+        if let Some(watches) = self.watched.write().unwrap().get_mut(&fence.vid) {
+            watches.push(Watch { fence, inside: None });
+        }
+    }
+
+    pub fn remove(&self, vid: &str, id: &ObjectId) {
+        if let Some(watches) = self.watched.write().unwrap().get_mut(vid) {
+            watches.retain(|watch| watch.fence._id != *id);
+        }
+    }
+
+    // the fences the vehicle entered or left by getting to `location`. The first location checked
+    // against a fence only says which side of it the vehicle is on
+    pub fn check(&self, vid: &str, location: &Location, at: DateTime) -> Vec<Crossing> {
+        let mut watched = self.watched.write().unwrap();
+        let watches = match watched.get_mut(vid) {
+            Some(watches) => watches,
+            None => return Vec::new(),
+        };
+        let mut crossings = Vec::new();
+        for watch in watches {
+            let inside = watch.fence.shape.contains(location);
+            if watch.inside.is_some_and(|was| was != inside) {
+                crossings.push(Crossing {
+                    fence: watch.fence._id,
+                    name: watch.fence.name.clone(),
+                    vid: vid.to_string(),
+                    entered: inside,
+                    at,
+                    location: location.clone(),
+                });
+            }
+            watch.inside = Some(inside);
+        }
+        crossings
+    }
+}
+
+pub async fn prepare(database: &Database) -> Result<()> {
+    database
+        .collection::<Document>(COLLECTION)
+        .create_index(IndexModel::builder().keys(doc! {"vid": 1}).build(), None)
+        .await?;
+    database
+        .collection::<Document>(EVENTS)
+        .create_index(IndexModel::builder().keys(doc! {"vid": 1, "at": -1}).build(), None)
+        .await?;
+    Ok(())
+}
+
+pub async fn load(database: &Database, vid: &str) -> Result<Vec<Fence>> {
+    let mut cursor = database.collection::<Fence>(COLLECTION).find(doc! {"vid": vid}, None).await?;
+    let mut fences = Vec::new();
+    while let Some(fence) = cursor.next().await {
+        fences.push(fence?);
+    }
+    Ok(fences)
+}
+
+pub async fn insert(database: &Database, fence: &Fence) -> Result<()> {
+    database.collection::<Fence>(COLLECTION).insert_one(fence, None).await?;
+    Ok(())
+}
+
+// whether there was such a fence on the vehicle
+pub async fn delete(database: &Database, vid: &str, id: &ObjectId) -> Result<bool> {
+    let result = database.collection::<Fence>(COLLECTION).delete_one(doc! {"_id": id, "vid": vid}, None).await?;
+    Ok(result.deleted_count > 0)
+}
+
+pub(crate) fn listing(vid: &str, fences: &[Fence]) -> Value {
+    json!({
+        "vid": vid,
+        "count": fences.len(),
+        "geofences": fences.iter().map(Fence::json).collect::<Vec<_>>(),
+    })
+}
+
+pub async fn record(database: &Database, crossings: &[Crossing]) -> Result<()> {
+    database
+        .collection::<Document>(EVENTS)
+        .insert_many(crossings.iter().map(Crossing::document), None)
+        .await?;
+    Ok(())
+}
+
+// a vehicle's crossings, latest first. `before` pages back through older ones
+pub async fn events(database: &Database, vid: &str, limit: i64, before: Option<i64>) -> Result<Value> {
+    let mut filter = doc! {"vid": vid};
+    if let Some(before) = before {
+        filter.insert("at", doc! {"$lt": DateTime::from_millis(before)});
+    }
+    let options = FindOptions::builder().sort(doc! {"at": -1}).limit(limit).build();
+    let mut cursor = database.collection::<Document>(EVENTS).find(filter, options).await?;
+    let mut events = Vec::new();
+    while let Some(event) = cursor.next().await {
+        let event = event?;
+        let coordinates = event.get_document("location").ok().and_then(|location| location.get_array("coordinates").ok());
+        let location = coordinates.and_then(|coordinates| {
+            Some(json!({"latitude": coordinates.get(1)?.as_f64()?, "longitude": coordinates.first()?.as_f64()?}))
+        });
+        events.push(json!({
+            "id": event.get_object_id("fence").map(|id| id.to_hex()).ok(),
+            "name": event.get_str("name").ok(),
+            "vid": vid,
+            "transition": event.get_str("transition").ok(),
+            "at": event.get_datetime("at").ok().and_then(|at| at.try_to_rfc3339_string().ok()),
+            "location": location,
+        }));
+    }
+    Ok(history(vid, events))
+}
+
+pub(crate) fn history(vid: &str, events: Vec<Value>) -> Value {
+    json!({
+        "vid": vid,
+        "count": events.len(),
+        "events": events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(latitude: f64, longitude: f64) -> Location {
+        Location { latitude, longitude }
+    }
+
+    #[test]
+    fn shapes_contain_what_they_cover() {
+        let circle = Shape::Circle { center: at(9.93, 76.26), radius: 500.0 };
+        // ~330 m & ~670 m north of the center
+        assert!(circle.contains(&at(9.933, 76.26)));
+        assert!(!circle.contains(&at(9.936, 76.26)));
+
+        // an L, so the notch between its arms is outside
+        let corners = vec![at(0.0, 0.0), at(0.0, 2.0), at(1.0, 2.0), at(1.0, 1.0), at(2.0, 1.0), at(2.0, 0.0)];
+        let polygon = Shape::Polygon { corners };
+        assert!(polygon.contains(&at(0.5, 1.5)));
+        assert!(polygon.contains(&at(1.5, 0.5)));
+        assert!(!polygon.contains(&at(1.5, 1.5)));
+        assert!(!polygon.contains(&at(-0.5, 0.5)));
+    }
+
+    #[test]
+    fn only_changing_sides_is_a_crossing() {
+        let geofences = Geofences::default();
+        let fence = Fence::new("vid", String::from("Home"), Shape::Circle { center: at(9.93, 76.26), radius: 500.0 });
+        let now = DateTime::now();
+        // unwatched vehicles are never checked
+        assert!(geofences.check("vid", &at(9.93, 76.26), now).is_empty());
+
+        geofences.watch("vid", vec![fence], Some(&at(9.93, 76.26)));
+        assert!(geofences.check("vid", &at(9.931, 76.26), now).is_empty());
+        let left = geofences.check("vid", &at(9.95, 76.26), now);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].json()["transition"], "exit");
+        assert!(geofences.check("vid", &at(9.96, 76.26), now).is_empty());
+        assert_eq!(geofences.check("vid", &at(9.93, 76.26), now)[0].json()["transition"], "enter");
+
+        // fences added on the fly only pick a side with the first reading
+        geofences.add(Fence::new("vid", String::from("Work"), Shape::Circle { center: at(9.95, 76.26), radius: 500.0 }));
+        assert!(geofences.check("vid", &at(9.931, 76.26), now).is_empty());
+        let crossings = geofences.check("vid", &at(9.95, 76.26), now);
+        assert_eq!(crossings.iter().map(Crossing::name).collect::<Vec<_>>(), vec!["Home", "Work"]);
+    }
+}
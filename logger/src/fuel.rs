@@ -0,0 +1,85 @@
+// how far the fuel level has to climb, in whatever unit the vehicle reports it in, before it
+// counts as a refuel. Smaller rises are sensor noise & fuel sloshing around the tank
+pub const REFUEL_THRESHOLD: u32 = 5;
+
+// what a single fuel reading adds to the day it falls on
+#[derive(Default, Debug, PartialEq)]
+pub struct Reading {
+    // fuel used since the previous reading. Rises that aren't refuels count against it, so noise
+    // around a steady level evens out
+    pub consumed: i64,
+    pub refuelled: u32,
+    // this reading is the one that gave the refuel away
+    pub refuel: bool,
+}
+
+// follows a vehicle's fuel level from one reading to the next. Lives in its ingestion actor, so
+// the first reading after the vehicle reconnects starts over & whatever happened while it was
+// offline isn't counted either way
+#[derive(Default)]
+pub struct Gauge {
+    last: Option<u32>,
+    // lowest level since the last refuel ended, refuels are measured from here
+    low: u32,
+    filling: bool,
+}
+
+impl Gauge {
+    pub fn read(&mut self, level: u32) -> Reading {
+        let last = match self.last.replace(level) {
+            Some(last) => last,
+            None => {
+                self.low = level;
+                return Reading::default();
+            }
+        };
+        let mut reading = Reading {
+            consumed: last as i64 - level as i64,
+            ..Reading::default()
+        };
+        if self.filling {
+            match level > last {
+                true => reading.refuelled = level - last,
+                false => {
+                    self.filling = false;
+                    self.low = level;
+                }
+            }
+        } else {
+            self.low = self.low.min(level);
+            if level >= self.low + REFUEL_THRESHOLD {
+                // pumps fill a few units per reading, the rise up to here is part of the refuel too
+                reading.refuelled = level - self.low;
+                reading.refuel = true;
+                self.filling = true;
+            }
+        }
+        // whatever was put in wasn't used
+        reading.consumed += reading.refuelled as i64;
+        reading
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(levels: &[u32]) -> (i64, u32, u32) {
+        let mut gauge = Gauge::default();
+        levels.iter().map(|level| gauge.read(*level)).fold((0, 0, 0), |(consumed, refuelled, refuels), reading| {
+            (consumed + reading.consumed, refuelled + reading.refuelled, refuels + reading.refuel as u32)
+        })
+    }
+
+    #[test]
+    fn noise_evens_out() {
+        assert_eq!(read(&[50, 49, 50, 48, 49, 47]), (3, 0, 0));
+    }
+
+    #[test]
+    fn slow_and_sudden_refuels_count_once() {
+        // drives down to 20, fills up a couple of units at a time & drives on
+        assert_eq!(read(&[50, 35, 20, 22, 24, 26, 28, 40, 60, 60, 55]), (35, 40, 1));
+        assert_eq!(read(&[30, 25, 60, 58, 20, 45]), (45, 60, 2));
+    }
+}
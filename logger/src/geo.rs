@@ -0,0 +1,241 @@
+use chrono::Utc;
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    error::Result,
+    options::{FindOptions, IndexOptions, UpdateOptions},
+    Database, IndexModel,
+};
+use serde_json::{json, Value};
+
+use crate::{Location, Message};
+
+// every located reading taken during a trip, one document per reading
+pub const ROUTES: &str = "routes";
+// the last place each vehicle was seen at, kept around while it's offline
+pub const LOCATIONS: &str = "locations";
+// mean radius of the earth, in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// GeoJSON point for a location, as long as it's actually somewhere on earth
+pub(crate) fn point(location: &Location) -> Option<Document> {
+    let valid = (-90.0..=90.0).contains(&location.latitude) && (-180.0..=180.0).contains(&location.longitude);
+    valid.then(|| doc! {"type": "Point", "coordinates": [location.longitude, location.latitude]})
+}
+
+// great-circle distance in meters
+pub(crate) fn distance(from: &Location, to: &Location) -> f64 {
+    let (from_latitude, to_latitude) = (from.latitude.to_radians(), to.latitude.to_radians());
+    let half_chord = ((to_latitude - from_latitude) / 2.0).sin().powi(2)
+        + from_latitude.cos() * to_latitude.cos() * ((to.longitude - from.longitude).to_radians() / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * half_chord.sqrt().asin()
+}
+
+// a reading on a trip's route
+pub struct Waypoint {
+    pub(crate) trip: ObjectId,
+    vid: String,
+    ts: DateTime,
+    location: Document,
+}
+
+impl Waypoint {
+    pub fn new(trip: ObjectId, vid: &str, message: &Message, at: DateTime) -> Option<Waypoint> {
+        Some(Waypoint {
+            trip,
+            vid: vid.to_string(),
+            ts: at,
+            location: point(message.location.as_ref()?)?,
+        })
+    }
+}
+
+pub async fn prepare(database: &Database) -> Result<()> {
+    let routes = database.collection::<Document>(ROUTES);
+    routes.create_index(IndexModel::builder().keys(doc! {"trip": 1, "ts": 1}).build(), None).await?;
+    routes.create_index(IndexModel::builder().keys(doc! {"location": "2dsphere"}).build(), None).await?;
+    database
+        .collection::<Document>(LOCATIONS)
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"location": "2dsphere"})
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn record(database: &Database, waypoints: Vec<Waypoint>) -> Result<()> {
+    if waypoints.is_empty() {
+        return Ok(());
+    }
+    let documents = waypoints
+        .into_iter()
+        .map(|waypoint| doc! {"trip": waypoint.trip, "vid": waypoint.vid, "ts": waypoint.ts, "location": waypoint.location});
+    database.collection::<Document>(ROUTES).insert_many(documents, None).await?;
+    Ok(())
+}
+
+impl Waypoint {
+    // longitude first, like GeoJSON
+    pub(crate) fn coordinates(&self) -> Option<[f64; 2]> {
+        match self.location.get_array("coordinates").map(|coordinates| coordinates.as_slice()) {
+            Ok([longitude, latitude]) => Some([longitude.as_f64()?, latitude.as_f64()?]),
+            _ => None,
+        }
+    }
+}
+
+// the last valid location in the batch & when the vehicle was there
+pub(crate) fn latest(batch: &[(Message, chrono::DateTime<Utc>)]) -> Option<(Location, DateTime)> {
+    batch.iter().rev().find_map(|(message, at)| {
+        let location = message.location.as_ref().filter(|location| point(location).is_some())?;
+        Some((location.clone(), DateTime::from_millis(at.timestamp_millis())))
+    })
+}
+
+// keeps the last location in the batch, if it had any
+pub async fn remember(database: &Database, vid: &str, batch: &[(Message, chrono::DateTime<Utc>)]) -> Result<()> {
+    let (location, at) = match latest(batch).and_then(|(location, at)| Some((point(&location)?, at))) {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+    database
+        .collection::<Document>(LOCATIONS)
+        .update_one(
+            doc! {"_id": vid},
+            doc! {"$set": {"location": location, "at": at}},
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+// where the vehicle last was & when it was there
+pub(crate) async fn find(database: &Database, vid: &str) -> Result<Option<(Location, DateTime)>> {
+    let location = database.collection::<Document>(LOCATIONS).find_one(doc! {"_id": vid}, None).await?;
+    Ok(location.and_then(|location| {
+        let coordinates = location.get_document("location").ok()?.get_array("coordinates").ok()?;
+        let found = Location {
+            latitude: coordinates.get(1)?.as_f64()?,
+            longitude: coordinates.first()?.as_f64()?,
+        };
+        Some((found, *location.get_datetime("at").ok()?))
+    }))
+}
+
+pub(crate) fn last((location, at): (Location, DateTime)) -> Value {
+    json!({
+        "latitude": location.latitude,
+        "longitude": location.longitude,
+        "at": at.try_to_rfc3339_string().ok(),
+    })
+}
+
+// x & y in meters around the route's first point. Plenty accurate over the length of a drive
+fn project(route: &[[f64; 2]]) -> Vec<(f64, f64)> {
+    let origin = match route.first() {
+        Some(origin) => origin,
+        None => return Vec::new(),
+    };
+    let scale = origin[1].to_radians().cos();
+    route
+        .iter()
+        .map(|[longitude, latitude]| {
+            (
+                (longitude - origin[0]).to_radians() * scale * EARTH_RADIUS,
+                (latitude - origin[1]).to_radians() * EARTH_RADIUS,
+            )
+        })
+        .collect()
+}
+
+fn distance_to_segment(point: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let length = dx * dx + dy * dy;
+    let t = match length > 0.0 {
+        true => (((point.0 - start.0) * dx + (point.1 - start.1) * dy) / length).clamp(0.0, 1.0),
+        false => 0.0,
+    };
+    let (x, y) = (start.0 + t * dx, start.1 + t * dy);
+    ((point.0 - x).powi(2) + (point.1 - y).powi(2)).sqrt()
+}
+
+// Douglas–Peucker: drops every point that's within `tolerance` meters of the line the points
+// around it would draw anyway. The first & last points are always kept
+pub fn simplify(route: &[[f64; 2]], tolerance: f64) -> Vec<[f64; 2]> {
+    if route.len() < 3 || tolerance <= 0.0 {
+        return route.to_vec();
+    }
+    let projected = project(route);
+    let mut keep = vec![false; route.len()];
+    keep[0] = true;
+    keep[route.len() - 1] = true;
+    let mut stack = vec![(0, route.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| (i, distance_to_segment(projected[i], projected[first], projected[last])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, distance)) = farthest {
+            if distance > tolerance {
+                keep[i] = true;
+                stack.push((first, i));
+                stack.push((i, last));
+            }
+        }
+    }
+    route.iter().zip(keep).filter(|(_, keep)| *keep).map(|(point, _)| *point).collect()
+}
+
+// the trip's route as a GeoJSON feature. Routes with less than two points don't make a line,
+// those come back without a geometry
+pub async fn route(database: &Database, trip: &ObjectId, tolerance: Option<f64>) -> Result<Value> {
+    let options = FindOptions::builder().sort(doc! {"ts": 1}).projection(doc! {"location": 1}).build();
+    let mut cursor = database.collection::<Document>(ROUTES).find(doc! {"trip": trip}, options).await?;
+    let mut route = Vec::new();
+    while let Some(waypoint) = cursor.next().await {
+        let waypoint = waypoint?;
+        let coordinates = waypoint.get_document("location").ok().and_then(|location| location.get_array("coordinates").ok());
+        if let Some([longitude, latitude]) = coordinates.map(|coordinates| coordinates.as_slice()) {
+            if let (Some(longitude), Some(latitude)) = (longitude.as_f64(), latitude.as_f64()) {
+                route.push([longitude, latitude]);
+            }
+        }
+    }
+    Ok(feature(trip, &route, tolerance))
+}
+
+pub(crate) fn feature(trip: &ObjectId, route: &[[f64; 2]], tolerance: Option<f64>) -> Value {
+    let recorded = route.len();
+    let route = simplify(route, tolerance.unwrap_or(0.0));
+    let geometry = match route.len() {
+        0 | 1 => Value::Null,
+        _ => json!({"type": "LineString", "coordinates": route}),
+    };
+    json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": {
+            "trip": trip.to_hex(),
+            "recorded": recorded,
+            "points": route.len(),
+            "tolerance": tolerance,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn straight_stretches_collapse_to_their_ends() {
+        // ~11 m apart heading north, with a 200 m detour east in the middle
+        let route = [[76.26, 9.93], [76.26, 9.9301], [76.26, 9.9302], [76.2618, 9.9303], [76.26, 9.9304], [76.26, 9.9305]];
+        assert_eq!(simplify(&route, 50.0), vec![[76.26, 9.93], [76.2618, 9.9303], [76.26, 9.9305]]);
+        assert_eq!(simplify(&route, 500.0), vec![[76.26, 9.93], [76.26, 9.9305]]);
+        assert_eq!(simplify(&route, 0.0).len(), route.len());
+    }
+}
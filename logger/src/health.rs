@@ -0,0 +1,228 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::{json, Map, Value};
+
+use crate::Message;
+
+// engines revving above this are working hard
+pub const HIGH_RPM: u32 = 4000;
+// engine temperatures above this, in °C, are overheating
+pub const OVERHEAT_TEMP: u32 = 105;
+// readings further apart than this, in milliseconds, don't say much about what happened in between.
+// Only this much of the gap is counted
+const MAX_GAP: i64 = 60 * 1000;
+
+// how long a vehicle spent revving high & running hot. Time between two readings is put down to
+// what the earlier one said. Lives in the vehicle's ingestion actor, next to its fuel gauge
+#[derive(Default)]
+pub struct Exposure {
+    last: Option<(DateTime<Utc>, bool, bool)>,
+}
+
+impl Exposure {
+    // milliseconds at high rpm & overheating since the previous reading
+    pub fn read(&mut self, message: &Message, at: &DateTime<Utc>) -> (i64, i64) {
+        let high_rpm = message.rpm.is_some_and(|rpm| rpm > HIGH_RPM);
+        let overheating = message.temp.is_some_and(|temp| temp > OVERHEAT_TEMP);
+        let exposure = match self.last {
+            Some((last, was_high_rpm, was_overheating)) => {
+                let elapsed = (*at - last).num_milliseconds().clamp(0, MAX_GAP);
+                (elapsed * was_high_rpm as i64, elapsed * was_overheating as i64)
+            }
+            None => (0, 0),
+        };
+        self.last = Some((*at, high_rpm, overheating));
+        exposure
+    }
+}
+
+// what a vehicle went through on a single day
+pub struct Usage {
+    pub date: NaiveDate,
+    pub distance: u32,
+    pub stress_events: u32,
+    pub high_rpm_seconds: f64,
+    pub overheat_seconds: f64,
+}
+
+// how much of a component's life a day used up, 1 being all of it
+pub struct Wear {
+    pub component: &'static str,
+    pub amount: f64,
+}
+
+// turns a day of driving into wear on the vehicle's components. Swap in a different one with
+// `Logger::with_degradation`
+pub trait DegradationModel: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // every component is expected in every result, in the same order
+    fn wear(&self, usage: &Usage) -> Vec<Wear>;
+}
+
+// a part of the vehicle & what wears it down. Everything is counted as the distance that would
+// have worn the component as much, out of the distance it's expected to last
+pub struct Component {
+    pub name: &'static str,
+    pub lifetime: f64,
+    pub per_stress_event: f64,
+    pub per_high_rpm_hour: f64,
+    pub per_overheat_hour: f64,
+}
+
+// rough figures for a passenger car, in kilometres
+pub struct DefaultModel {
+    pub components: Vec<Component>,
+}
+
+impl Default for DefaultModel {
+    fn default() -> Self {
+        let component = |name, lifetime, per_stress_event, per_high_rpm_hour, per_overheat_hour| Component {
+            name,
+            lifetime,
+            per_stress_event,
+            per_high_rpm_hour,
+            per_overheat_hour,
+        };
+        DefaultModel {
+            components: vec![
+                component("engine", 300_000.0, 0.2, 500.0, 5000.0),
+                component("transmission", 250_000.0, 0.5, 300.0, 500.0),
+                component("brakes", 50_000.0, 2.0, 0.0, 0.0),
+                component("tyres", 60_000.0, 1.0, 0.0, 0.0),
+            ],
+        }
+    }
+}
+
+impl DegradationModel for DefaultModel {
+    fn name(&self) -> &'static str {
+        "default"
+    }
+
+    fn wear(&self, usage: &Usage) -> Vec<Wear> {
+        self.components
+            .iter()
+            .map(|component| {
+                let equivalent = usage.distance as f64
+                    + usage.stress_events as f64 * component.per_stress_event
+                    + usage.high_rpm_seconds / 3600.0 * component.per_high_rpm_hour
+                    + usage.overheat_seconds / 3600.0 * component.per_overheat_hour;
+                Wear {
+                    component: component.name,
+                    amount: equivalent / component.lifetime,
+                }
+            })
+            .collect()
+    }
+}
+
+// health is the share of a component's life that's left, in percent
+fn health(wear: &[(&'static str, f64)]) -> (Value, f64) {
+    let mut components = Map::new();
+    let mut total = 0.0;
+    for (component, worn) in wear {
+        let health = ((1.0 - worn) * 100.0).clamp(0.0, 100.0);
+        total += health;
+        components.insert(component.to_string(), json!((health * 100.0).round() / 100.0));
+    }
+    let overall = match wear.len() {
+        0 => 100.0,
+        length => total / length as f64,
+    };
+    (Value::Object(components), (overall * 100.0).round() / 100.0)
+}
+
+// wear adds up from the first day the vehicle was logged. `days` has to be oldest first, the
+// trend only has the days from `from` on
+pub fn report(model: &dyn DegradationModel, days: &[Usage], from: Option<NaiveDate>) -> Value {
+    let mut worn: Vec<(&'static str, f64)> = Vec::new();
+    let mut trend = Vec::new();
+    for day in days {
+        for (i, wear) in model.wear(day).into_iter().enumerate() {
+            match worn.get_mut(i) {
+                Some((_, amount)) => *amount += wear.amount,
+                None => worn.push((wear.component, wear.amount)),
+            }
+        }
+        if from.is_none_or(|from| day.date >= from) {
+            let (components, overall) = health(&worn);
+            trend.push(json!({
+                "date": day.date.format("%Y-%m-%d").to_string(),
+                "components": components,
+                "overall": overall,
+            }));
+        }
+    }
+    // a vehicle that was never driven is as good as new
+    if worn.is_empty() {
+        let nothing = Usage {
+            date: NaiveDate::MIN,
+            distance: 0,
+            stress_events: 0,
+            high_rpm_seconds: 0.0,
+            overheat_seconds: 0.0,
+        };
+        worn = model.wear(&nothing).into_iter().map(|wear| (wear.component, 0.0)).collect();
+    }
+    let (components, overall) = health(&worn);
+    json!({
+        "model": model.name(),
+        "components": components,
+        "overall": overall,
+        "trend": trend,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(rpm: u32, temp: u32) -> Message {
+        Message {
+            gear: None,
+            rpm: Some(rpm),
+            speed: Some(60),
+            location: None,
+            temp: Some(temp),
+            fuel: None,
+            odo: 0,
+            stressed: false,
+        }
+    }
+
+    #[test]
+    fn exposure_counts_time_until_the_next_reading() {
+        let at = |seconds: i64| Utc.timestamp_opt(1_680_000_000 + seconds, 0).unwrap();
+        let mut exposure = Exposure::default();
+        assert_eq!(exposure.read(&message(4500, 90), &at(0)), (0, 0));
+        assert_eq!(exposure.read(&message(4500, 110), &at(10)), (10_000, 0));
+        assert_eq!(exposure.read(&message(2000, 90), &at(15)), (5_000, 5_000));
+        // the vehicle went quiet for a while
+        assert_eq!(exposure.read(&message(4500, 110), &at(600)), (0, 0));
+        assert_eq!(exposure.read(&message(4500, 110), &at(3600)), (60_000, 60_000));
+    }
+
+    #[test]
+    fn health_wears_down_from_the_first_day() {
+        let day = |day, distance, stress_events| Usage {
+            date: NaiveDate::from_ymd_opt(2023, 4, day).unwrap(),
+            distance,
+            stress_events,
+            high_rpm_seconds: 0.0,
+            overheat_seconds: 0.0,
+        };
+        let model = DefaultModel::default();
+        let days = [day(1, 5000, 0), day(2, 0, 2500), day(3, 0, 0)];
+        let report = report(&model, &days, NaiveDate::from_ymd_opt(2023, 4, 2));
+        // 5000 km & 2500 events at 2 km each out of 50000 km
+        assert_eq!(report["components"]["brakes"], 80.0);
+        assert_eq!(report["components"]["tyres"], 87.5);
+        assert_eq!(report["trend"].as_array().unwrap().len(), 2);
+        assert_eq!(report["trend"][0]["date"], "2023-04-02");
+
+        let new = super::report(&model, &[], None);
+        assert_eq!(new["overall"], 100.0);
+        assert_eq!(new["components"]["engine"], 100.0);
+    }
+}
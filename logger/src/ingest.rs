@@ -0,0 +1,481 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::{Actor, ActorContext, ActorFutureExt, AsyncContext, AtomicResponse, Context, Handler, WrapFuture};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use mongodb::bson::{self, doc, Bson, Document};
+
+use crate::{
+    days::{self, Timezones},
+    fuel::Gauge,
+    health::Exposure,
+    store::LogStore,
+    trips::{Changes, Tracker},
+    Log, Message,
+};
+
+// how long messages sit in memory before they're written, whichever limit is hit first
+#[derive(Clone, Copy)]
+pub struct Batching {
+    pub interval: Duration,
+    pub size: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Batching {
+            interval: Duration::from_secs(1),
+            size: 50,
+        }
+    }
+}
+
+// a vehicle's broadcast, stamped with when it reached the server
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct Record {
+    pub message: Message,
+    pub at: DateTime<Utc>,
+}
+
+// writes whatever is buffered & stops the actor, sent when the vehicle leaves or the server shuts down
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct Drain;
+
+// one per connected vehicle. Flushes run with the actor blocked, so they never overlap and a
+// vehicle's daily document only ever has one writer
+pub struct Ingest {
+    vid: String,
+    store: Arc<dyn LogStore>,
+    batching: Batching,
+    timezones: Timezones,
+    buffer: Vec<(Message, DateTime<Utc>)>,
+    tracker: Tracker,
+    // the last reading that was summarized, so a new day picks up where the one before left off
+    odometer: Option<u32>,
+    gauge: Gauge,
+    exposure: Exposure,
+}
+
+impl Ingest {
+    // `idle_gap` is how long the vehicle has to stand still for its trip to end
+    pub fn new(vid: String, store: Arc<dyn LogStore>, batching: Batching, timezones: Timezones, idle_gap: Duration) -> Ingest {
+        Ingest {
+            tracker: Tracker::new(vid.clone(), idle_gap),
+            vid,
+            store,
+            batching,
+            timezones,
+            buffer: Vec::with_capacity(batching.size),
+            odometer: None,
+            gauge: Gauge::default(),
+            exposure: Exposure::default(),
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        // the vehicle may have stopped sending anything at all since it came to a halt
+        self.tracker.check(&Utc::now());
+        let changes = self.tracker.take();
+        if self.buffer.is_empty() && changes.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.batching.size));
+        let timezone = self.timezones.get(&self.vid);
+        let days = summarize(&batch, timezone, &mut self.odometer, &mut self.gauge, &mut self.exposure);
+        ctx.wait(self.persist(batch, timezone, days, changes).into_actor(self));
+    }
+
+    // a batch, the summaries of the days it covers & the trips that changed, all written in one go
+    fn persist(&self, batch: Vec<(Message, DateTime<Utc>)>, timezone: Tz, days: Vec<(NaiveDate, Summary)>, changes: Changes) -> impl std::future::Future<Output = ()> + 'static {
+        let (store, vid) = (self.store.clone(), self.vid.clone());
+        async move { store.persist(&vid, &batch, timezone, &days, changes).await }
+    }
+}
+
+impl Actor for Ingest {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let (store, vid) = (self.store.clone(), self.vid.clone());
+        ctx.wait(async move { store.open(&vid).await }.into_actor(self));
+        ctx.run_interval(self.batching.interval, |ingest, ctx| ingest.flush(ctx));
+    }
+}
+
+impl Handler<Record> for Ingest {
+    type Result = ();
+
+    fn handle(&mut self, record: Record, ctx: &mut Context<Self>) {
+        self.tracker.track(&record.message, &record.at);
+        self.buffer.push((record.message, record.at));
+        if self.buffer.len() >= self.batching.size {
+            self.flush(ctx);
+        }
+    }
+}
+
+impl Handler<Drain> for Ingest {
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, _: Drain, _: &mut Context<Self>) -> Self::Result {
+        let batch = std::mem::take(&mut self.buffer);
+        let timezone = self.timezones.get(&self.vid);
+        let days = summarize(&batch, timezone, &mut self.odometer, &mut self.gauge, &mut self.exposure);
+        self.tracker.finish();
+        let changes = self.tracker.take();
+        AtomicResponse::new(Box::pin(
+            self.persist(batch, timezone, days, changes)
+                .into_actor(self)
+                .map(|_, _, ctx| ctx.stop()),
+        ))
+    }
+}
+
+// odometers that show 6 digits wrap around to 0 after 999999
+const ROLLOVER: u32 = 1_000_000;
+// a drop in the reading only counts as a rollover if it would mean the vehicle went at most this
+// far since the last reading. Anything else is a reset, like a swapped instrument cluster
+const MAX_ROLLOVER_GAP: u32 = 1000;
+
+// distance between two consecutive readings. After a reset counting carries on from the new
+// reading, the distance across the reset itself is unknown & left out
+pub(crate) fn travelled(previous: u32, current: u32) -> u32 {
+    if current >= previous {
+        return current - previous;
+    }
+    // current < previous, so this can't overflow
+    let gap = ROLLOVER.saturating_sub(previous) + current;
+    match previous < ROLLOVER && gap <= MAX_ROLLOVER_GAP {
+        true => gap,
+        false => 0,
+    }
+}
+
+// what one day of a batch adds to that day's document
+pub struct Summary {
+    // readings that came with a speed, the average is taken over these
+    count: u32,
+    sum_speed: u32,
+    stressed: u32,
+    first_odometer: u32,
+    last_odometer: u32,
+    // the vehicle's reading before first_odometer, if it was summarized earlier. A day that's just
+    // being created counts from it, so the stretch across midnight goes to the new day
+    previous: Option<u32>,
+    // travelled between the batch's own readings. The stretch from the day's previous reading up
+    // to first_odometer is added by MongoDB, it's the only one that knows that reading
+    distance: u32,
+    max_speed: Option<(u32, DateTime<Utc>)>,
+    // readings that came with an engine temperature
+    temp_count: u32,
+    sum_temp: u64,
+    min_temp: Option<u32>,
+    max_temp: Option<u32>,
+    fuel_consumed: i64,
+    refuels: u32,
+    refuelled: u32,
+    high_rpm_ms: i64,
+    overheat_ms: i64,
+}
+
+impl Summary {
+    // `gauge` & `exposure` have to see every reading of the vehicle in order, so summaries are
+    // made as batches are flushed, by the vehicle's own actor
+    fn new(day: &[(Message, DateTime<Utc>)], odometer: &mut Option<u32>, gauge: &mut Gauge, exposure: &mut Exposure) -> Summary {
+        let mut summary = Summary {
+            count: 0,
+            sum_speed: 0,
+            stressed: 0,
+            first_odometer: day[0].0.odo,
+            last_odometer: day[0].0.odo,
+            previous: *odometer,
+            distance: 0,
+            max_speed: None,
+            temp_count: 0,
+            sum_temp: 0,
+            min_temp: None,
+            max_temp: None,
+            fuel_consumed: 0,
+            refuels: 0,
+            refuelled: 0,
+            high_rpm_ms: 0,
+            overheat_ms: 0,
+        };
+        for (message, at) in day {
+            let (high_rpm, overheat) = exposure.read(message, at);
+            summary.high_rpm_ms += high_rpm;
+            summary.overheat_ms += overheat;
+            if let Some(temp) = message.temp {
+                summary.temp_count += 1;
+                summary.sum_temp += temp as u64;
+                summary.min_temp = Some(summary.min_temp.map_or(temp, |min| min.min(temp)));
+                summary.max_temp = Some(summary.max_temp.map_or(temp, |max| max.max(temp)));
+            }
+            if let Some(fuel) = message.fuel {
+                let reading = gauge.read(fuel);
+                summary.fuel_consumed += reading.consumed;
+                summary.refuelled += reading.refuelled;
+                summary.refuels += reading.refuel as u32;
+            }
+            if let Some(speed) = message.speed {
+                summary.count += 1;
+                summary.sum_speed += speed;
+                if summary.max_speed.as_ref().is_none_or(|(max, _)| speed > *max) {
+                    summary.max_speed = Some((speed, *at));
+                }
+            }
+            if message.stressed {
+                summary.stressed += 1;
+            }
+            summary.distance += travelled(summary.last_odometer, message.odo);
+            summary.last_odometer = message.odo;
+        }
+        *odometer = Some(summary.last_odometer);
+        summary
+    }
+
+    // applied by MongoDB in a single step, so concurrent writers to the same day never lose each
+    // other's counts. Fields that aren't there yet are the ones of a day that's just being created
+    pub(crate) fn update(&self) -> Vec<Document> {
+        // `travelled` from the day's previous reading to the batch's first one
+        let previous = doc! {"$ifNull": ["$last_odometer", self.previous.unwrap_or(self.first_odometer)]};
+        let rolled_over = doc! {"$add": [{"$subtract": [ROLLOVER, &previous]}, self.first_odometer]};
+        let gap = doc! {"$switch": {
+            "branches": [
+                {"case": {"$gte": [self.first_odometer, &previous]}, "then": {"$subtract": [self.first_odometer, &previous]}},
+                {"case": {"$and": [{"$lt": [&previous, ROLLOVER]}, {"$lte": [&rolled_over, MAX_ROLLOVER_GAP]}]}, "then": &rolled_over},
+            ],
+            "default": 0,
+        }};
+        let max_speed = match &self.max_speed {
+            Some((speed, hit_at)) => Bson::from(doc! {"$cond": [
+                {"$gt": [speed, {"$ifNull": [{"$arrayElemAt": ["$max_speed", 0]}, -1]}]},
+                {"$literal": [speed, bson::DateTime::from_millis(hit_at.timestamp_millis())]},
+                "$max_speed",
+            ]}),
+            None => Bson::from(doc! {"$ifNull": ["$max_speed", {"$literal": [0, Bson::Null]}]}),
+        };
+        let add = |field: &str, value: Bson| doc! {"$add": [{"$ifNull": [format!("${}", field), 0]}, value]};
+        let mut set = doc! {
+            "message_count": add("message_count", self.count.into()),
+            "sum_speed": add("sum_speed", self.sum_speed.into()),
+            "stress": add("stress", self.stressed.into()),
+            "distance": {"$add": [{"$ifNull": ["$distance", 0]}, gap, self.distance]},
+            "first_odometer": {"$ifNull": ["$first_odometer", self.first_odometer]},
+            "last_odometer": self.last_odometer,
+            "max_speed": max_speed,
+            "temp_count": add("temp_count", self.temp_count.into()),
+            "sum_temp": add("sum_temp", (self.sum_temp as i64).into()),
+            "fuel_consumed": add("fuel_consumed", self.fuel_consumed.into()),
+            "refuels": add("refuels", self.refuels.into()),
+            "refuelled": add("refuelled", self.refuelled.into()),
+            "high_rpm_ms": add("high_rpm_ms", self.high_rpm_ms.into()),
+            "overheat_ms": add("overheat_ms", self.overheat_ms.into()),
+        };
+        // $min & $max skip fields that aren't there yet
+        if let (Some(min), Some(max)) = (self.min_temp, self.max_temp) {
+            set.insert("min_temp", doc! {"$min": ["$min_temp", min]});
+            set.insert("max_temp", doc! {"$max": ["$max_temp", max]});
+        }
+        vec![
+            doc! {"$set": set},
+            doc! {"$set": {
+                "average_speed": {"$cond": [
+                    {"$gt": ["$message_count", 0]},
+                    {"$toLong": {"$floor": {"$divide": ["$sum_speed", "$message_count"]}}},
+                    0,
+                ]},
+            }},
+        ]
+    }
+
+    // `update` for stores that aren't MongoDB, applied to the day's log as it is so far
+    pub(crate) fn apply(&self, log: Option<Log>, date: bson::DateTime) -> Log {
+        let new = log.is_none();
+        let mut log = log.unwrap_or(Log {
+            _id: None,
+            date,
+            average_speed: 0,
+            distance: 0,
+            stress: 0,
+            first_odometer: Some(self.first_odometer),
+            last_odometer: self.previous.unwrap_or(self.first_odometer),
+            message_count: 0,
+            sum_speed: 0,
+            max_speed: (0, Bson::Null),
+            temp_count: 0,
+            sum_temp: 0,
+            min_temp: None,
+            max_temp: None,
+            fuel_consumed: 0,
+            refuels: 0,
+            refuelled: 0,
+            high_rpm_ms: 0,
+            overheat_ms: 0,
+        });
+        log.distance += travelled(log.last_odometer, self.first_odometer) + self.distance;
+        log.first_odometer = log.first_odometer.or(Some(self.first_odometer));
+        log.last_odometer = self.last_odometer;
+        log.message_count += self.count;
+        log.sum_speed += self.sum_speed;
+        log.stress += self.stressed;
+        if let Some((speed, hit_at)) = &self.max_speed {
+            if new || *speed > log.max_speed.0 {
+                log.max_speed = (*speed, Bson::DateTime(bson::DateTime::from_millis(hit_at.timestamp_millis())));
+            }
+        }
+        log.temp_count += self.temp_count;
+        log.sum_temp += self.sum_temp;
+        log.min_temp = log.min_temp.into_iter().chain(self.min_temp).min();
+        log.max_temp = log.max_temp.into_iter().chain(self.max_temp).max();
+        log.fuel_consumed += self.fuel_consumed;
+        log.refuels += self.refuels;
+        log.refuelled += self.refuelled;
+        log.high_rpm_ms += self.high_rpm_ms;
+        log.overheat_ms += self.overheat_ms;
+        log.average_speed = match log.message_count {
+            0 => 0,
+            count => log.sum_speed / count,
+        };
+        log
+    }
+}
+
+// splits a batch into the local days it covers, which is almost always just the one
+pub(crate) fn summarize(batch: &[(Message, DateTime<Utc>)], timezone: Tz, odometer: &mut Option<u32>, gauge: &mut Gauge, exposure: &mut Exposure) -> Vec<(NaiveDate, Summary)> {
+    let mut days = Vec::new();
+    let mut start = 0;
+    while start < batch.len() {
+        let day = days::day(&batch[start].1, timezone);
+        let end = batch[start..]
+            .iter()
+            .position(|(_, at)| days::day(at, timezone) != day)
+            .map_or(batch.len(), |offset| start + offset);
+        days.push((day, Summary::new(&batch[start..end], odometer, gauge, exposure)));
+        start = end;
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join_all;
+    use crate::{memory::MemoryLogs, store::MongoLogs, telemetry::{self, Range}};
+    use mongodb::bson::oid::ObjectId;
+
+    const WRITERS: u32 = 2000;
+
+    fn message(i: u32) -> Message {
+        Message {
+            gear: Some(3),
+            rpm: Some(2000),
+            speed: Some(i % 100),
+            location: None,
+            temp: None,
+            fuel: None,
+            odo: 1000 + i,
+            stressed: i.is_multiple_of(10),
+        }
+    }
+
+    #[test]
+    fn odometer_rollovers_count_and_resets_dont() {
+        assert_eq!(travelled(1200, 1250), 50);
+        assert_eq!(travelled(1200, 1200), 0);
+        assert_eq!(travelled(999_990, 15), 25);
+        assert_eq!(travelled(999_990, 5000), 0);
+        assert_eq!(travelled(56_000, 12), 0);
+        assert_eq!(travelled(1_000_020, 3), 0);
+    }
+
+    #[test]
+    fn batches_accumulate_distance_across_a_rollover() {
+        let at = Utc::now();
+        let day: Vec<_> = [999_980, 999_995, 4, 30, 10].into_iter().map(|odo| (Message { odo, ..message(1) }, at)).collect();
+        let summary = Summary::new(&day, &mut None, &mut Gauge::default(), &mut Exposure::default());
+        assert_eq!(summary.first_odometer, 999_980);
+        assert_eq!(summary.last_odometer, 10);
+        // 15 + 9 + 26, then a reset
+        assert_eq!(summary.distance, 50);
+    }
+
+    #[test]
+    fn fuel_carries_over_between_batches() {
+        let at = Utc::now();
+        let reading = |fuel, temp| (Message { fuel: Some(fuel), temp, ..message(1) }, at);
+        let (mut gauge, mut exposure) = (Gauge::default(), Exposure::default());
+        let first = Summary::new(&[reading(40, Some(80)), reading(38, None), reading(30, Some(95))], &mut None, &mut gauge, &mut exposure);
+        assert_eq!((first.temp_count, first.sum_temp, first.min_temp, first.max_temp), (2, 175, Some(80), Some(95)));
+        assert_eq!(first.fuel_consumed, 10);
+        // the drop from 30 belongs to the next batch, then it fills up
+        let second = Summary::new(&[reading(28, None), reading(50, None), reading(49, None)], &mut None, &mut gauge, &mut exposure);
+        assert_eq!((second.fuel_consumed, second.refuels, second.refuelled), (3, 1, 22));
+        assert_eq!(second.min_temp, None);
+    }
+
+    // every write is its own batch, the way separate servers or actors would race on the same day
+    async fn race(store: &dyn LogStore, vid: &str, at: DateTime<Utc>) {
+        join_all((0..WRITERS).map(|i| {
+            let batch = vec![(message(i), at)];
+            let days = summarize(&batch, chrono_tz::UTC, &mut None, &mut Gauge::default(), &mut Exposure::default());
+            async move { store.persist(vid, &batch, chrono_tz::UTC, &days, Changes::default()).await }
+        }))
+        .await;
+    }
+
+    // the in-memory backend takes every write whole, so this checks its bookkeeping, not the pipeline.
+    // concurrent_writes_keep_exact_counts is the one racing the atomic upserts
+    #[actix_rt::test]
+    async fn memory_backend_counts_every_write() {
+        let store = MemoryLogs::default();
+        let at = Utc::now();
+        race(&store, "vid", at).await;
+
+        let log = store.log("vid", days::midnight(days::day(&at, chrono_tz::UTC), chrono_tz::UTC)).await.unwrap().unwrap();
+        let sum_speed: u32 = (0..WRITERS).map(|i| i % 100).sum();
+        assert_eq!((log.message_count, log.sum_speed, log.stress), (WRITERS, sum_speed, WRITERS / 10));
+        assert_eq!((log.average_speed, log.max_speed.0), (sum_speed / WRITERS, 99));
+        let range = Range {
+            vid: String::from("vid"),
+            start: at.timestamp_millis(),
+            end: at.timestamp_millis() + 1,
+            interval: None,
+            limit: WRITERS as i64,
+        };
+        let telemetry = store.telemetry(range).await.unwrap();
+        assert_eq!(telemetry["points"].as_array().unwrap().len(), WRITERS as usize);
+        assert_eq!(telemetry["truncated"], false);
+    }
+
+    // the upsert pipeline has to add every racing write in without losing any, so it needs MongoDB
+    #[actix_rt::test]
+    async fn concurrent_writes_keep_exact_counts() {
+        let Some(database) = crate::test_database().await else { return };
+        let vid = ObjectId::new().to_hex();
+        let store = MongoLogs::new(database.clone(), Duration::from_secs(60 * 60), chrono_tz::UTC).await;
+        store.open(&vid).await;
+
+        let at = Utc::now();
+        race(&store, &vid, at).await;
+
+        let collection = database.collection::<Document>(&vid);
+        assert_eq!(collection.count_documents(None, None).await.unwrap(), 1);
+        let log = collection.find_one(doc! {"date": days::midnight(days::day(&at, chrono_tz::UTC), chrono_tz::UTC)}, None).await.unwrap().unwrap();
+        let sum_speed: u32 = (0..WRITERS).map(|i| i % 100).sum();
+        assert_eq!(log.get_i64("message_count").unwrap(), WRITERS as i64);
+        assert_eq!(log.get_i64("sum_speed").unwrap(), sum_speed as i64);
+        assert_eq!(log.get_i64("stress").unwrap(), (WRITERS / 10) as i64);
+        assert_eq!(log.get_i64("average_speed").unwrap(), (sum_speed / WRITERS) as i64);
+        assert_eq!(log.get_array("max_speed").unwrap()[0].as_i64(), Some(99));
+        assert_eq!(
+            database.collection::<Document>(telemetry::COLLECTION).count_documents(doc! {"vid": &vid}, None).await.unwrap(),
+            WRITERS as u64
+        );
+
+        database.drop(None).await.unwrap();
+    }
+}
@@ -1,24 +1,50 @@
-use chrono::{Datelike, Local, FixedOffset};
+pub mod days;
+pub mod export;
+pub mod fuel;
+pub mod fences;
+pub mod geo;
+pub mod health;
+pub mod ingest;
+pub mod memory;
+pub mod reports;
+pub mod store;
+pub mod telemetry;
+pub mod trips;
+
+use actix::{Actor, Addr, Arbiter, ArbiterHandle};
+use chrono::{NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
 use core::fmt;
-use futures_util::stream::StreamExt;
-use mongodb::{
-    bson::{doc, oid::ObjectId},
-    options::{ClientOptions, FindOneOptions, FindOptions},
-    Client, Collection, Database,
-};
+use mongodb::bson::{oid::ObjectId, Bson, DateTime};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use days::{Span, Timezones};
+use fences::{Crossing, Fence, Geofences, Shape};
+use health::{DefaultModel, DegradationModel, Usage};
+use ingest::{Batching, Drain, Ingest, Record};
+use reports::Bucket;
+use store::LogStore;
 
 pub struct Logger {
-    database: Option<Database>,
-    message_count_map: HashMap<String, u32>,
+    store: Option<Arc<dyn LogStore>>,
+    // ingestion actors of the vehicles currently sending telemetry, all running on `arbiter`
+    ingestors: Arc<Mutex<HashMap<String, Addr<Ingest>>>>,
+    arbiter: Option<ArbiterHandle>,
+    batching: Batching,
+    timezones: Timezones,
+    idle_gap: Duration,
+    geofences: Geofences,
+    degradation: Arc<dyn DegradationModel>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Location {
-    latitude: f64,
-    longitude: f64
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -33,17 +59,106 @@ pub struct Message {
     stressed: bool,
 }
 
+// a vehicle's day, as it's kept by the stores
 #[derive(Serialize, Deserialize, Clone)]
-struct Log {
+pub struct Log {
     _id: Option<ObjectId>,
-    date: String,
+    // the moment the day started in the vehicle's timezone
+    date: DateTime,
     average_speed: u32,
     distance: u32,
     stress: u32,
+    // days logged before this was tracked don't have it
+    #[serde(default)]
+    first_odometer: Option<u32>,
     last_odometer: u32,
     message_count: u32,
     sum_speed: u32,
-    max_speed: (u32, String),
+    // the speed & when it was hit
+    max_speed: (u32, Bson),
+    // days logged before engine temperature & fuel were tracked have none of these
+    #[serde(default)]
+    temp_count: u32,
+    #[serde(default)]
+    sum_temp: u64,
+    min_temp: Option<u32>,
+    max_temp: Option<u32>,
+    #[serde(default)]
+    fuel_consumed: i64,
+    #[serde(default)]
+    refuels: u32,
+    #[serde(default)]
+    refuelled: u32,
+    #[serde(default)]
+    high_rpm_ms: i64,
+    #[serde(default)]
+    overheat_ms: i64,
+}
+
+// engine temperature & fuel over one or more days
+#[derive(Default)]
+struct Conditions {
+    temp_count: u32,
+    sum_temp: u64,
+    min_temp: Option<u32>,
+    max_temp: Option<u32>,
+    fuel_consumed: i64,
+    refuels: u32,
+    refuelled: u32,
+}
+
+impl Conditions {
+    fn add(&mut self, log: &Log) {
+        self.temp_count += log.temp_count;
+        self.sum_temp += log.sum_temp;
+        self.min_temp = self.min_temp.into_iter().chain(log.min_temp).min();
+        self.max_temp = self.max_temp.into_iter().chain(log.max_temp).max();
+        self.fuel_consumed += log.fuel_consumed;
+        self.refuels += log.refuels;
+        self.refuelled += log.refuelled;
+    }
+
+    fn temperature(&self) -> serde_json::Value {
+        let average = match self.temp_count {
+            0 => None,
+            count => Some(round(self.sum_temp as f64 / count as f64)),
+        };
+        json!({
+            "min": self.min_temp,
+            "average": average,
+            "max": self.max_temp,
+        })
+    }
+
+    // fuel is in whatever unit the vehicle reports it in & distance in the odometer's. Efficiency is
+    // left out until some fuel was actually used
+    fn fuel(&self, distance: u32) -> serde_json::Value {
+        // noise can leave a day that barely used any fuel slightly negative
+        let consumed = self.fuel_consumed.max(0);
+        let (efficiency, per_100) = match (consumed, distance) {
+            (0, _) | (_, 0) => (None, None),
+            (consumed, distance) => (Some(round(distance as f64 / consumed as f64)), Some(round(consumed as f64 * 100.0 / distance as f64))),
+        };
+        json!({
+            "consumed": consumed,
+            "refuels": self.refuels,
+            "refuelled": self.refuelled,
+            "efficiency": efficiency,
+            "consumption_per_100": per_100,
+        })
+    }
+}
+
+// the stress events of a day as wear, the way it's been reported from the start. /logs/health has
+// the fuller picture
+fn degradation(events: f64) -> f64 {
+    let required_events = 1000.0;
+    (events / required_events) * 0.01
+}
+
+// to two decimals
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
 }
 
 impl fmt::Display for Message {
@@ -59,8 +174,14 @@ impl fmt::Display for Message {
 impl Clone for Logger {
     fn clone(&self) -> Self {
         Logger {
-            database: self.database.clone(),
-            message_count_map: self.message_count_map.clone(),
+            store: self.store.clone(),
+            ingestors: self.ingestors.clone(),
+            arbiter: self.arbiter.clone(),
+            batching: self.batching,
+            timezones: self.timezones.clone(),
+            idle_gap: self.idle_gap,
+            geofences: self.geofences.clone(),
+            degradation: self.degradation.clone(),
         }
     }
 }
@@ -68,285 +189,284 @@ impl Clone for Logger {
 impl Default for Logger {
     fn default() -> Self {
         Logger {
-            database: None,
-            message_count_map: HashMap::new(),
+            store: None,
+            ingestors: Arc::new(Mutex::new(HashMap::new())),
+            arbiter: None,
+            batching: Batching::default(),
+            timezones: Timezones::new(Tz::UTC),
+            idle_gap: Duration::from_secs(5 * 60),
+            geofences: Geofences::default(),
+            degradation: Arc::new(DefaultModel::default()),
         }
     }
 }
 
 impl Logger {
-    pub async fn new() -> Self {
-        let mut client_options = ClientOptions::parse("mongodb://localhost:27017/")
-            .await
-            .unwrap();
-        client_options.app_name = Some("alpadrive".to_string());
-        let client = Client::with_options(client_options).unwrap();
-        let database = client.database("alpadrive-logs");
+    // `timezone` counts the days of vehicles that haven't set their own & trips end once a vehicle
+    // has been standing still for `idle_gap`.
+    // Has to be called from within the actix system the ingestion actors should run on
+    pub fn new(store: Arc<dyn LogStore>, batching: Batching, timezone: Tz, idle_gap: Duration) -> Self {
         Logger {
-            database: Some(database),
-            message_count_map: HashMap::new(),
+            store: Some(store),
+            ingestors: Arc::new(Mutex::new(HashMap::new())),
+            arbiter: Some(Arbiter::current()),
+            batching,
+            timezones: Timezones::new(timezone),
+            idle_gap,
+            geofences: Geofences::default(),
+            degradation: Arc::new(DefaultModel::default()),
         }
     }
 
-    // function extracts the last known document from the collection
-    // if not present, then returns a (base stat, whether update required)
-    async fn get_base_stats(&self, collection: Collection<Log>) -> (Log, bool) {
-        let options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
-        let today = Local::now().naive_local();
-        let default = (
-            Log {
-                _id: Some(ObjectId::new()),
-                average_speed: 0,
-                distance: 0,
-                last_odometer: 0,
-                stress: 0,
-                message_count: 0,
-                sum_speed: 0,
-                date: format!("{}-{}-{}", today.day(), today.month(), today.year()),
-                max_speed: (0, Local::now().format("%I:%M %p").to_string()),
-            },
-            false,
-        );
-
-        match collection.find_one(None, options).await {
-            Ok(value) => match value {
-                Some(log) => {
-                    // if the last inserted document is from a previous date then return default
-                    let date = log.clone().date;
-                    let current_date = Local::now().naive_local();
-                    if date
-                        == format!(
-                            "{}-{}-{}",
-                            current_date.day(),
-                            current_date.month(),
-                            current_date.year()
-                        )
-                    {
-                        (log, true)
-                    } else {
-                        default
-                    }
-                }
-                None => default,
-            },
-            Err(_) => default,
-        }
+    // rates vehicle health with `model` instead of the default one
+    pub fn with_degradation(mut self, model: impl DegradationModel + 'static) -> Self {
+        self.degradation = Arc::new(model);
+        self
     }
 
-    fn calculate_degradation(&self, events: u32) -> f64 {
-        let required_events = 1000.0;
-        (events as f64 / required_events) * 0.01
-    }
-
-    pub async fn log(&mut self, message: Message, vid: String) {
-        let collection = self
-            .database
-            .clone()
-            .unwrap_or_else(|| panic!("Logger couldn't find an active database"))
-            .collection::<Log>(&vid);
-        let (mut base_stats, update_required) = self.get_base_stats(collection.clone()).await;
-
-        if update_required {
-            // perform calculation and update operations
-            let id = match base_stats._id {
-                Some(value) => value,
-                None => ObjectId::new(),
-            };
+    // the timezone a vehicle with this setting has its days counted in
+    pub fn timezone(&self, name: Option<&str>) -> Tz {
+        self.timezones.resolve(name)
+    }
 
-            if message.odo > base_stats.last_odometer {
-                base_stats.distance = message.odo - base_stats.last_odometer;
-            }
-            let mut count = base_stats.message_count;
+    // called when a vehicle comes online or changes its timezone, so new messages land on the right day
+    pub fn set_timezone(&self, vid: &str, name: Option<&str>) {
+        self.timezones.set(vid, name);
+    }
 
-            if let Some(speed) = message.speed {
-                base_stats.sum_speed += speed;
-                if speed > base_stats.max_speed.0 {
-                    base_stats.max_speed = (speed, Local::now().with_timezone(&FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap()).format("%I:%M %p").to_string())
-                }
+    // a logger made with `Logger::default()` has nothing to read from, its queries fail instead
+    fn store(&self) -> Result<&Arc<dyn LogStore>, String> {
+        self.store.as_ref().ok_or_else(|| String::from("Logger couldn't find an active store"))
+    }
 
-                count += 1;
-                base_stats.average_speed = base_stats.sum_speed / count;
-            }
+    fn calculate_degradation(&self, events: u32) -> f64 {
+        degradation(events as f64)
+    }
 
-            if message.stressed {
-                base_stats.stress = ((base_stats.stress * (count - 1)) + 1) / (count);
-            }
+    // hands the message to the vehicle's ingestion actor, starting one if it doesn't have one yet.
+    // It's written along with the rest of its batch, not right away
+    pub fn log(&self, message: Message, vid: String) {
+        let (store, arbiter) = match (&self.store, &self.arbiter) {
+            (Some(store), Some(arbiter)) => (store, arbiter),
+            _ => return,
+        };
+        let record = Record { message, at: Utc::now() };
+        let mut ingestors = self.ingestors.lock().unwrap();
+        let ingest = ingestors.entry(vid.clone()).or_insert_with(|| {
+            let ingest = Ingest::new(vid, store.clone(), self.batching, self.timezones.clone(), self.idle_gap);
+            Ingest::start_in_arbiter(arbiter, move |_| ingest)
+        });
+        ingest.do_send(record);
+    }
 
-            base_stats.message_count = count;
-            base_stats.last_odometer = message.odo;
-
-            match collection
-                .update_one(
-                    doc! {"_id": id},
-                    doc! {
-                        "$set": {
-                            "average_speed": base_stats.average_speed,
-                            "distance": base_stats.distance,
-                            "stress": base_stats.stress,
-                            "last_odometer": base_stats.last_odometer,
-                            "message_count": base_stats.message_count,
-                            "sum_speed": base_stats.sum_speed,
-                            "max_speed": [base_stats.max_speed.0, base_stats.max_speed.1]
-                        }
-                    },
-                    None,
-                )
-                .await
-            {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("{:?}", e);
-                }
-            };
-        } else {
-            // just insert the data as a new document
-            base_stats.last_odometer = message.odo;
-            let speed = message.speed.unwrap_or(0);
-            base_stats.average_speed = speed;
-            base_stats.max_speed = (speed, Local::now().format("%I:%M %p").to_string());
-            collection
-                .insert_one(&base_stats, None)
-                .await
-                .expect("Failed to insert document");
+    // flushes & stops the vehicle's ingestion actor, once it's gone offline
+    pub fn release(&self, vid: &str) {
+        if let Some(ingest) = self.ingestors.lock().unwrap().remove(vid) {
+            ingest.do_send(Drain);
+        }
+        self.geofences.forget(vid);
+    }
+
+    // flushes every vehicle's buffered messages, waiting until they're all written
+    pub async fn shutdown(&self) {
+        let ingestors: Vec<Addr<Ingest>> = self.ingestors.lock().unwrap().drain().map(|(_, ingest)| ingest).collect();
+        for ingest in ingestors {
+            let _ = ingest.send(Drain).await;
         }
     }
 
-    pub async fn dailylogs(&self, date: String, vid: String) -> Result<String, String> {
-        let collection = self
-            .database
-            .clone()
-            .unwrap_or_else(|| panic!("Logger couldn't find an active database"))
-            .collection::<Log>(&vid);
-        let filter = doc! {"date": date};
-        let options = FindOneOptions::builder().build();
 
-        match collection.find_one(filter, options).await {
+    pub async fn dailylogs(&self, vid: String, day: NaiveDate, timezone: Tz) -> Result<String, String> {
+        match self.store()?.log(&vid, days::midnight(day, timezone)).await {
             Ok(result) => match result {
-                Some(result) => Ok(json!({
-                    "average_speed": result.average_speed,
-                    "stress_count": result.stress,
-                    "degradation": self.calculate_degradation(result.stress),
-                    "distance_travelled": result.distance,
-                    "last_odometer": result.last_odometer,
-                    "max_speed": {
-                        "speed": result.max_speed.0,
-                        "hit_at": result.max_speed.1
-                    }
-                })
-                .to_string()),
+                Some(result) => {
+                    let mut conditions = Conditions::default();
+                    conditions.add(&result);
+                    Ok(json!({
+                        "date": day.format("%Y-%m-%d").to_string(),
+                        "timezone": timezone.name(),
+                        "average_speed": result.average_speed,
+                        "stress_count": result.stress,
+                        "degradation": self.calculate_degradation(result.stress),
+                        "distance_travelled": result.distance,
+                        "first_odometer": result.first_odometer,
+                        "last_odometer": result.last_odometer,
+                        "max_speed": {
+                            "speed": result.max_speed.0,
+                            "hit_at": days::clock(&result.max_speed.1, timezone)
+                        },
+                        "temperature": conditions.temperature(),
+                        "fuel": conditions.fuel(result.distance),
+                    })
+                    .to_string())
+                }
                 None => Err(String::from("No results were found for this day.")),
             },
-            Err(e) => Err(String::from(format!("{:?}", e))),
+            Err(e) => Err(e),
         }
     }
 
-    pub async fn periodiclogs(
-        &self,
-        vid: String,
-        start_date: String,
-        end_date: String,
-    ) -> Result<String, String> {
-        let collection = self
-            .database
-            .clone()
-            .unwrap_or_else(|| panic!("Logger couldn't find active database"))
-            .collection::<Log>(&vid);
-        let filter = doc! {"date": {
-            "$gte": start_date,
-            "$lte": end_date,
-        }};
-
-        match collection
-            .find(filter, FindOptions::builder().build())
-            .await
-        {
-            Ok(mut cursor) => {
-                let mut average_speed = 0;
-                let mut distance = 0;
-                let mut max_speed = (0, String::from(""));
-                let mut last_odo = 0;
-                let mut stress_count = 0;
-                let mut degradation = 0.0;
-                let mut length = 0;
-
-                while let Some(result) = cursor.next().await {
-                    if let Ok(doc) = result {
-                        distance += doc.distance;
-                        average_speed += doc.average_speed;
-                        last_odo = doc.last_odometer;
-                        stress_count += doc.stress;
-                        degradation += self.calculate_degradation(doc.stress);
-                        length += 1;
-                        if max_speed.0 < doc.max_speed.0 {
-                            max_speed = doc.max_speed;
-                        }
-                    }
-                }
-                degradation /= length as f64;
-
-                Ok(json!({
-                    "distance_travelled": distance,
-                    "average_speed": average_speed/length,
-                    "stress_count": stress_count,
-                    "degradation": degradation,
-                    "last_odometer": last_odo,
-                    "max_speed": {
-                        "speed": max_speed.0,
-                        "hit_at": max_speed.1
-                    }
-                })
-                .to_string())
+    // both days are included
+    pub async fn periodiclogs(&self, vid: String, start: NaiveDate, end: NaiveDate, timezone: Tz, bucket: Bucket) -> Result<String, String> {
+        self.report(vid, Span::new(Some(start), Some(end), timezone), timezone, bucket).await
+    }
+
+    pub async fn overall_logs(&self, vid: String, timezone: Tz, bucket: Bucket) -> Result<String, String> {
+        self.report(vid, Span::default(), timezone, bucket).await
+    }
+
+    async fn report(&self, vid: String, span: Span, timezone: Tz, bucket: Bucket) -> Result<String, String> {
+        self.store()?.report(&vid, span, timezone, bucket).await.map(|result| result.to_string())
+    }
+
+    // opens the export, the rows are read from the store as they're streamed out
+    pub async fn export(&self, export: export::Export) -> Result<export::Rows, String> {
+        self.store()?.export(export).await
+    }
+
+    pub async fn telemetry(&self, range: telemetry::Range) -> Result<String, String> {
+        self.store()?.telemetry(range).await.map(|result| result.to_string())
+    }
+
+    // `before` is in milliseconds since the epoch
+    pub async fn trips(&self, vid: String, limit: i64, before: Option<i64>) -> Result<String, String> {
+        self.store()?.trips(&vid, limit, before).await.map(|result| result.to_string())
+    }
+
+    // the trip & the vid of the vehicle it belongs to
+    pub async fn trip(&self, id: ObjectId) -> Result<Option<(String, String)>, String> {
+        let result = self.store()?.trip(&id).await?;
+        Ok(result.map(|(vid, trip)| (vid, trip.to_string())))
+    }
+
+    // the trip's route as GeoJSON, with points within `tolerance` meters of the line dropped
+    pub async fn route(&self, trip: ObjectId, tolerance: Option<f64>) -> Result<String, String> {
+        self.store()?.route(&trip, tolerance).await.map(|result| result.to_string())
+    }
+
+    // where the vehicle was last seen, `None` if it never sent a location
+    pub async fn location(&self, vid: String) -> Result<Option<String>, String> {
+        let online = self.ingestors.lock().unwrap().contains_key(&vid);
+        let result = self.store()?.location(&vid).await?;
+        Ok(result.map(|last| {
+            let mut location = geo::last(last);
+            location["vid"] = json!(vid);
+            location["online"] = json!(online);
+            location.to_string()
+        }))
+    }
+
+    // starts checking the vehicle's locations against its geofences, once it comes online
+    pub async fn watch_fences(&self, vid: &str) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let (fences, last) = match (store.fences(vid).await, store.location(vid).await) {
+            (Ok(fences), Ok(last)) => (fences, last),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Failed to load the geofences of {}: {}", vid, e);
+                return;
             }
-            Err(_) => Err(String::from("An unexpected error occured")),
-        }
+        };
+        self.geofences.watch(vid, fences, last.as_ref().map(|(location, _)| location));
     }
 
-    pub async fn overall_logs(&self, vid: String) -> Result<String, String> {
-        let collection = self
-            .database
-            .clone()
-            .unwrap_or_else(|| panic!("Logger couldn't find active database"))
-            .collection::<Log>(&vid);
-
-        match collection.find(None, None).await {
-            Ok(mut cursor) => {
-                let mut average_speed = 0;
-                let mut distance = 0;
-                let mut max_speed = (0, String::from(""));
-                let mut last_odo = 0;
-                let mut stress_count = 0;
-                let mut degradation = 0.0;
-                let mut length = 0;
-
-                while let Some(result) = cursor.next().await {
-                    if let Ok(doc) = result {
-                        distance += doc.distance;
-                        average_speed += doc.average_speed;
-                        last_odo = doc.last_odometer;
-                        stress_count += doc.stress;
-                        degradation += self.calculate_degradation(doc.stress);
-                        length += 1;
-                        if max_speed.0 < doc.max_speed.0 {
-                            max_speed = doc.max_speed;
-                        }
-                    }
+    // the geofences the vehicle entered or left with this message. They're recorded in the background
+    pub fn crossings(&self, vid: &str, message: &Message) -> Vec<Crossing> {
+        let location = match &message.location {
+            Some(location) if geo::point(location).is_some() => location,
+            _ => return Vec::new(),
+        };
+        let crossings = self.geofences.check(vid, location, DateTime::now());
+        if let (Some(store), Some(arbiter), false) = (&self.store, &self.arbiter, crossings.is_empty()) {
+            let store = store.clone();
+            let recorded = crossings.clone();
+            let vid = vid.to_string();
+            arbiter.spawn(async move {
+                if let Err(e) = store.record_crossings(&recorded).await {
+                    eprintln!("Failed to record the geofence events of {}: {}", vid, e);
                 }
-                degradation /= length as f64;
-
-                Ok(json!({
-                    "distance_travelled": distance,
-                    "average_speed": average_speed/length,
-                    "stress_count": stress_count,
-                    "degradation": degradation,
-                    "last_odometer": last_odo,
-                    "max_speed": {
-                        "speed": max_speed.0,
-                        "hit_at": max_speed.1
-                    }
-                })
-                .to_string())
-            }
-            Err(_) => Err(String::from("An unexpected error occured")),
+            });
         }
+        crossings
+    }
+
+    pub async fn add_fence(&self, vid: String, name: String, shape: Shape) -> Result<String, String> {
+        let fence = Fence::new(&vid, name, shape);
+        self.store()?.add_fence(&fence).await?;
+        let result = fence.json().to_string();
+        self.geofences.add(fence);
+        Ok(result)
+    }
+
+    pub async fn fences(&self, vid: String) -> Result<String, String> {
+        let fences = self.store()?.fences(&vid).await?;
+        Ok(fences::listing(&vid, &fences).to_string())
+    }
+
+    // whether the vehicle had such a geofence
+    pub async fn remove_fence(&self, vid: String, id: ObjectId) -> Result<bool, String> {
+        let removed = self.store()?.remove_fence(&vid, &id).await?;
+        self.geofences.remove(&vid, &id);
+        Ok(removed)
+    }
+
+    // `before` is in milliseconds since the epoch
+    pub async fn fence_events(&self, vid: String, limit: i64, before: Option<i64>) -> Result<String, String> {
+        self.store()?.crossings(&vid, limit, before).await.map(|result| result.to_string())
+    }
+
+    // health as of `end`, or the latest day if there's none. The trend covers `start` to `end`
+    pub async fn health(&self, vid: String, start: Option<NaiveDate>, end: Option<NaiveDate>, timezone: Tz) -> Result<String, String> {
+        let logs = self.store()?.logs(&vid, Span::new(None, end, timezone)).await?;
+        let mut usage = Vec::new();
+        for log in logs {
+            let date = match Utc.timestamp_millis_opt(log.date.timestamp_millis()).single() {
+                Some(date) => days::day(&date, timezone),
+                None => continue,
+            };
+            usage.push(Usage {
+                date,
+                distance: log.distance,
+                stress_events: log.stress,
+                high_rpm_seconds: log.high_rpm_ms as f64 / 1000.0,
+                overheat_seconds: log.overheat_ms as f64 / 1000.0,
+            });
+        }
+        let mut report = health::report(self.degradation.as_ref(), &usage, start);
+        report["vid"] = json!(vid);
+        report["timezone"] = json!(timezone.name());
+        Ok(report.to_string())
+    }
+}
+
+// a throwaway database on the server ALPADRIVE_TEST_MONGODB points at. Tests that need MongoDB
+// pass without checking anything when it isn't set
+#[cfg(test)]
+pub(crate) async fn test_database() -> Option<mongodb::Database> {
+    let uri = match std::env::var("ALPADRIVE_TEST_MONGODB") {
+        Ok(uri) => uri,
+        Err(_) => {
+            eprintln!("ALPADRIVE_TEST_MONGODB isn't set, skipping a test that needs MongoDB");
+            return None;
+        }
+    };
+    let client = mongodb::Client::with_uri_str(&uri).await.unwrap();
+    Some(client.database(&format!("alpadrive-test-{}", ObjectId::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn queries_without_a_store_fail() {
+        let logger = Logger::default();
+        let vid = ObjectId::new().to_hex();
+        let missing = Err(String::from("Logger couldn't find an active store"));
+        assert_eq!(logger.dailylogs(vid.clone(), Utc::now().date_naive(), Tz::UTC).await, missing);
+        assert_eq!(logger.fences(vid.clone()).await, missing);
+        assert_eq!(logger.location(vid).await, Err(String::from("Logger couldn't find an active store")));
     }
 }
@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures_util::stream::{self, StreamExt};
+use mongodb::bson::{self, oid::ObjectId, Document};
+use serde_json::Value;
+
+use crate::{
+    days::{self, Span},
+    export::{self, Data, Export, Rows},
+    fences::{self, Crossing, Fence},
+    geo,
+    ingest::Summary,
+    reports::{self, Bucket},
+    store::LogStore,
+    telemetry::{self, Range},
+    trips::{self, Changes, Trip},
+    Location, Log, Message,
+};
+
+#[derive(Default)]
+struct Tables {
+    // a vehicle's daily logs by the millisecond their day starts
+    logs: HashMap<String, BTreeMap<i64, Log>>,
+    // a vehicle's readings, oldest first
+    telemetry: HashMap<String, Vec<Document>>,
+    trips: HashMap<ObjectId, Trip>,
+    routes: HashMap<ObjectId, Vec<[f64; 2]>>,
+    locations: HashMap<String, (Location, bson::DateTime)>,
+    fences: Vec<Fence>,
+    crossings: Vec<Crossing>,
+}
+
+// keeps everything in the server's memory, for development & tests. Nothing survives a restart
+pub struct MemoryLogs {
+    // readings older than this are dropped as new ones come in
+    retention: Duration,
+    tables: RwLock<Tables>,
+}
+
+impl MemoryLogs {
+    pub fn new(retention: Duration) -> MemoryLogs {
+        MemoryLogs {
+            retention,
+            tables: RwLock::new(Tables::default()),
+        }
+    }
+}
+
+impl Default for MemoryLogs {
+    fn default() -> Self {
+        MemoryLogs::new(Duration::from_secs(30 * 24 * 60 * 60))
+    }
+}
+
+// the latest `limit` of `items` with `at` before `before`, latest first
+fn page<T>(mut items: Vec<T>, at: impl Fn(&T) -> i64, limit: i64, before: Option<i64>) -> Vec<T> {
+    items.retain(|item| before.is_none_or(|before| at(item) < before));
+    items.sort_by_key(|item| std::cmp::Reverse(at(item)));
+    items.truncate(limit.max(0) as usize);
+    items
+}
+
+#[async_trait]
+impl LogStore for MemoryLogs {
+    async fn open(&self, vid: &str) {
+        let mut tables = self.tables.write().unwrap();
+        for trip in tables.trips.values_mut().filter(|trip| trip.vid == vid) {
+            trip.ongoing = false;
+        }
+    }
+
+    async fn persist(&self, vid: &str, batch: &[(Message, DateTime<Utc>)], timezone: Tz, days: &[(NaiveDate, Summary)], changes: Changes) {
+        let mut guard = self.tables.write().unwrap();
+        let tables = &mut *guard;
+        for waypoint in changes.route {
+            if let Some(coordinates) = waypoint.coordinates() {
+                tables.routes.entry(waypoint.trip).or_default().push(coordinates);
+            }
+        }
+        for trip in changes.trips {
+            tables.trips.insert(trip._id, trip);
+        }
+        if batch.is_empty() {
+            return;
+        }
+
+        let oldest = bson::DateTime::from_millis(Utc::now().timestamp_millis() - self.retention.as_millis() as i64);
+        let readings = tables.telemetry.entry(vid.to_string()).or_default();
+        readings.retain(|reading| reading.get_datetime("ts").is_ok_and(|ts| *ts >= oldest));
+        readings.extend(batch.iter().map(|(message, at)| telemetry::document(message, vid, at)));
+        if let Some(last) = geo::latest(batch) {
+            tables.locations.insert(vid.to_string(), last);
+        }
+
+        let logs = tables.logs.entry(vid.to_string()).or_default();
+        for (day, summary) in days {
+            let date = days::midnight(*day, timezone);
+            let log = summary.apply(logs.remove(&date.timestamp_millis()), date);
+            logs.insert(date.timestamp_millis(), log);
+        }
+    }
+
+    async fn log(&self, vid: &str, date: bson::DateTime) -> Result<Option<Log>, String> {
+        let tables = self.tables.read().unwrap();
+        Ok(tables.logs.get(vid).and_then(|logs| logs.get(&date.timestamp_millis())).cloned())
+    }
+
+    async fn logs(&self, vid: &str, span: Span) -> Result<Vec<Log>, String> {
+        let tables = self.tables.read().unwrap();
+        let logs = tables.logs.get(vid).map(|logs| logs.values().filter(|log| span.contains(&log.date)).cloned().collect());
+        Ok(logs.unwrap_or_default())
+    }
+
+    async fn report(&self, vid: &str, span: Span, timezone: Tz, bucket: Bucket) -> Result<Value, String> {
+        let logs = self.logs(vid, span).await?;
+        Ok(reports::fold(&logs, timezone, bucket))
+    }
+
+    // the rows are copied out up front, there's nothing to read them from later. Their lines are
+    // only put together as they're streamed out
+    async fn export(&self, export: Export) -> Result<Rows, String> {
+        let (columns, format, span, timezone) = (export.data.columns(), export.format, export.span(), export.timezone);
+        let lines = match export.data {
+            Data::Daily => {
+                let logs = self.logs(&export.vid, span).await?;
+                stream::iter(logs).map(move |log| Ok(format.line(columns, export::daily(&log, timezone)))).boxed()
+            }
+            Data::Telemetry => {
+                let readings: Vec<Document> = {
+                    let tables = self.tables.read().unwrap();
+                    let readings = tables.telemetry.get(&export.vid).map(Vec::as_slice).unwrap_or_default();
+                    readings.iter().filter(|reading| reading.get_datetime("ts").is_ok_and(|ts| span.contains(ts))).cloned().collect()
+                };
+                stream::iter(readings).map(move |reading| Ok(format.line(columns, export::reading(&reading)))).boxed()
+            }
+        };
+        Ok(export::stream(format, export.data, lines))
+    }
+
+    async fn telemetry(&self, range: Range) -> Result<Value, String> {
+        let readings: Vec<Document> = {
+            let tables = self.tables.read().unwrap();
+            let readings = tables.telemetry.get(&range.vid).map(Vec::as_slice).unwrap_or_default();
+            readings
+                .iter()
+                .filter(|reading| {
+                    reading
+                        .get_datetime("ts")
+                        .is_ok_and(|ts| (range.start..range.end).contains(&ts.timestamp_millis()))
+                })
+                .cloned()
+                .collect()
+        };
+        let readings = match range.interval {
+            Some(interval) => telemetry::average(&readings, interval),
+            None => readings,
+        };
+        // one more than asked for, to tell whether the range was cut short
+        let points = readings.iter().take(range.limit as usize + 1).map(telemetry::point).collect();
+        Ok(telemetry::window(&range, points))
+    }
+
+    async fn trips(&self, vid: &str, limit: i64, before: Option<i64>) -> Result<Value, String> {
+        let tables = self.tables.read().unwrap();
+        let trips: Vec<&Trip> = tables.trips.values().filter(|trip| trip.vid == vid).collect();
+        let trips = page(trips, |trip| trip.start.timestamp_millis(), limit, before);
+        Ok(trips::listing(vid, trips.into_iter().map(Trip::json).collect()))
+    }
+
+    async fn trip(&self, id: &ObjectId) -> Result<Option<(String, Value)>, String> {
+        let tables = self.tables.read().unwrap();
+        Ok(tables.trips.get(id).map(|trip| (trip.vid.clone(), trip.json())))
+    }
+
+    async fn route(&self, trip: &ObjectId, tolerance: Option<f64>) -> Result<Value, String> {
+        let tables = self.tables.read().unwrap();
+        let route = tables.routes.get(trip).map(Vec::as_slice).unwrap_or_default();
+        Ok(geo::feature(trip, route, tolerance))
+    }
+
+    async fn location(&self, vid: &str) -> Result<Option<(Location, bson::DateTime)>, String> {
+        Ok(self.tables.read().unwrap().locations.get(vid).cloned())
+    }
+
+    async fn fences(&self, vid: &str) -> Result<Vec<Fence>, String> {
+        let tables = self.tables.read().unwrap();
+        Ok(tables.fences.iter().filter(|fence| fence.vid == vid).cloned().collect())
+    }
+
+    async fn add_fence(&self, fence: &Fence) -> Result<(), String> {
+        self.tables.write().unwrap().fences.push(fence.clone());
+        Ok(())
+    }
+
+    async fn remove_fence(&self, vid: &str, id: &ObjectId) -> Result<bool, String> {
+        let mut tables = self.tables.write().unwrap();
+        let count = tables.fences.len();
+        tables.fences.retain(|fence| !(fence._id == *id && fence.vid == vid));
+        Ok(tables.fences.len() < count)
+    }
+
+    async fn record_crossings(&self, crossings: &[Crossing]) -> Result<(), String> {
+        self.tables.write().unwrap().crossings.extend_from_slice(crossings);
+        Ok(())
+    }
+
+    async fn crossings(&self, vid: &str, limit: i64, before: Option<i64>) -> Result<Value, String> {
+        let tables = self.tables.read().unwrap();
+        let crossings: Vec<&Crossing> = tables.crossings.iter().filter(|crossing| crossing.vid == vid).collect();
+        let crossings = page(crossings, |crossing| crossing.at.timestamp_millis(), limit, before);
+        Ok(fences::history(vid, crossings.into_iter().map(Crossing::json).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fuel::Gauge, health::Exposure, ingest::summarize};
+    use chrono::TimeZone;
+
+    fn reading(speed: u32, odo: u32, seconds: i64) -> (Message, DateTime<Utc>) {
+        let message = Message {
+            gear: Some(3),
+            rpm: Some(2000),
+            speed: Some(speed),
+            location: Some(Location { latitude: 9.93, longitude: 76.26 }),
+            temp: None,
+            fuel: None,
+            odo,
+            stressed: speed > 80,
+        };
+        (message, Utc.timestamp_opt(1_680_000_000 + seconds, 0).unwrap())
+    }
+
+    // batches land the way they would in MongoDB, the gap between them included
+    #[actix_rt::test]
+    async fn batches_add_up_like_they_do_in_mongodb() {
+        // the readings are from 2023, they'd be past the default retention
+        let store = MemoryLogs::new(Duration::from_secs(100 * 365 * 24 * 60 * 60));
+        let (mut odometer, mut gauge, mut exposure) = (None, Gauge::default(), Exposure::default());
+        for batch in [vec![reading(40, 100, 0), reading(90, 102, 10)], vec![reading(60, 105, 20), reading(20, 106, 70)]] {
+            let days = summarize(&batch, chrono_tz::UTC, &mut odometer, &mut gauge, &mut exposure);
+            store.persist("vid", &batch, chrono_tz::UTC, &days, Changes::default()).await;
+        }
+
+        let day = days::day(&reading(0, 0, 0).1, chrono_tz::UTC);
+        let log = store.log("vid", days::midnight(day, chrono_tz::UTC)).await.unwrap().unwrap();
+        assert_eq!((log.distance, log.first_odometer, log.last_odometer), (6, Some(100), 106));
+        assert_eq!((log.message_count, log.average_speed, log.stress, log.max_speed.0), (4, 52, 1, 90));
+
+        let report = store.report("vid", Span::default(), chrono_tz::UTC, Bucket::Month).await.unwrap();
+        assert_eq!(report["days"], 1);
+        assert_eq!(report["distance_travelled"], 6);
+
+        let range = Range {
+            vid: String::from("vid"),
+            start: 1_680_000_000_000,
+            end: 1_680_000_100_000,
+            interval: Some(60),
+            limit: 1,
+        };
+        let telemetry = store.telemetry(range).await.unwrap();
+        assert_eq!(telemetry["truncated"], true);
+        assert_eq!(telemetry["points"][0]["speed"], 63.33);
+        assert!(store.location("vid").await.unwrap().is_some());
+        assert!(store.location("other").await.unwrap().is_none());
+    }
+
+    // what's driven across midnight counts toward the new day, whether or not the day changes within a batch
+    #[actix_rt::test]
+    async fn the_stretch_across_midnight_goes_to_the_new_day() {
+        let store = MemoryLogs::new(Duration::from_secs(100 * 365 * 24 * 60 * 60));
+        // 1_680_000_000 is 10:40 UTC, midnight is 48_000 seconds later
+        let (midnight, next) = (48_000, 48_000 + 24 * 60 * 60);
+        let batches = [
+            vec![reading(40, 1000, midnight - 20), reading(40, 1001, midnight - 10)],
+            vec![reading(40, 1004, midnight + 10), reading(40, 1006, midnight + 20)],
+            vec![reading(40, 1007, next - 10), reading(40, 1009, next + 10)],
+        ];
+        let (mut odometer, mut gauge, mut exposure) = (None, Gauge::default(), Exposure::default());
+        for batch in batches {
+            let days = summarize(&batch, chrono_tz::UTC, &mut odometer, &mut gauge, &mut exposure);
+            store.persist("vid", &batch, chrono_tz::UTC, &days, Changes::default()).await;
+        }
+
+        let distance = |seconds| {
+            let day = days::day(&reading(0, 0, seconds).1, chrono_tz::UTC);
+            store.log("vid", days::midnight(day, chrono_tz::UTC))
+        };
+        assert_eq!(distance(midnight - 20).await.unwrap().unwrap().distance, 1);
+        let second = distance(midnight + 10).await.unwrap().unwrap();
+        assert_eq!((second.distance, second.first_odometer, second.last_odometer), (6, Some(1004), 1007));
+        assert_eq!(distance(next + 10).await.unwrap().unwrap().distance, 2);
+    }
+
+    // an export holds on to the readings it was opened with, & its lines are put together as it's read
+    #[actix_rt::test]
+    async fn exports_stream_the_rows_they_were_opened_with() {
+        let store = MemoryLogs::new(Duration::from_secs(100 * 365 * 24 * 60 * 60));
+        let batch = vec![reading(40, 100, 0), reading(90, 102, 10)];
+        let days = summarize(&batch, chrono_tz::UTC, &mut None, &mut Gauge::default(), &mut Exposure::default());
+        store.persist("vid", &batch, chrono_tz::UTC, &days, Changes::default()).await;
+
+        let export = Export {
+            vid: String::from("vid"),
+            data: Data::Telemetry,
+            format: export::Format::Ndjson,
+            start: None,
+            end: None,
+            timezone: chrono_tz::UTC,
+        };
+        let rows = store.export(export).await.unwrap();
+        let later = vec![reading(60, 105, 20)];
+        store.persist("vid", &later, chrono_tz::UTC, &[], Changes::default()).await;
+
+        let text: String = rows.map(Result::unwrap).collect::<Vec<_>>().await.concat();
+        let speeds: Vec<Value> = text.lines().map(|line| serde_json::from_str::<Value>(line).unwrap()["speed"].clone()).collect();
+        assert_eq!(speeds, vec![Value::from(40), Value::from(90)]);
+    }
+}
@@ -0,0 +1,338 @@
+use std::str::FromStr;
+
+use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{self, doc, Bson, Document},
+    error::Result,
+    Database,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{days::{self, Span}, degradation, Conditions, Log};
+
+// how the days of a report are put together in its series
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bucket {
+    Day,
+    Week,
+    Month,
+}
+
+impl Bucket {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+
+    // labels the bucket a day falls in. Weeks are ISO weeks, like 2023-W13
+    fn format(&self) -> &'static str {
+        match self {
+            Bucket::Day => "%Y-%m-%d",
+            Bucket::Week => "%G-W%V",
+            Bucket::Month => "%Y-%m",
+        }
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            _ => Err(()),
+        }
+    }
+}
+
+// daily logs added up, by MongoDB. Days logged before a field was tracked count as zero for it
+#[derive(Deserialize)]
+struct Group {
+    // the bucket's label, nothing for the totals
+    _id: Bson,
+    start: bson::DateTime,
+    days: u32,
+    distance: i64,
+    message_count: i64,
+    sum_speed: i64,
+    stress: i64,
+    first_odometer: Option<u32>,
+    last_odometer: u32,
+    max_speed: (u32, Bson),
+    temp_count: i64,
+    sum_temp: i64,
+    min_temp: Option<u32>,
+    max_temp: Option<u32>,
+    fuel_consumed: i64,
+    refuels: i64,
+    refuelled: i64,
+}
+
+impl Group {
+    // the same thing MongoDB works out for a bucket, for stores that don't have it
+    fn new(id: Bson, log: &Log) -> Group {
+        let mut group = Group {
+            _id: id,
+            start: log.date,
+            days: 0,
+            distance: 0,
+            message_count: 0,
+            sum_speed: 0,
+            stress: 0,
+            first_odometer: log.first_odometer,
+            last_odometer: log.last_odometer,
+            max_speed: (0, Bson::Null),
+            temp_count: 0,
+            sum_temp: 0,
+            min_temp: None,
+            max_temp: None,
+            fuel_consumed: 0,
+            refuels: 0,
+            refuelled: 0,
+        };
+        group.add(log);
+        group
+    }
+
+    // logs have to be added oldest first
+    fn add(&mut self, log: &Log) {
+        self.days += 1;
+        self.distance += log.distance as i64;
+        self.message_count += log.message_count as i64;
+        self.sum_speed += log.sum_speed as i64;
+        self.stress += log.stress as i64;
+        self.last_odometer = log.last_odometer;
+        if log.max_speed.0 > self.max_speed.0 {
+            self.max_speed = log.max_speed.clone();
+        }
+        self.temp_count += log.temp_count as i64;
+        self.sum_temp += log.sum_temp as i64;
+        self.min_temp = self.min_temp.into_iter().chain(log.min_temp).min();
+        self.max_temp = self.max_temp.into_iter().chain(log.max_temp).max();
+        self.fuel_consumed += log.fuel_consumed;
+        self.refuels += log.refuels as i64;
+        self.refuelled += log.refuelled as i64;
+    }
+
+    fn json(&self, timezone: Tz) -> Value {
+        let conditions = Conditions {
+            temp_count: self.temp_count as u32,
+            sum_temp: self.sum_temp as u64,
+            min_temp: self.min_temp,
+            max_temp: self.max_temp,
+            fuel_consumed: self.fuel_consumed,
+            refuels: self.refuels as u32,
+            refuelled: self.refuelled as u32,
+        };
+        let average_speed = match self.message_count {
+            0 => 0,
+            count => self.sum_speed / count,
+        };
+        let distance = self.distance as u32;
+        json!({
+            "days": self.days,
+            "distance_travelled": distance,
+            "average_speed": average_speed,
+            "stress_count": self.stress,
+            // the average of the daily figures
+            "degradation": degradation(self.stress as f64 / self.days.max(1) as f64),
+            "first_odometer": self.first_odometer,
+            "last_odometer": self.last_odometer,
+            "max_speed": {
+                "speed": self.max_speed.0,
+                "hit_at": days::clock(&self.max_speed.1, timezone)
+            },
+            "temperature": conditions.temperature(),
+            "fuel": conditions.fuel(distance),
+        })
+    }
+}
+
+fn group(id: impl Into<Bson>) -> Document {
+    let sum = |field: &str| doc! {"$sum": format!("${}", field)};
+    doc! {"$group": {
+        "_id": id.into(),
+        "start": {"$min": "$date"},
+        "days": {"$sum": 1},
+        "distance": sum("distance"),
+        "message_count": sum("message_count"),
+        "sum_speed": sum("sum_speed"),
+        "stress": sum("stress"),
+        // days come in oldest first
+        "first_odometer": {"$first": "$first_odometer"},
+        "last_odometer": {"$last": "$last_odometer"},
+        "max_speeds": {"$push": "$max_speed"},
+        "temp_count": sum("temp_count"),
+        "sum_temp": sum("sum_temp"),
+        "min_temp": {"$min": "$min_temp"},
+        "max_temp": {"$max": "$max_temp"},
+        "fuel_consumed": sum("fuel_consumed"),
+        "refuels": sum("refuels"),
+        "refuelled": sum("refuelled"),
+    }}
+}
+
+// the highest of the days' max speeds, the earliest one if several days hit it
+fn fastest() -> [Document; 2] {
+    [
+        doc! {"$addFields": {"max_speed": {"$reduce": {
+            "input": "$max_speeds",
+            "initialValue": [0, Bson::Null],
+            "in": {"$cond": [
+                {"$gt": [{"$arrayElemAt": ["$$this", 0]}, {"$arrayElemAt": ["$$value", 0]}]},
+                "$$this",
+                "$$value",
+            ]},
+        }}}},
+        doc! {"$project": {"max_speeds": 0}},
+    ]
+}
+
+// the daily logs in `span` added up, along with a series of them bucketed by `bucket` in the
+// vehicle's timezone. Both come out of a single aggregation
+pub async fn run(database: &Database, vid: &str, span: Span, timezone: Tz, bucket: Bucket) -> Result<Value> {
+    let label = doc! {"$dateToString": {"format": bucket.format(), "date": "$date", "timezone": timezone.name()}};
+    let mut series = vec![group(label)];
+    series.extend(fastest());
+    series.push(doc! {"$sort": {"start": 1}});
+    let mut totals = vec![group(Bson::Null)];
+    totals.extend(fastest());
+    let pipeline = [
+        doc! {"$match": span.filter("date")},
+        doc! {"$sort": {"date": 1}},
+        doc! {"$facet": {"series": series, "totals": totals}},
+    ];
+    let mut cursor = database.collection::<Document>(vid).aggregate(pipeline, None).await?;
+    let facets = match cursor.next().await {
+        Some(facets) => facets?,
+        None => Document::new(),
+    };
+    let groups = |facet: &str| -> Result<Vec<Group>> {
+        let mut groups = Vec::new();
+        for group in facets.get_array(facet).map(|groups| groups.as_slice()).unwrap_or_default() {
+            if let Bson::Document(group) = group {
+                groups.push(bson::from_document(group.clone())?);
+            }
+        }
+        Ok(groups)
+    };
+    let totals = groups("totals")?.into_iter().next();
+    Ok(assemble(groups("series")?, totals, timezone, bucket))
+}
+
+// `run` for stores that aren't MongoDB. `logs` have to be oldest first
+pub(crate) fn fold(logs: &[Log], timezone: Tz, bucket: Bucket) -> Value {
+    let mut series: Vec<Group> = Vec::new();
+    let mut totals: Option<Group> = None;
+    for log in logs {
+        let label = Utc
+            .timestamp_millis_opt(log.date.timestamp_millis())
+            .single()
+            .map(|date| date.with_timezone(&timezone).format(bucket.format()).to_string());
+        let label = Bson::from(label);
+        match series.last_mut() {
+            Some(group) if group._id == label => group.add(log),
+            _ => series.push(Group::new(label, log)),
+        }
+        match &mut totals {
+            Some(totals) => totals.add(log),
+            None => totals = Some(Group::new(Bson::Null, log)),
+        }
+    }
+    assemble(series, totals, timezone, bucket)
+}
+
+fn assemble(series: Vec<Group>, totals: Option<Group>, timezone: Tz, bucket: Bucket) -> Value {
+    let series: Vec<Value> = series
+        .iter()
+        .map(|group| {
+            let mut entry = group.json(timezone);
+            entry["period"] = json!(group._id.as_str());
+            entry["start"] = json!(Utc
+                .timestamp_millis_opt(group.start.timestamp_millis())
+                .single()
+                .map(|start| days::day(&start, timezone).format("%Y-%m-%d").to_string()));
+            entry
+        })
+        .collect();
+
+    // without any days there's nothing to add up, the report says as much instead
+    let mut report = match totals {
+        Some(totals) => totals.json(timezone),
+        None => json!({
+            "days": 0,
+            "distance_travelled": 0,
+            "average_speed": 0,
+            "stress_count": 0,
+            "degradation": 0.0,
+            "first_odometer": Value::Null,
+            "last_odometer": Value::Null,
+            "max_speed": Value::Null,
+            "temperature": Conditions::default().temperature(),
+            "fuel": Conditions::default().fuel(0),
+        }),
+    };
+    report["timezone"] = json!(timezone.name());
+    report["group_by"] = json!(bucket.name());
+    report["series"] = json!(series);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use mongodb::bson::oid::ObjectId;
+
+    // runs the $facet & $group pipelines, so it needs MongoDB
+    #[actix_rt::test]
+    async fn reports_add_up_days_into_buckets() {
+        let Some(database) = crate::test_database().await else { return };
+        let vid = ObjectId::new().to_hex();
+        let timezone = chrono_tz::Asia::Kolkata;
+
+        let empty = run(&database, &vid, Span::default(), timezone, Bucket::Day).await.unwrap();
+        assert_eq!(empty["days"], 0);
+        assert_eq!(empty["average_speed"], 0);
+        assert_eq!(empty["series"], json!([]));
+
+        let day = |month, day, speed: i64, odometer: i64| {
+            let date = days::midnight(NaiveDate::from_ymd_opt(2023, month, day).unwrap(), timezone);
+            doc! {
+                "date": date,
+                "distance": 10,
+                "message_count": 10,
+                "sum_speed": speed * 10,
+                "average_speed": speed,
+                "stress": 100,
+                "first_odometer": odometer,
+                "last_odometer": odometer + 10,
+                "max_speed": [speed, date],
+            }
+        };
+        let logs = [day(3, 31, 40, 100), day(4, 1, 60, 110), day(4, 2, 60, 120)];
+        database.collection::<Document>(&vid).insert_many(logs, None).await.unwrap();
+
+        let report = run(&database, &vid, Span::default(), timezone, Bucket::Month).await.unwrap();
+        assert_eq!(report["days"], 3);
+        assert_eq!(report["distance_travelled"], 30);
+        assert_eq!(report["average_speed"], 53);
+        assert_eq!(report["first_odometer"], 100);
+        assert_eq!(report["last_odometer"], 130);
+        assert_eq!(report["max_speed"]["speed"], 60);
+        assert_eq!(report["series"].as_array().unwrap().len(), 2);
+        assert_eq!(report["series"][0]["period"], "2023-03");
+        assert_eq!(report["series"][1]["start"], "2023-04-01");
+        assert_eq!(report["series"][1]["distance_travelled"], 20);
+
+        database.drop(None).await.unwrap();
+    }
+}
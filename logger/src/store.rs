@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document},
+    options::{FindOptions, IndexOptions, UpdateOptions},
+    Database, IndexModel,
+};
+use serde_json::Value;
+
+use crate::{
+    days::{self, Span},
+    export::{self, Export, Rows},
+    fences::{self, Crossing, Fence},
+    geo,
+    ingest::Summary,
+    reports::{self, Bucket},
+    telemetry::{self, Range},
+    trips::{self, Changes},
+    Location, Log, Message,
+};
+
+// where everything the logger keeps ends up. Errors are the store's own, as text
+#[async_trait]
+pub trait LogStore: Send + Sync {
+    // called before a vehicle's first batch, trips left ongoing by an earlier run are over by now
+    async fn open(&self, vid: &str);
+    // a batch, the summaries of the days it covers & the trips that changed. Failures are printed,
+    // the batch is gone either way
+    async fn persist(&self, vid: &str, batch: &[(Message, DateTime<Utc>)], timezone: Tz, days: &[(NaiveDate, Summary)], changes: Changes);
+    // the log of the day starting at `date`
+    async fn log(&self, vid: &str, date: bson::DateTime) -> Result<Option<Log>, String>;
+    // oldest first
+    async fn logs(&self, vid: &str, span: Span) -> Result<Vec<Log>, String>;
+    async fn report(&self, vid: &str, span: Span, timezone: Tz, bucket: Bucket) -> Result<Value, String>;
+    async fn export(&self, export: Export) -> Result<Rows, String>;
+    async fn telemetry(&self, range: Range) -> Result<Value, String>;
+    // latest first. `before` is in milliseconds since the epoch
+    async fn trips(&self, vid: &str, limit: i64, before: Option<i64>) -> Result<Value, String>;
+    // the trip along with the vehicle it belongs to
+    async fn trip(&self, id: &ObjectId) -> Result<Option<(String, Value)>, String>;
+    async fn route(&self, trip: &ObjectId, tolerance: Option<f64>) -> Result<Value, String>;
+    async fn location(&self, vid: &str) -> Result<Option<(Location, bson::DateTime)>, String>;
+    async fn fences(&self, vid: &str) -> Result<Vec<Fence>, String>;
+    async fn add_fence(&self, fence: &Fence) -> Result<(), String>;
+    // whether the vehicle had such a fence
+    async fn remove_fence(&self, vid: &str, id: &ObjectId) -> Result<bool, String>;
+    async fn record_crossings(&self, crossings: &[Crossing]) -> Result<(), String>;
+    // latest first. `before` is in milliseconds since the epoch
+    async fn crossings(&self, vid: &str, limit: i64, before: Option<i64>) -> Result<Value, String>;
+}
+
+fn text(e: mongodb::error::Error) -> String {
+    format!("{:?}", e)
+}
+
+// daily logs get a collection per vehicle, everything else is shared
+pub struct MongoLogs {
+    database: Database,
+}
+
+impl MongoLogs {
+    // `retention` is how long raw telemetry is kept around & `timezone` the one older daily logs
+    // were counted in
+    pub async fn new(database: Database, retention: Duration, timezone: Tz) -> MongoLogs {
+        if let Err(e) = telemetry::prepare(&database, retention).await {
+            eprintln!("Failed to set up the telemetry collection: {}", e);
+        }
+        if let Err(e) = trips::prepare(&database).await {
+            eprintln!("Failed to set up the trips collection: {}", e);
+        }
+        if let Err(e) = geo::prepare(&database).await {
+            eprintln!("Failed to set up the route & location collections: {}", e);
+        }
+        if let Err(e) = fences::prepare(&database).await {
+            eprintln!("Failed to set up the geofence collections: {}", e);
+        }
+        if let Err(e) = days::migrate(&database, timezone).await {
+            eprintln!("Failed to convert the dates of older daily logs: {}", e);
+        }
+        MongoLogs { database }
+    }
+}
+
+#[async_trait]
+impl LogStore for MongoLogs {
+    // daily documents are looked up by date, which makes concurrent upserts of a new day safe too
+    async fn open(&self, vid: &str) {
+        let index = IndexModel::builder()
+            .keys(doc! {"date": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        if let Err(e) = self.database.collection::<Document>(vid).create_index(index, None).await {
+            eprintln!("Failed to index the logs of {}: {}", vid, e);
+        }
+        if let Err(e) = trips::close(&self.database, vid).await {
+            eprintln!("Failed to close the earlier trips of {}: {}", vid, e);
+        }
+    }
+
+    // a batch turns into a single insert of its raw points & a single upsert per day it covers. Trips
+    // that changed & their routes are written along with it
+    async fn persist(&self, vid: &str, batch: &[(Message, DateTime<Utc>)], timezone: Tz, days: &[(NaiveDate, Summary)], changes: Changes) {
+        if !changes.is_empty() {
+            if let Err(e) = trips::save(&self.database, changes).await {
+                eprintln!("Failed to save the trips of {}: {}", vid, e);
+            }
+        }
+        if batch.is_empty() {
+            return;
+        }
+        if let Err(e) = telemetry::record(&self.database, batch, vid).await {
+            eprintln!("Failed to record telemetry for {}: {}", vid, e);
+        }
+        if let Err(e) = geo::remember(&self.database, vid, batch).await {
+            eprintln!("Failed to update the location of {}: {}", vid, e);
+        }
+
+        let collection = self.database.collection::<Document>(vid);
+        let options = UpdateOptions::builder().upsert(true).build();
+        for (day, summary) in days {
+            if let Err(e) = collection.update_one(doc! {"date": days::midnight(*day, timezone)}, summary.update(), options.clone()).await {
+                eprintln!("Failed to write the logs of {} for {}: {:?}", vid, day, e);
+            }
+        }
+    }
+
+    async fn log(&self, vid: &str, date: bson::DateTime) -> Result<Option<Log>, String> {
+        self.database.collection::<Log>(vid).find_one(doc! {"date": date}, None).await.map_err(text)
+    }
+
+    async fn logs(&self, vid: &str, span: Span) -> Result<Vec<Log>, String> {
+        let options = FindOptions::builder().sort(doc! {"date": 1}).build();
+        let mut cursor = self.database.collection::<Log>(vid).find(span.filter("date"), options).await.map_err(text)?;
+        let mut logs = Vec::new();
+        while let Some(log) = cursor.next().await {
+            logs.push(log.map_err(text)?);
+        }
+        Ok(logs)
+    }
+
+    async fn report(&self, vid: &str, span: Span, timezone: Tz, bucket: Bucket) -> Result<Value, String> {
+        reports::run(&self.database, vid, span, timezone, bucket).await.map_err(text)
+    }
+
+    // the rows are read from the database as they're streamed out
+    async fn export(&self, export: Export) -> Result<Rows, String> {
+        export::rows(&self.database, export).await.map_err(text)
+    }
+
+    async fn telemetry(&self, range: Range) -> Result<Value, String> {
+        telemetry::range(&self.database, range).await.map_err(text)
+    }
+
+    async fn trips(&self, vid: &str, limit: i64, before: Option<i64>) -> Result<Value, String> {
+        trips::list(&self.database, vid, limit, before).await.map_err(text)
+    }
+
+    async fn trip(&self, id: &ObjectId) -> Result<Option<(String, Value)>, String> {
+        trips::get(&self.database, id).await.map_err(text)
+    }
+
+    async fn route(&self, trip: &ObjectId, tolerance: Option<f64>) -> Result<Value, String> {
+        geo::route(&self.database, trip, tolerance).await.map_err(text)
+    }
+
+    async fn location(&self, vid: &str) -> Result<Option<(Location, bson::DateTime)>, String> {
+        geo::find(&self.database, vid).await.map_err(text)
+    }
+
+    async fn fences(&self, vid: &str) -> Result<Vec<Fence>, String> {
+        fences::load(&self.database, vid).await.map_err(text)
+    }
+
+    async fn add_fence(&self, fence: &Fence) -> Result<(), String> {
+        fences::insert(&self.database, fence).await.map_err(text)
+    }
+
+    async fn remove_fence(&self, vid: &str, id: &ObjectId) -> Result<bool, String> {
+        fences::delete(&self.database, vid, id).await.map_err(text)
+    }
+
+    async fn record_crossings(&self, crossings: &[Crossing]) -> Result<(), String> {
+        fences::record(&self.database, crossings).await.map_err(text)
+    }
+
+    async fn crossings(&self, vid: &str, limit: i64, before: Option<i64>) -> Result<Value, String> {
+        fences::events(&self.database, vid, limit, before).await.map_err(text)
+    }
+}
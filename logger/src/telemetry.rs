@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{self, doc, Bson, DateTime, Document},
+    error::{ErrorKind, Result},
+    options::{AggregateOptions, CreateCollectionOptions, IndexOptions, TimeseriesGranularity, TimeseriesOptions},
+    Database, IndexModel,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{Location, Message};
+
+// every message a vehicle broadcasts ends up here, one document per message
+pub const COLLECTION: &str = "telemetry";
+// returned by MongoDB when the collection is already there
+const NAMESPACE_EXISTS: i32 = 48;
+
+// a single telemetry reading, as stored in the time-series collection
+#[derive(Serialize, Deserialize)]
+struct Point {
+    ts: DateTime,
+    vid: String,
+    gear: Option<u32>,
+    rpm: Option<u32>,
+    speed: Option<u32>,
+    location: Option<Location>,
+    temp: Option<u32>,
+    fuel: Option<u32>,
+    odo: u32,
+    stressed: bool,
+}
+
+impl Point {
+    fn new(message: &Message, vid: &str, at: &chrono::DateTime<Utc>) -> Point {
+        Point {
+            ts: DateTime::from_millis(at.timestamp_millis()),
+            vid: vid.to_string(),
+            gear: message.gear,
+            rpm: message.rpm,
+            speed: message.speed,
+            location: message.location.clone(),
+            temp: message.temp,
+            fuel: message.fuel,
+            odo: message.odo,
+            stressed: message.stressed,
+        }
+    }
+}
+
+// makes sure the telemetry collection exists with the configured retention. MongoDB 5.0 & above
+// get a native time-series collection, older servers a plain one with a TTL index doing the same job
+pub async fn prepare(database: &Database, retention: Duration) -> Result<()> {
+    let options = CreateCollectionOptions::builder()
+        .timeseries(
+            TimeseriesOptions::builder()
+                .time_field(String::from("ts"))
+                .meta_field(Some(String::from("vid")))
+                .granularity(Some(TimeseriesGranularity::Seconds))
+                .build(),
+        )
+        .expire_after_seconds(retention)
+        .build();
+    match database.create_collection(COLLECTION, options).await {
+        Ok(_) => return Ok(()),
+        Err(e) => match *e.kind {
+            // retention may have changed since the collection was created
+            ErrorKind::Command(ref error) if error.code == NAMESPACE_EXISTS => {
+                let modified = database
+                    .run_command(doc! {"collMod": COLLECTION, "expireAfterSeconds": retention.as_secs() as i64}, None)
+                    .await;
+                if modified.is_ok() {
+                    return Ok(());
+                }
+            }
+            _ => eprintln!("Time-series collections aren't available, storing telemetry in a regular collection: {}", e),
+        },
+    }
+
+    let collection = database.collection::<Document>(COLLECTION);
+    collection
+        .create_index(IndexModel::builder().keys(doc! {"vid": 1, "ts": 1}).build(), None)
+        .await?;
+    // changing the expiry of an existing TTL index takes a collMod, so drop & recreate it instead
+    let _ = collection.drop_index("ts_ttl", None).await;
+    collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"ts": 1})
+                .options(IndexOptions::builder().name(String::from("ts_ttl")).expire_after(retention).build())
+                .build(),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// a reading the way it's stored, for stores that keep the documents themselves
+pub(crate) fn document(message: &Message, vid: &str, at: &chrono::DateTime<Utc>) -> Document {
+    bson::to_document(&Point::new(message, vid, at)).unwrap_or_default()
+}
+
+pub async fn record(database: &Database, batch: &[(Message, chrono::DateTime<Utc>)], vid: &str) -> Result<()> {
+    let points = batch.iter().map(|(message, at)| Point::new(message, vid, at));
+    database.collection::<Point>(COLLECTION).insert_many(points, None).await?;
+    Ok(())
+}
+
+// what to pull out of the time-series for a single vehicle
+pub struct Range {
+    pub vid: String,
+    // both in milliseconds since the epoch, start inclusive & end exclusive
+    pub start: i64,
+    pub end: i64,
+    // averages readings into buckets of this many seconds instead of returning every point
+    pub interval: Option<u64>,
+    pub limit: i64,
+}
+
+fn number(value: Option<&Bson>) -> Value {
+    match value {
+        Some(Bson::Int32(value)) => json!(value),
+        Some(Bson::Int64(value)) => json!(value),
+        // bucket averages are rounded to keep the payload small
+        Some(Bson::Double(value)) => json!((value * 100.0).round() / 100.0),
+        _ => Value::Null,
+    }
+}
+
+pub(crate) fn point(document: &Document) -> Value {
+    let ts = match document.get("ts") {
+        Some(Bson::DateTime(ts)) => ts.try_to_rfc3339_string().ok(),
+        _ => None,
+    };
+    let location = match document.get_document("location") {
+        Ok(location) => json!({
+            "latitude": number(location.get("latitude")),
+            "longitude": number(location.get("longitude")),
+        }),
+        Err(_) => Value::Null,
+    };
+    json!({
+        "ts": ts,
+        "gear": number(document.get("gear")),
+        "rpm": number(document.get("rpm")),
+        "speed": number(document.get("speed")),
+        "location": location,
+        "temp": number(document.get("temp")),
+        "fuel": number(document.get("fuel")),
+        "odo": number(document.get("odo")),
+        "stressed": document.get_bool("stressed").unwrap_or(false),
+    })
+}
+
+pub async fn range(database: &Database, range: Range) -> Result<Value> {
+    let mut pipeline = vec![
+        doc! {"$match": {"vid": &range.vid, "ts": {"$gte": DateTime::from_millis(range.start), "$lt": DateTime::from_millis(range.end)}}},
+        doc! {"$sort": {"ts": 1}},
+    ];
+    if let Some(interval) = range.interval {
+        let millis = interval as i64 * 1000;
+        // buckets start at multiples of the interval since the epoch, so the same range always
+        // lines up the same way. Plain arithmetic instead of $dateTrunc keeps this working before 5.0
+        pipeline.push(doc! {"$group": {
+            "_id": {"$subtract": [{"$toLong": "$ts"}, {"$mod": [{"$toLong": "$ts"}, millis]}]},
+            "gear": {"$last": "$gear"},
+            "rpm": {"$avg": "$rpm"},
+            "speed": {"$avg": "$speed"},
+            "location": {"$last": "$location"},
+            "temp": {"$avg": "$temp"},
+            "fuel": {"$avg": "$fuel"},
+            "odo": {"$max": "$odo"},
+            "stressed": {"$max": "$stressed"},
+        }});
+        pipeline.push(doc! {"$sort": {"_id": 1}});
+        pipeline.push(doc! {"$set": {"ts": {"$toDate": "$_id"}}});
+    }
+    // one more than asked for, to tell whether the range was cut short
+    pipeline.push(doc! {"$limit": range.limit + 1});
+
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let mut cursor = database.collection::<Document>(COLLECTION).aggregate(pipeline, options).await?;
+    let mut points = Vec::new();
+    while let Some(document) = cursor.next().await {
+        points.push(point(&document?));
+    }
+    Ok(window(&range, points))
+}
+
+fn float(value: Option<&Bson>) -> Option<f64> {
+    match value {
+        Some(Bson::Int32(value)) => Some(*value as f64),
+        Some(Bson::Int64(value)) => Some(*value as f64),
+        Some(Bson::Double(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+// the same buckets `range` has MongoDB make, for stores that don't have it. `points` have to be
+// oldest first
+pub(crate) fn average(points: &[Document], interval: u64) -> Vec<Document> {
+    let millis = interval as i64 * 1000;
+    let start = |point: &Document| point.get_datetime("ts").ok().map(|ts| ts.timestamp_millis() - ts.timestamp_millis().rem_euclid(millis));
+    let mut buckets = Vec::new();
+    let mut first = 0;
+    while first < points.len() {
+        let bucket = start(&points[first]);
+        let last = points[first..]
+            .iter()
+            .position(|point| start(point) != bucket)
+            .map_or(points.len(), |offset| first + offset);
+        let readings = &points[first..last];
+        let mean = |field: &str| {
+            let values: Vec<f64> = readings.iter().filter_map(|point| float(point.get(field))).collect();
+            match values.len() {
+                0 => Bson::Null,
+                count => Bson::Double(values.iter().sum::<f64>() / count as f64),
+            }
+        };
+        let latest = |field: &str| readings.last().and_then(|point| point.get(field)).cloned().unwrap_or(Bson::Null);
+        let odo = readings
+            .iter()
+            .filter_map(|point| point.get("odo"))
+            .max_by(|a, b| float(Some(a)).unwrap_or(0.0).total_cmp(&float(Some(b)).unwrap_or(0.0)));
+        buckets.push(doc! {
+            "ts": bucket.map(DateTime::from_millis),
+            "gear": latest("gear"),
+            "rpm": mean("rpm"),
+            "speed": mean("speed"),
+            "location": latest("location"),
+            "temp": mean("temp"),
+            "fuel": mean("fuel"),
+            "odo": odo.cloned().unwrap_or(Bson::Null),
+            "stressed": readings.iter().any(|point| point.get_bool("stressed").unwrap_or(false)),
+        });
+        first = last;
+    }
+    buckets
+}
+
+// `points` may have one more than the range's limit, which tells it was cut short
+pub(crate) fn window(range: &Range, mut points: Vec<Value>) -> Value {
+    let truncated = points.len() as i64 > range.limit;
+    points.truncate(range.limit as usize);
+    json!({
+        "vid": range.vid,
+        "start": DateTime::from_millis(range.start).try_to_rfc3339_string().ok(),
+        "end": DateTime::from_millis(range.end).try_to_rfc3339_string().ok(),
+        "interval": range.interval,
+        "count": points.len(),
+        "truncated": truncated,
+        "points": points,
+    })
+}
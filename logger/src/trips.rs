@@ -0,0 +1,317 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::stream::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    error::Result,
+    options::{FindOptions, ReplaceOptions},
+    Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    geo::{self, Waypoint},
+    ingest::travelled,
+    Location, Message,
+};
+
+pub const COLLECTION: &str = "trips";
+// vehicles that don't report a speed count as moving once the engine revs above idle
+const IDLE_RPM: u32 = 1200;
+
+// a single drive, from the vehicle starting to move until it's been standing still for a while
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Trip {
+    pub(crate) _id: ObjectId,
+    pub(crate) vid: String,
+    pub(crate) start: DateTime,
+    // the last time the vehicle was moving
+    end: DateTime,
+    // still being driven, or the server went down before it ended
+    pub(crate) ongoing: bool,
+    distance: u32,
+    // readings that came with a speed, the average is taken over these
+    readings: u32,
+    sum_speed: u64,
+    max_speed: u32,
+    stress_events: u32,
+    start_location: Option<Location>,
+    end_location: Option<Location>,
+    last_odometer: u32,
+}
+
+impl Trip {
+    fn new(vid: &str, message: &Message, at: DateTime) -> Trip {
+        Trip {
+            _id: ObjectId::new(),
+            vid: vid.to_string(),
+            start: at,
+            end: at,
+            ongoing: true,
+            distance: 0,
+            readings: 0,
+            sum_speed: 0,
+            max_speed: 0,
+            stress_events: 0,
+            start_location: message.location.clone(),
+            end_location: message.location.clone(),
+            last_odometer: message.odo,
+        }
+    }
+
+    fn add(&mut self, message: &Message, at: DateTime, moving: bool) {
+        self.distance += travelled(self.last_odometer, message.odo);
+        self.last_odometer = message.odo;
+        if let Some(speed) = message.speed {
+            self.readings += 1;
+            self.sum_speed += speed as u64;
+            self.max_speed = self.max_speed.max(speed);
+        }
+        if message.stressed {
+            self.stress_events += 1;
+        }
+        if message.location.is_some() {
+            if self.start_location.is_none() {
+                self.start_location = message.location.clone();
+            }
+            self.end_location = message.location.clone();
+        }
+        if moving {
+            self.end = at;
+        }
+    }
+
+    pub(crate) fn json(&self) -> Value {
+        let location = |location: &Option<Location>| match location {
+            Some(location) => json!({"latitude": location.latitude, "longitude": location.longitude}),
+            None => Value::Null,
+        };
+        json!({
+            "id": self._id.to_hex(),
+            "vid": self.vid,
+            "start": self.start.try_to_rfc3339_string().ok(),
+            "end": self.end.try_to_rfc3339_string().ok(),
+            "ongoing": self.ongoing,
+            "duration": (self.end.timestamp_millis() - self.start.timestamp_millis()) / 1000,
+            "distance": self.distance,
+            "average_speed": match self.readings {
+                0 => 0,
+                readings => self.sum_speed / readings as u64,
+            },
+            "max_speed": self.max_speed,
+            "stress_events": self.stress_events,
+            "start_location": location(&self.start_location),
+            "end_location": location(&self.end_location),
+        })
+    }
+}
+
+fn moving(message: &Message) -> bool {
+    match message.speed {
+        Some(speed) => speed > 0,
+        None => message.rpm.unwrap_or(0) > IDLE_RPM,
+    }
+}
+
+// what a Tracker has to write since it was last asked
+#[derive(Default)]
+pub struct Changes {
+    pub trips: Vec<Trip>,
+    pub route: Vec<Waypoint>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.trips.is_empty() && self.route.is_empty()
+    }
+}
+
+// splits a vehicle's readings into trips as they come in. Lives in the vehicle's ingestion actor
+pub struct Tracker {
+    vid: String,
+    // how long the vehicle has to stand still for its trip to end
+    idle_gap: Duration,
+    current: Option<Trip>,
+    // trips that need to be written, ended ones & the current one if it changed
+    pending: Vec<Trip>,
+    changed: bool,
+    // located readings of the current trip that haven't been written yet
+    route: Vec<Waypoint>,
+}
+
+impl Tracker {
+    pub fn new(vid: String, idle_gap: Duration) -> Tracker {
+        Tracker {
+            vid,
+            idle_gap,
+            current: None,
+            pending: Vec::new(),
+            changed: false,
+            route: Vec::new(),
+        }
+    }
+
+    pub fn track(&mut self, message: &Message, at: &chrono::DateTime<Utc>) {
+        self.check(at);
+        let at = DateTime::from_millis(at.timestamp_millis());
+        let moving = moving(message);
+        let trip = match &mut self.current {
+            Some(trip) => {
+                trip.add(message, at, moving);
+                trip
+            }
+            // idling before a trip doesn't count towards it
+            None if moving => {
+                let mut trip = Trip::new(&self.vid, message, at);
+                trip.add(message, at, moving);
+                self.current.insert(trip)
+            }
+            None => return,
+        };
+        self.route.extend(Waypoint::new(trip._id, &self.vid, message, at));
+        self.changed = true;
+    }
+
+    // ends the current trip once the vehicle hasn't moved for idle_gap
+    pub fn check(&mut self, now: &chrono::DateTime<Utc>) {
+        let idle = match &self.current {
+            Some(trip) => now.timestamp_millis() - trip.end.timestamp_millis(),
+            None => return,
+        };
+        if idle >= self.idle_gap.as_millis() as i64 {
+            self.finish();
+        }
+    }
+
+    // ends the current trip right away, like when the vehicle disconnects
+    pub fn finish(&mut self) {
+        if let Some(mut trip) = self.current.take() {
+            trip.ongoing = false;
+            self.pending.push(trip);
+            self.changed = false;
+        }
+    }
+
+    // everything that changed since the last call
+    pub fn take(&mut self) -> Changes {
+        let mut trips = std::mem::take(&mut self.pending);
+        if self.changed {
+            trips.extend(self.current.clone());
+            self.changed = false;
+        }
+        Changes {
+            trips,
+            route: std::mem::take(&mut self.route),
+        }
+    }
+}
+
+pub async fn prepare(database: &Database) -> Result<()> {
+    database
+        .collection::<Document>(COLLECTION)
+        .create_index(IndexModel::builder().keys(doc! {"vid": 1, "start": -1}).build(), None)
+        .await?;
+    Ok(())
+}
+
+// trips left ongoing when the server went down last time are over by now
+pub async fn close(database: &Database, vid: &str) -> Result<()> {
+    database
+        .collection::<Document>(COLLECTION)
+        .update_many(doc! {"vid": vid, "ongoing": true}, doc! {"$set": {"ongoing": false}}, None)
+        .await?;
+    Ok(())
+}
+
+pub async fn save(database: &Database, changes: Changes) -> Result<()> {
+    let collection = database.collection::<Trip>(COLLECTION);
+    let options = ReplaceOptions::builder().upsert(true).build();
+    for trip in changes.trips {
+        collection.replace_one(doc! {"_id": trip._id}, &trip, options.clone()).await?;
+    }
+    geo::record(database, changes.route).await
+}
+
+// a vehicle's trips, latest first. `before` pages back through older ones
+pub async fn list(database: &Database, vid: &str, limit: i64, before: Option<i64>) -> Result<Value> {
+    let mut filter = doc! {"vid": vid};
+    if let Some(before) = before {
+        filter.insert("start", doc! {"$lt": DateTime::from_millis(before)});
+    }
+    let options = FindOptions::builder().sort(doc! {"start": -1}).limit(limit).build();
+    let mut cursor = database.collection::<Trip>(COLLECTION).find(filter, options).await?;
+    let mut trips = Vec::new();
+    while let Some(trip) = cursor.next().await {
+        trips.push(trip?.json());
+    }
+    Ok(listing(vid, trips))
+}
+
+pub(crate) fn listing(vid: &str, trips: Vec<Value>) -> Value {
+    json!({
+        "vid": vid,
+        "count": trips.len(),
+        "trips": trips,
+    })
+}
+
+// the trip along with the vehicle it belongs to, so access can be checked
+pub async fn get(database: &Database, id: &ObjectId) -> Result<Option<(String, Value)>> {
+    let trip = database.collection::<Trip>(COLLECTION).find_one(doc! {"_id": id}, None).await?;
+    Ok(trip.map(|trip| (trip.vid.clone(), trip.json())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(speed: u32, odo: u32) -> Message {
+        Message {
+            gear: None,
+            rpm: Some(900),
+            speed: Some(speed),
+            location: None,
+            temp: None,
+            fuel: None,
+            odo,
+            stressed: false,
+        }
+    }
+
+    fn at(seconds: i64) -> chrono::DateTime<Utc> {
+        Utc.timestamp_opt(1_680_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn trips_start_moving_and_end_after_the_idle_gap() {
+        let mut tracker = Tracker::new(String::from("vid"), Duration::from_secs(300));
+        tracker.track(&message(0, 100), &at(0));
+        assert!(tracker.take().is_empty());
+
+        tracker.track(&message(30, 100), &at(10));
+        tracker.track(&message(50, 101), &at(70));
+        // stopping at a light doesn't end it
+        tracker.track(&message(0, 102), &at(130));
+        tracker.track(&message(40, 103), &at(250));
+        let ongoing = tracker.take().trips;
+        assert_eq!(ongoing.len(), 1);
+        assert!(ongoing[0].ongoing);
+
+        tracker.track(&message(0, 104), &at(400));
+        tracker.check(&at(551));
+        let ended = tracker.take().trips;
+        assert_eq!(ended.len(), 1);
+        let trip = ended[0].json();
+        assert_eq!(trip["ongoing"], false);
+        assert_eq!(trip["duration"], 240);
+        assert_eq!(trip["distance"], 4);
+        assert_eq!(trip["max_speed"], 50);
+        assert_eq!(trip["average_speed"], 24);
+
+        tracker.track(&message(20, 104), &at(600));
+        assert_ne!(tracker.take().trips[0]._id, ended[0]._id);
+    }
+}
@@ -1,10 +1,12 @@
 pub mod messages;
+pub mod pairing;
 pub mod ws;
 
 pub mod sockets {
-    use crate::messages::{ClientActorMessage, Connect, Disconnect, WsMessage};
+    use crate::messages::{ClientActorMessage, Connect, Disconnect, PairingRequest, Revoke, RoleChange, WsMessage};
+    use crate::pairing::PairingCodes;
     use crate::ws::{Action, Mode, Sender};
-    use logger::Logger;
+    use logger::{fences::Crossing, Logger};
     use actix::prelude::{Actor, Handler, Recipient};
     use actix::{Addr, SyncArbiter, SyncContext};
     use actix_web_actors::ws::CloseCode;
@@ -12,14 +14,19 @@ pub mod sockets {
     use std::collections::hash_map::Entry;
     use std::collections::{HashMap, HashSet};
     use std::sync::{Arc, RwLock};
-    use async_std::task;
+    use types::actors::users::Role;
+
+    // close code sent to users whose access to the vehicle was revoked while they were in its room
+    pub const ACCESS_REVOKED: CloseCode = CloseCode::Other(4003);
 
     pub struct Lobby {
         sessions: HashMap<String, Recipient<WsMessage>>,
         rooms: HashMap<String, HashSet<String>>, //room id  to list of users id
         admins: HashMap<String, String>,
+        clients: HashMap<String, (String, Role)>, //conn id to uid & role of the user behind it
         lock: Arc<RwLock<HashMap<String, String>>>,
         sessions_lock: Arc<RwLock<usize>>,
+        codes: PairingCodes,
         logger: Logger
     }
 
@@ -29,8 +36,10 @@ pub mod sockets {
                 sessions: HashMap::new(),
                 rooms: HashMap::new(),
                 admins: HashMap::new(),
+                clients: HashMap::new(),
                 lock: Arc::new(RwLock::new(HashMap::new())),
                 sessions_lock: Arc::new(RwLock::new(0)),
+                codes: PairingCodes::default(),
                 logger: Logger::default()
             }
         }
@@ -42,8 +51,10 @@ pub mod sockets {
                 sessions: self.sessions.clone(),
                 rooms: self.rooms.clone(),
                 admins: self.admins.clone(),
+                clients: self.clients.clone(),
                 lock: self.lock.clone(),
                 sessions_lock: self.sessions_lock.clone(),
+                codes: self.codes.clone(),
                 logger: self.logger.clone()
             }
         }
@@ -53,14 +64,17 @@ pub mod sockets {
         pub async fn new(
             lock: Arc<RwLock<HashMap<String, String>>>,
             sessions: Arc<RwLock<usize>>,
+            codes: PairingCodes,
+            logger: Logger,
         ) -> Addr<Self> {
-            let logger = Logger::new().await;
             let lobby = Lobby {
                 sessions: HashMap::new(),
                 rooms: HashMap::new(),
                 admins: HashMap::new(),
+                clients: HashMap::new(),
                 lock: lock.clone(),
                 sessions_lock: sessions,
+                codes,
                 logger
             };
             let addr = SyncArbiter::start(1, move || lobby.clone());
@@ -70,7 +84,7 @@ pub mod sockets {
         // send message to user in the room
         fn send_message(&self, message: &str, id_to: &String) {
             if let Some(socket_recipient) = self.sessions.get(id_to) {
-                let _ = socket_recipient.do_send(WsMessage {
+                socket_recipient.do_send(WsMessage {
                     message: message.to_string(),
                     id: id_to.to_string(),
                     action: Action::Send,
@@ -80,7 +94,7 @@ pub mod sockets {
         // send disconnect to an existing user in the room
         fn send_disconnect(&self, reason: &str, id_to: &String, code: CloseCode) {
             if let Some(socket_recipient) = self.sessions.get(id_to) {
-                let _ = socket_recipient.do_send(WsMessage {
+                socket_recipient.do_send(WsMessage {
                     message: reason.to_string(),
                     id: id_to.to_string(),
                     action: Action::Disconnect(code),
@@ -95,7 +109,7 @@ pub mod sockets {
             id_to: String,
             code: CloseCode,
         ) {
-            let _ = recipient.do_send(WsMessage {
+            recipient.do_send(WsMessage {
                 message: reason,
                 id: id_to,
                 action: Action::Disconnect(code),
@@ -106,7 +120,7 @@ pub mod sockets {
             let sessions = self.sessions.clone();
             if let Entry::Occupied(admin) = self.admins.entry(room) {
                 if let Some(socket_recipient) = sessions.get(admin.get()) {
-                    let _ = socket_recipient.do_send(WsMessage {
+                    socket_recipient.do_send(WsMessage {
                         message: message,
                         id: admin.get().to_string(),
                         action: Action::Send,
@@ -140,6 +154,17 @@ pub mod sockets {
                 .filter(|conn_id| *conn_id.to_owned() != id)
                 .for_each(|user_id| self.send_message(&*message, user_id));
         }
+        // tells everyone in the room, the vehicle included, that it entered or left a geofence
+        fn geofence(&self, room: &String, crossing: &Crossing) {
+            let message = match crossing.entered() {
+                true => format!("The vehicle entered {}", crossing.name()),
+                false => format!("The vehicle left {}", crossing.name()),
+            };
+            if let Some(room) = self.rooms.get(room) {
+                let event = json!({"event": "geofence", "client": { "uid": "", "conn_id": "" }, "message": message, "geofence": crossing.json(), "error": ""}).to_string();
+                room.iter().for_each(|conn_id| self.send_message(&event, conn_id));
+            }
+        }
         // given and room and a target id inside it, sends the message to that id only
         fn whisper(&self, message: String, room: String, target: String) {
             self.rooms
@@ -161,6 +186,7 @@ pub mod sockets {
 
         fn handle(&mut self, msg: Disconnect, _: &mut SyncContext<Self>) {
             if self.sessions.remove(&msg.id).is_some() {
+                let uid = self.clients.remove(&msg.id).map(|(uid, _)| uid).unwrap_or_default();
                 if let Entry::Occupied(admin) = self.admins.entry(msg.room_id.clone()) {
                     if &msg.id == admin.get() {
                         let rooms = self.rooms.get(&msg.room_id).unwrap();
@@ -175,8 +201,9 @@ pub mod sockets {
                         drop(admins);
                         self.rooms.remove(&msg.room_id);
                         self.admins.remove(&msg.room_id);
+                        self.logger.release(&msg.room_id);
                     } else {
-                        self.message_vehicle(msg.room_id.clone(), json!({"event": "disconnect", "client": { "uid": "", "conn_id": msg.id }, "message": "A client has disconnected", "error": "" }).to_string());
+                        self.message_vehicle(msg.room_id.clone(), json!({"event": "disconnect", "client": { "uid": uid, "conn_id": msg.id }, "message": "A client has disconnected", "error": "" }).to_string());
                         if let Some(lobby) = self.rooms.get_mut(&msg.room_id) {
                             lobby.remove(&msg.id);
                         }
@@ -199,8 +226,9 @@ pub mod sockets {
             match self.rooms.entry(msg.room_id.clone()) {
                 Entry::Occupied(mut o) => {
                     match msg.sender {
-                        Sender::Client(uid) => {
+                        Sender::Client(uid, role) => {
                             o.get_mut().insert(msg.self_id.clone());
+                            self.clients.insert(msg.self_id.clone(), (uid.clone(), role));
                             self.insert(msg.self_id.clone(), msg.addr, &msg.room_id);
                             self.message_vehicle(msg.room_id, json!({"event": "connected", "client": {"uid": uid, "conn_id": msg.self_id}}).to_string());
                             let mut sessions_guard = self.sessions_lock.write().unwrap();
@@ -233,7 +261,7 @@ pub mod sockets {
                     }
                 }
                 Entry::Vacant(o) => match msg.sender {
-                    Sender::Client(_) => self.send_disconnect_standalone(
+                    Sender::Client(..) => self.send_disconnect_standalone(
                         String::from("Vehicle isn't active at the moment. Try again later."),
                         &msg.addr,
                         msg.self_id,
@@ -263,6 +291,76 @@ pub mod sockets {
         }
     }
 
+    // handler for when a user is unpaired from a vehicle. Any of their connections in the room are
+    // closed right away instead of waiting for them to leave on their own
+    impl Handler<Revoke> for Lobby {
+        type Result = ();
+
+        fn handle(&mut self, msg: Revoke, _: &mut SyncContext<Self>) -> Self::Result {
+            let revoked: Vec<String> = match self.rooms.get(&msg.room_id) {
+                Some(room) => room
+                    .iter()
+                    .filter(|conn_id| matches!(self.clients.get(*conn_id), Some((uid, _)) if *uid == msg.uid))
+                    .cloned()
+                    .collect(),
+                None => return,
+            };
+            for conn_id in revoked {
+                self.send_disconnect(&json!({"event": "disconnect", "client": { "uid": msg.uid, "conn_id": conn_id }, "message": "Access to this vehicle was revoked", "error": ""}).to_string(), &conn_id, ACCESS_REVOKED);
+                self.message_vehicle(msg.room_id.clone(), json!({"event": "disconnect", "client": { "uid": msg.uid, "conn_id": conn_id }, "message": "A client's access was revoked", "error": ""}).to_string());
+                // the session is dropped here, so the Disconnect sent once the actor stops is a no-op
+                self.sessions.remove(&conn_id);
+                self.clients.remove(&conn_id);
+                if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+                    room.remove(&conn_id);
+                }
+                let mut sessions_guard = self.sessions_lock.write().unwrap();
+                let sessions = *sessions_guard;
+                *sessions_guard = sessions - 1;
+            }
+        }
+    }
+
+    // handler for when an owner changes a user's role, so it applies to connections already in the room
+    impl Handler<RoleChange> for Lobby {
+        type Result = ();
+
+        fn handle(&mut self, msg: RoleChange, _: &mut SyncContext<Self>) -> Self::Result {
+            if let Some(room) = self.rooms.get(&msg.room_id) {
+                for conn_id in room {
+                    if let Some((uid, role)) = self.clients.get_mut(conn_id) {
+                        if *uid == msg.uid {
+                            *role = msg.role;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // handler for when a vehicle asks for a pairing code to show its users
+    impl Handler<PairingRequest> for Lobby {
+        type Result = ();
+
+        fn handle(&mut self, msg: PairingRequest, _: &mut SyncContext<Self>) -> Self::Result {
+            if self.admins.get(&msg.room_id) != Some(&msg.id) {
+                return;
+            }
+            let code = self.codes.issue(&msg.room_id);
+            self.send_message(
+                &json!({
+                    "event": "code",
+                    "client": { "uid": "", "conn_id": msg.id },
+                    "message": code,
+                    "expires_in": self.codes.ttl().as_secs(),
+                    "error": ""
+                })
+                .to_string(),
+                &msg.id,
+            );
+        }
+    }
+
     // handler for when a client sends messages
     // pending work here to handle requests appropriately
     impl Handler<ClientActorMessage> for Lobby {
@@ -270,15 +368,27 @@ pub mod sockets {
 
         // echo the message back to all clients
         fn handle(&mut self, msg: ClientActorMessage, _: &mut SyncContext<Self>) -> Self::Result {
+            if let Some((uid, role)) = self.clients.get(&msg.id) {
+                if !msg.mode.permitted(*role) {
+                    self.send_message(&json!({"event": "error", "client": { "uid": uid, "conn_id": msg.id }, "message": "", "error": "Your role on this vehicle doesn't allow messages in this mode"}).to_string(), &msg.id);
+                    return;
+                }
+            }
             match msg.mode {
                 Mode::Broadcast => {
-                    let mut logger = self.logger.clone();
-                    let data = msg.msg.message.clone();
-                    let vid = msg.room_id.clone();
-                    task::spawn(async move {
-                        logger.log(data, vid).await;
-                    });
-                    self.broadcast(msg.msg.to_string(), msg.room_id.clone(), msg.id.clone())
+                    // users' broadcasts are only passed on, just what the vehicle sends is its telemetry
+                    let crossings = match self.admins.get(&msg.room_id) == Some(&msg.id) {
+                        true => {
+                            let crossings = self.logger.crossings(&msg.room_id, &msg.msg.message);
+                            self.logger.log(msg.msg.message.clone(), msg.room_id.clone());
+                            crossings
+                        }
+                        false => Vec::new(),
+                    };
+                    self.broadcast(msg.msg.to_string(), msg.room_id.clone(), msg.id.clone());
+                    for crossing in crossings {
+                        self.geofence(&msg.room_id, &crossing);
+                    }
                 },
                 Mode::Whisper(target) => self.whisper(msg.msg.to_string(), msg.room_id, target),
                 _ => self.message_vehicle(msg.room_id.clone(), msg.msg.to_string()),
@@ -1,5 +1,6 @@
 use actix::prelude::{Message, Recipient};// use serde_json::Value;
 use crate::ws::{Sender, Action, Mode, ClientMessage};
+use types::actors::users::Role;
 
 //WsConn responds to this to pipe it through to the actual client
 #[derive(Message)]
@@ -30,6 +31,31 @@ pub struct Disconnect {
     pub reason: Option<String>
 }
 
+//vehicle sends this to the lobby to get a fresh pairing code
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PairingRequest {
+    pub room_id: String,
+    pub id: String
+}
+
+//server sends this to the lobby when a user loses access to a vehicle
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Revoke {
+    pub room_id: String,
+    pub uid: String
+}
+
+//server sends this to the lobby when an owner changes another user's role
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoleChange {
+    pub room_id: String,
+    pub uid: String,
+    pub role: Role
+}
+
 //client sends this to the lobby for the lobby to echo out.
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -0,0 +1,173 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// no 0/O or 1/I so codes can be read off a screen without guessing
+const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+// expired & used codes are remembered a while longer so users get a meaningful error
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+
+struct Code {
+    vid: String,
+    issued: Instant,
+    used: bool,
+}
+
+// result of a user trying to pair with a code
+pub enum Redemption {
+    Valid(String),
+    Unknown,
+    Expired,
+    Used,
+    TooManyAttempts,
+}
+
+// short lived pairing codes requested by vehicles over their admin session.
+// shared between the Lobby (which issues them) and the workers (which redeem them)
+#[derive(Clone)]
+pub struct PairingCodes {
+    codes: Arc<RwLock<HashMap<String, Code>>>,
+    // failed attempts per user, along with when the first one in the current window happened
+    attempts: Arc<RwLock<HashMap<String, (u32, Instant)>>>,
+    // the same per address the attempts came from, so signing up more accounts doesn't buy more guesses
+    sources: Arc<RwLock<HashMap<String, (u32, Instant)>>>,
+    ttl: Duration,
+    length: usize,
+    max_attempts: u32,
+    max_guesses: u32,
+}
+
+impl Default for PairingCodes {
+    fn default() -> Self {
+        PairingCodes::new(Duration::from_secs(5 * 60), 6, 5, 20)
+    }
+}
+
+impl PairingCodes {
+    pub fn new(ttl: Duration, length: usize, max_attempts: u32, max_guesses: u32) -> PairingCodes {
+        PairingCodes {
+            codes: Arc::new(RwLock::new(HashMap::new())),
+            attempts: Arc::new(RwLock::new(HashMap::new())),
+            sources: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+            length: length.clamp(6, 8),
+            max_attempts,
+            max_guesses,
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // issuing a new code for a vehicle invalidates any of its codes that haven't been used yet
+    pub fn issue(&self, vid: &str) -> String {
+        let mut codes = self.codes.write().unwrap();
+        codes.retain(|_, code| code.issued.elapsed() < self.ttl + RETENTION);
+        codes.retain(|_, code| code.vid != vid || code.used);
+
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code: String = (0..self.length)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect();
+            if !codes.contains_key(&code) {
+                break code;
+            }
+        };
+        codes.insert(
+            code.clone(),
+            Code {
+                vid: vid.to_string(),
+                issued: Instant::now(),
+                used: false,
+            },
+        );
+        code
+    }
+
+    fn expired(&self, code: &Code) -> bool {
+        code.issued.elapsed() >= self.ttl
+    }
+
+    // the vehicle the code was issued to. Nothing is used up yet, so the code still works if pairing
+    // fails for some other reason. Codes that don't exist count against the user & the address
+    // they came from, codes that were used or ran out were real codes & aren't guesses
+    pub fn check(&self, uid: &str, source: &str, code: &str) -> Redemption {
+        let mut attempts = self.attempts.write().unwrap();
+        let mut sources = self.sources.write().unwrap();
+        attempts.retain(|_, (_, since)| since.elapsed() < self.ttl);
+        sources.retain(|_, (_, since)| since.elapsed() < self.ttl);
+        if attempts.get(uid).is_some_and(|(count, _)| *count >= self.max_attempts)
+            || sources.get(source).is_some_and(|(count, _)| *count >= self.max_guesses)
+        {
+            return Redemption::TooManyAttempts;
+        }
+
+        let codes = self.codes.read().unwrap();
+        let result = match codes.get(&code.to_uppercase()) {
+            Some(code) if code.used => Redemption::Used,
+            Some(code) if self.expired(code) => Redemption::Expired,
+            Some(code) => Redemption::Valid(code.vid.clone()),
+            None => Redemption::Unknown,
+        };
+
+        if matches!(result, Redemption::Unknown) {
+            for (counts, key) in [(&mut attempts, uid), (&mut sources, source)] {
+                counts.entry(key.to_string()).and_modify(|(count, _)| *count += 1).or_insert((1, Instant::now()));
+            }
+        }
+        result
+    }
+
+    // uses up a code that passed `check`. False when someone else got to it first
+    pub fn redeem(&self, code: &str) -> bool {
+        match self.codes.write().unwrap().get_mut(&code.to_uppercase()) {
+            Some(code) if !code.used && !self.expired(code) => {
+                code.used = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checking_a_code_doesnt_use_it_up() {
+        let codes = PairingCodes::default();
+        let code = codes.issue("vid");
+        assert!(matches!(codes.check("alice", "10.0.0.1", &code), Redemption::Valid(vid) if vid == "vid"));
+        assert!(matches!(codes.check("bob", "10.0.0.2", &code.to_lowercase()), Redemption::Valid(_)));
+        assert!(codes.redeem(&code));
+        assert!(!codes.redeem(&code));
+        assert!(matches!(codes.check("bob", "10.0.0.2", &code), Redemption::Used));
+    }
+
+    // a guess that misses costs the guesser, not the codes other vehicles are showing
+    #[test]
+    fn wrong_guesses_only_count_against_their_source() {
+        let codes = PairingCodes::new(Duration::from_secs(60), 6, 5, 10);
+        let code = codes.issue("vid");
+        for user in 0..5 {
+            for _ in 0..2 {
+                assert!(matches!(codes.check(&user.to_string(), "10.0.0.1", "AAAAAA"), Redemption::Unknown));
+            }
+        }
+        // the address is out of guesses whichever account it uses
+        assert!(matches!(codes.check("mallory", "10.0.0.1", &code), Redemption::TooManyAttempts));
+        assert!(matches!(codes.check("alice", "10.0.0.2", &code), Redemption::Valid(_)));
+        assert!(codes.redeem(&code));
+
+        // running into codes that were used doesn't count
+        for _ in 0..10 {
+            assert!(matches!(codes.check("bob", "10.0.0.3", &code), Redemption::Used));
+        }
+        let fresh = codes.issue("vid");
+        assert!(matches!(codes.check("bob", "10.0.0.3", &fresh), Redemption::Valid(_)));
+    }
+}
@@ -1,9 +1,6 @@
 extern crate types;
 
-#[path = "./messages.rs"]
-mod messages;
-
-use crate::messages::{ClientActorMessage, Connect, Disconnect, WsMessage};
+use crate::messages::{ClientActorMessage, Connect, Disconnect, PairingRequest, WsMessage};
 use crate::sockets::Lobby;
 use actix::{fut, ActorContext, ActorFutureExt};
 use actix::{Actor, Addr, ContextFutureSpawner, Running, StreamHandler, WrapFuture};
@@ -15,9 +12,26 @@ use serde_json::json;
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use logger::Message;
+use types::actors::users::Role;
+
+// the longest reason a close frame can carry
+const MAX_CLOSE_REASON: usize = 123;
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+// how often connections are pinged, and how long they can go without answering before being dropped
+#[derive(Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
 
 // each ClientActorMessage will have a mode indicating the mode of that message
 // Lobby uses this to send messages appropriately
@@ -29,11 +43,21 @@ pub enum Mode {
     Request,         // when the client has to request certain specific data from the vehicle.
 }
 
+impl Mode {
+    // whether a user with the given role may send messages in this mode
+    pub fn permitted(&self, role: Role) -> bool {
+        match self {
+            Mode::Action => role != Role::Viewer,
+            _ => true,
+        }
+    }
+}
+
 // each Connect message will have a sender indicating who that request came from
 // used by lobby to handle connect requests appropriately
 #[derive(Clone)]
 pub enum Sender {
-    Client(String),
+    Client(String, Role),
     Admin,
     Pair(String),
 }
@@ -56,12 +80,19 @@ pub struct ClientMessage {
     pub attachments: Vec<String>,
 }
 
+// vehicles ask for pairing codes with a bare {"mode": "pair"}, outside of the regular message format
+#[derive(Deserialize)]
+struct Command {
+    mode: String,
+}
+
 pub struct WsConn {
     id: String,
     lobby_addr: Addr<Lobby>,
     hb: Instant,
     room: String,
     sender: Sender,
+    heartbeat: Heartbeat,
 }
 
 fn draft_message(event: &str, message: &str, error: &str, conn_id: &str, uid: &str) -> String {
@@ -117,18 +148,19 @@ impl ClientMessage {
 }
 
 impl WsConn {
-    pub fn new(room: String, id: String, lobby: Addr<Lobby>, sender: Sender) -> WsConn {
+    pub fn new(room: String, id: String, lobby: Addr<Lobby>, sender: Sender, heartbeat: Heartbeat) -> WsConn {
         WsConn {
             id,
             room,
             hb: Instant::now(),
             lobby_addr: lobby,
             sender,
+            heartbeat,
         }
     }
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.heartbeat.interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.heartbeat.timeout {
                 // println!("Disconnecting failed heartbeat");
                 act.lobby_addr.do_send(Disconnect {
                     id: act.id.clone(),
@@ -204,6 +236,16 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
             }
             Ok(ws::Message::Nop) => (),
             Ok(Text(s)) => {
+                if matches!(serde_json::from_str::<Command>(&s), Ok(command) if command.mode == "pair") {
+                    match self.sender {
+                        Sender::Admin => self.lobby_addr.do_send(PairingRequest {
+                            room_id: self.room.clone(),
+                            id: self.id.clone(),
+                        }),
+                        _ => ctx.text(draft_message("error", "", "Only the vehicle can request a pairing code", &self.id, "")),
+                    }
+                    return;
+                }
                 match serde_json::from_str::<ClientMessage>(&s) {
                     Ok(value) => {
                         match value.get_mode() {
@@ -233,10 +275,16 @@ impl Handler<WsMessage> for WsConn {
         match msg.action {
             Action::Send => ctx.text(msg.message),
             Action::Disconnect(code) => {
-                ctx.close(Some(ws::CloseReason {
-                    code,
-                    description: Some(msg.message),
-                }));
+                // longer reasons go out as an event first, otherwise clients drop the connection with a
+                // protocol error instead
+                let description = match msg.message.len() > MAX_CLOSE_REASON {
+                    true => {
+                        ctx.text(msg.message);
+                        None
+                    }
+                    false => Some(msg.message),
+                };
+                ctx.close(Some(ws::CloseReason { code, description }));
                 ctx.stop();
             }
             Action::Pair => {}
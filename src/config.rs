@@ -0,0 +1,443 @@
+use std::{env, fs, path::Path};
+
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use toml::{value::Table, Value};
+
+use crate::credentials::Credentials;
+
+// used when neither --config nor ALPADRIVE_CONFIG point somewhere else. It's fine if it doesn't exist
+const DEFAULT_PATH: &str = "alpadrive.toml";
+const ENV_PREFIX: &str = "ALPADRIVE_";
+// environment variables that were around before the config file, mapped to their setting
+const ENV_ALIASES: [(&str, &str); 1] = [("ALPADRIVE_TOKEN_SECRET", "auth.token_secret")];
+
+const USAGE: &str = "Usage: server [OPTIONS]
+
+Options:
+    --config <path>        TOML file to read the configuration from (default: alpadrive.toml)
+    --host <host>          address to bind to, same as --set server.host=<host>
+    --port <port>          port to bind to, same as --set server.port=<port>
+    --database-uri <uri>   MongoDB connection string, same as --set database.uri=<uri>
+    --set <key>=<value>    override any setting, e.g. --set sockets.client_timeout=20
+    --help                 print this message
+
+Settings are layered: built in defaults < config file < ALPADRIVE_<SECTION>__<KEY> environment variables < flags.";
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: String::from("127.0.0.1"),
+            port: 7878,
+        }
+    }
+}
+
+// where users, vehicles & logs are kept. memory keeps them in the server itself, for local demos &
+// tests that shouldn't need a MongoDB server
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Mongodb,
+    Memory,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: Backend,
+    pub uri: String,
+    pub app_name: String,
+    pub name: String,
+    pub logs: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: Backend::Mongodb,
+            uri: String::from("mongodb://localhost:27017/"),
+            app_name: String::from("alpadrive"),
+            name: String::from("alpadrive"),
+            logs: String::from("alpadrive-logs"),
+        }
+    }
+}
+
+// TLS is turned on by setting both cert & key. Certificates are reloaded when the files change,
+// so renewing them doesn't need a restart
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM encoded certificate chain, leaf first
+    pub cert: Option<String>,
+    // PEM encoded PKCS#8, RSA or SEC1 private key
+    pub key: Option<String>,
+    pub reload_interval: u64,
+    // plain HTTP listener on redirect_port that sends everything over to HTTPS
+    pub redirect: bool,
+    pub redirect_port: u16,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: None,
+            key: None,
+            reload_interval: 30,
+            redirect: false,
+            redirect_port: 80,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some() && self.key.is_some()
+    }
+}
+
+// all durations are in seconds
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketsConfig {
+    pub heartbeat_interval: u64,
+    pub client_timeout: u64,
+}
+
+impl Default for SocketsConfig {
+    fn default() -> Self {
+        SocketsConfig {
+            heartbeat_interval: 5,
+            client_timeout: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // tokens signed with a random secret don't survive a restart, so set this in production
+    pub token_secret: Option<String>,
+    // 64 hex characters. Device secrets are stored encrypted with it, so vehicles registered while it
+    // isn't set have to rotate their secret after a restart
+    pub device_key: Option<String>,
+    pub access_ttl: u64,
+    pub refresh_ttl: u64,
+    pub challenge_ttl: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            token_secret: None,
+            device_key: None,
+            access_ttl: 15 * 60,
+            refresh_ttl: 30 * 24 * 60 * 60,
+            challenge_ttl: 60,
+        }
+    }
+}
+
+// Argon2id cost parameters. Changing them rehashes passwords as users log in
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    pub memory_cost: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        CredentialsConfig {
+            memory_cost: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // raw points older than this are dropped by MongoDB. Daily aggregates aren't affected
+    pub retention_days: u64,
+    // broadcasts are buffered per vehicle & written every flush_interval seconds, or as soon as
+    // batch_size of them are waiting
+    pub flush_interval: u64,
+    pub batch_size: usize,
+    // IANA timezone that splits daily logs into days, for vehicles that haven't set their own
+    pub timezone: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            retention_days: 30,
+            flush_interval: 1,
+            batch_size: 50,
+            timezone: String::from("UTC"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TripsConfig {
+    // seconds a vehicle has to stand still for its trip to end
+    pub idle_gap: u64,
+}
+
+impl Default for TripsConfig {
+    fn default() -> Self {
+        TripsConfig { idle_gap: 5 * 60 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairingConfig {
+    pub ttl: u64,
+    pub length: usize,
+    // wrong codes a user can try before having to wait for `ttl`
+    pub max_attempts: u32,
+    // wrong codes one address can try before having to wait for `ttl`, whichever users they're for
+    pub max_guesses: u32,
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        PairingConfig {
+            ttl: 5 * 60,
+            length: 6,
+            max_attempts: 5,
+            max_guesses: 20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub database: DatabaseConfig,
+    pub sockets: SocketsConfig,
+    pub auth: AuthConfig,
+    pub credentials: CredentialsConfig,
+    pub pairing: PairingConfig,
+    pub telemetry: TelemetryConfig,
+    pub trips: TripsConfig,
+}
+
+// what was passed on the command line
+#[derive(Default)]
+struct Args {
+    config: Option<String>,
+    overrides: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            let (flag, inline) = match flag.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                _ => (flag, None),
+            };
+            let value = match inline.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(format!("{} expects a value. Run with --help to see all options", flag)),
+            };
+            match flag.as_str() {
+                "--config" => parsed.config = Some(value),
+                "--host" => parsed.overrides.push((String::from("server.host"), value)),
+                "--port" => parsed.overrides.push((String::from("server.port"), value)),
+                "--database-uri" => parsed.overrides.push((String::from("database.uri"), value)),
+                "--set" => match value.split_once('=') {
+                    Some((key, value)) => parsed.overrides.push((key.to_string(), value.to_string())),
+                    None => return Err(format!("--set expects <key>=<value>, got {}", value)),
+                },
+                _ => return Err(format!("Unknown option {}. Run with --help to see all options", flag)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+// overlays the values from a config file on top of the defaults, section by section
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// sets a dotted key like sockets.client_timeout from a raw string, using the type of the default value
+fn set(table: &mut Table, key: &str, raw: &str) -> Result<(), String> {
+    let (section, field) = match key.split_once('.') {
+        Some(path) => path,
+        None => return Err(format!("{} isn't a setting. Settings look like <section>.<key>", key)),
+    };
+    let section = match table.get_mut(section) {
+        Some(Value::Table(section)) => section,
+        _ => return Err(format!("{} isn't a setting, there's no [{}] section", key, section)),
+    };
+    let value = match section.get(field) {
+        Some(Value::Integer(_)) => match raw.parse() {
+            Ok(value) => Value::Integer(value),
+            Err(_) => return Err(format!("{} expects a whole number, got {}", key, raw)),
+        },
+        Some(Value::Boolean(_)) => match raw.parse() {
+            Ok(value) => Value::Boolean(value),
+            Err(_) => return Err(format!("{} expects true or false, got {}", key, raw)),
+        },
+        // strings & optional settings without a default. Unknown keys are caught when deserializing
+        _ => Value::String(raw.to_string()),
+    };
+    section.insert(field.to_string(), value);
+    Ok(())
+}
+
+fn env_overrides() -> Vec<(String, String)> {
+    env::vars()
+        .filter_map(|(name, value)| {
+            if let Some((_, key)) = ENV_ALIASES.iter().find(|(alias, _)| *alias == name) {
+                return Some((key.to_string(), value));
+            }
+            let key = name.strip_prefix(ENV_PREFIX)?;
+            let (section, field) = key.split_once("__")?;
+            Some((format!("{}.{}", section.to_lowercase(), field.to_lowercase()), value))
+        })
+        .collect()
+}
+
+impl Config {
+    // builds the configuration from all layers, reporting every problem at once instead of the first
+    pub fn load() -> Result<Config, Vec<String>> {
+        let args = Args::parse(env::args().skip(1)).map_err(|e| vec![e])?;
+        let mut table = match Value::try_from(Config::default()) {
+            Ok(Value::Table(table)) => table,
+            _ => return Err(vec![String::from("Failed to build the default configuration")]),
+        };
+
+        let path = args.config.clone().or_else(|| env::var("ALPADRIVE_CONFIG").ok());
+        let required = path.is_some();
+        let path = path.unwrap_or_else(|| DEFAULT_PATH.to_string());
+        if required || Path::new(&path).exists() {
+            let contents = fs::read_to_string(&path).map_err(|e| vec![format!("Failed to read {}: {}", path, e)])?;
+            let file = contents.parse::<Table>().map_err(|e| vec![format!("{} isn't valid TOML: {}", path, e)])?;
+            merge(&mut table, file);
+        }
+
+        let errors: Vec<String> = env_overrides()
+            .into_iter()
+            .chain(args.overrides)
+            .filter_map(|(key, value)| set(&mut table, &key, &value).err())
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let config: Config = Value::Table(table).try_into().map_err(|e: toml::de::Error| vec![e.message().to_string()])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.server.port == 0 {
+            errors.push(String::from("server.port can't be 0"));
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            errors.push(String::from("tls.cert and tls.key have to be set together"));
+        }
+        for path in [&self.tls.cert, &self.tls.key].into_iter().flatten() {
+            if !Path::new(path).is_file() {
+                errors.push(format!("{} doesn't exist or isn't a file", path));
+            }
+        }
+        if self.tls.reload_interval == 0 {
+            errors.push(String::from("tls.reload_interval has to be at least 1 second"));
+        }
+        if self.tls.redirect && !self.tls.enabled() {
+            errors.push(String::from("tls.redirect needs tls.cert and tls.key, there's nothing to redirect to"));
+        }
+        if self.tls.redirect && self.tls.redirect_port == self.server.port {
+            errors.push(String::from("tls.redirect_port has to be different from server.port"));
+        }
+        let mongodb = self.database.uri.starts_with("mongodb://") || self.database.uri.starts_with("mongodb+srv://");
+        if self.database.backend == Backend::Mongodb && !mongodb {
+            errors.push(String::from("database.uri has to be a mongodb:// or mongodb+srv:// connection string"));
+        }
+        if self.database.name.is_empty() || self.database.logs.is_empty() {
+            errors.push(String::from("database.name and database.logs can't be empty"));
+        }
+        if self.database.name == self.database.logs {
+            errors.push(String::from("database.name and database.logs have to be different databases"));
+        }
+        if self.sockets.heartbeat_interval == 0 {
+            errors.push(String::from("sockets.heartbeat_interval has to be at least 1 second"));
+        }
+        if self.sockets.client_timeout <= self.sockets.heartbeat_interval {
+            errors.push(String::from("sockets.client_timeout has to be longer than sockets.heartbeat_interval"));
+        }
+        if let Some(secret) = &self.auth.token_secret {
+            if secret.len() < 32 {
+                errors.push(String::from("auth.token_secret has to be at least 32 characters long"));
+            }
+        }
+        if let Some(key) = &self.auth.device_key {
+            if hex::decode(key).map_or(true, |key| key.len() != 32) {
+                errors.push(String::from("auth.device_key has to be 64 hex characters"));
+            }
+        }
+        if self.auth.access_ttl == 0 || self.auth.challenge_ttl == 0 {
+            errors.push(String::from("auth.access_ttl and auth.challenge_ttl have to be at least 1 second"));
+        }
+        if self.auth.refresh_ttl <= self.auth.access_ttl {
+            errors.push(String::from("auth.refresh_ttl has to be longer than auth.access_ttl"));
+        }
+        if let Err(e) = Credentials::new(self.credentials.memory_cost, self.credentials.iterations, self.credentials.parallelism) {
+            errors.push(format!("credentials has invalid Argon2 parameters: {}", e));
+        }
+        if !(6..=8).contains(&self.pairing.length) {
+            errors.push(String::from("pairing.length has to be between 6 and 8"));
+        }
+        if self.pairing.ttl == 0 || self.pairing.max_attempts == 0 || self.pairing.max_guesses == 0 {
+            errors.push(String::from("pairing.ttl, pairing.max_attempts and pairing.max_guesses have to be at least 1"));
+        }
+        if self.telemetry.retention_days == 0 {
+            errors.push(String::from("telemetry.retention_days has to be at least 1"));
+        }
+        if self.telemetry.flush_interval == 0 || self.telemetry.batch_size == 0 {
+            errors.push(String::from("telemetry.flush_interval and telemetry.batch_size have to be at least 1"));
+        }
+        if self.telemetry.timezone.parse::<Tz>().is_err() {
+            errors.push(format!("telemetry.timezone has to be an IANA timezone like Asia/Kolkata, not {}", self.telemetry.timezone));
+        }
+        if self.trips.idle_gap == 0 {
+            errors.push(String::from("trips.idle_gap has to be at least 1 second"));
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
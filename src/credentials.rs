@@ -0,0 +1,118 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::sync::{Arc, OnceLock};
+
+// outcome of checking a password against whatever is stored in User.password
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Valid,
+    // the password matched, but the stored value is either legacy plaintext or
+    // was hashed with parameters other than the current ones. Store a fresh hash.
+    Rehash,
+    Invalid,
+}
+
+// hashes & verifies user passwords with Argon2id. Every hash gets its own random salt,
+// which is encoded in the PHC string along with the parameters used to produce it
+#[derive(Clone)]
+pub struct Credentials {
+    params: Params,
+    // a hash of a random password no one knows, made with the current parameters the first time
+    // it's needed. Logins for users that don't exist are checked against it so they take as long
+    // as any other
+    decoy: Arc<OnceLock<String>>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials::with(Params::DEFAULT)
+    }
+}
+
+impl Credentials {
+    // memory cost is in KiB. The defaults follow the OWASP recommendation of m=19456, t=2, p=1
+    pub fn new(memory_cost: u32, iterations: u32, parallelism: u32) -> Result<Self, argon2::Error> {
+        Ok(Credentials::with(Params::new(memory_cost, iterations, parallelism, None)?))
+    }
+
+    fn with(params: Params) -> Credentials {
+        Credentials {
+            params,
+            decoy: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn decoy(&self) -> &str {
+        // the parameters were already checked, so hashing can't fail
+        self.decoy.get_or_init(|| self.hash(SaltString::generate(&mut OsRng).as_str()).unwrap_or_default())
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, argon2::password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self.hasher().hash_password(password.as_bytes(), &salt)?.to_string())
+    }
+
+    pub fn verify(&self, password: &str, stored: &str) -> Verification {
+        match PasswordHash::new(stored) {
+            Ok(hash) => {
+                // verify with whatever the hash says it was made with, not the current params
+                if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+                    return Verification::Invalid;
+                }
+                if self.is_current(&hash) {
+                    Verification::Valid
+                } else {
+                    Verification::Rehash
+                }
+            }
+            // anything that isn't a PHC string was stored before hashing was introduced
+            Err(_) => match constant_time_eq(password.as_bytes(), stored.as_bytes()) {
+                true => Verification::Rehash,
+                false => Verification::Invalid,
+            },
+        }
+    }
+
+    fn is_current(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into()) {
+            return false;
+        }
+        match Params::try_from(hash) {
+            Ok(params) => {
+                params.m_cost() == self.params.m_cost()
+                    && params.t_cost() == self.params.t_cost()
+                    && params.p_cost() == self.params.p_cost()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// compares without bailing out on the first mismatch so response timing doesn't leak the prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // checking a password against the decoy costs a full verify & never lets anyone in
+    #[test]
+    fn the_decoy_is_a_current_hash_nothing_matches() {
+        let credentials = Credentials::new(64, 1, 1).unwrap();
+        let decoy = PasswordHash::new(credentials.decoy()).unwrap();
+        assert!(credentials.is_current(&decoy));
+        assert_eq!(credentials.verify("", credentials.decoy()), Verification::Invalid);
+        assert_eq!(credentials.verify("correct horse battery 9", credentials.decoy()), Verification::Invalid);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LENGTH: usize = 12;
+// challenges a vehicle can have out at once. Asking for one isn't authenticated, so past this the
+// oldest are dropped instead of letting the map grow
+const OUTSTANDING: usize = 8;
+
+// a freshly generated device secret. `secret` is handed to the vehicle exactly once,
+// only `sealed` is ever stored on the Vehicle document
+pub struct DeviceSecret {
+    pub secret: String,
+    pub sealed: String,
+}
+
+fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// the key vehicles sign challenges with is the SHA-256 digest of their secret. It's stored encrypted
+// with this server side key, so whoever gets to read the vehicles collection still can't sign anything
+#[derive(Clone)]
+pub struct DeviceKey {
+    cipher: Aes256Gcm,
+}
+
+impl DeviceKey {
+    pub fn new(key: &[u8]) -> Option<DeviceKey> {
+        let cipher = Aes256Gcm::new_from_slice(key).ok()?;
+        Some(DeviceKey { cipher })
+    }
+
+    // gone with the restart, along with every secret sealed with it
+    pub fn random() -> DeviceKey {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        DeviceKey { cipher: Aes256Gcm::new_from_slice(&key).unwrap() }
+    }
+
+    pub fn generate(&self) -> DeviceSecret {
+        let secret = random_hex(32);
+        let sealed = self.seal(&Sha256::digest(secret.as_bytes()));
+        DeviceSecret { secret, sealed }
+    }
+
+    fn seal(&self, signing_key: &[u8]) -> String {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        // encrypting a 32 byte key into a Vec can't fail
+        let mut sealed = self.cipher.encrypt(Nonce::from_slice(&nonce), signing_key).unwrap();
+        sealed.splice(0..0, nonce);
+        hex::encode(sealed)
+    }
+
+    fn open(&self, stored: &str) -> Option<Vec<u8>> {
+        let sealed = hex::decode(stored).ok()?;
+        if sealed.len() <= NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, key) = sealed.split_at(NONCE_LENGTH);
+        self.cipher.decrypt(Nonce::from_slice(nonce), key).ok()
+    }
+
+    // the vehicle proves it holds the secret by signing a challenge with HMAC-SHA256,
+    // keyed with the SHA-256 digest of its secret. The secret itself never goes over the wire
+    pub fn verify(&self, stored: &str, challenge: &str, signature: &str) -> bool {
+        let (key, signature) = match (self.open(stored), hex::decode(signature)) {
+            (Some(key), Ok(signature)) => (key, signature),
+            _ => return false,
+        };
+        match <HmacSha256 as Mac>::new_from_slice(&key) {
+            Ok(mut mac) => {
+                mac.update(challenge.as_bytes());
+                mac.verify_slice(&signature).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// single use challenges handed out to vehicles before they host a room, shared between all workers.
+// keyed by the challenge itself, so asking for more of them never invalidates the ones already out
+#[derive(Clone)]
+pub struct Challenges {
+    // challenge -> the vehicle it was issued to & when
+    issued: Arc<RwLock<HashMap<String, (String, Instant)>>>,
+    ttl: Duration,
+}
+
+impl Challenges {
+    pub fn new(ttl: Duration) -> Challenges {
+        Challenges {
+            issued: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn issue(&self, vid: &str) -> String {
+        let challenge = random_hex(16);
+        let mut issued = self.issued.write().unwrap();
+        issued.retain(|_, (_, at)| at.elapsed() < self.ttl);
+        let mut outstanding: Vec<(String, Instant)> = issued.iter().filter(|(_, (to, _))| to == vid).map(|(challenge, (_, at))| (challenge.clone(), *at)).collect();
+        if outstanding.len() >= OUTSTANDING {
+            outstanding.sort_by_key(|(_, at)| *at);
+            for (oldest, _) in &outstanding[..=outstanding.len() - OUTSTANDING] {
+                issued.remove(oldest);
+            }
+        }
+        issued.insert(challenge.clone(), (vid.to_string(), Instant::now()));
+        challenge
+    }
+
+    // the challenge is only used up when `signed` agrees it was signed properly, so wrong attempts
+    // can't burn the one the vehicle is about to use
+    pub fn take(&self, vid: &str, challenge: &str, signed: impl FnOnce() -> bool) -> bool {
+        let mut issued = self.issued.write().unwrap();
+        match issued.get(challenge) {
+            Some((issued_to, at)) if issued_to == vid && at.elapsed() < self.ttl && signed() => {
+                issued.remove(challenge);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(key: &[u8], challenge: &str) -> String {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(key).unwrap();
+        mac.update(challenge.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    // reading what's stored isn't enough to sign as the vehicle
+    #[test]
+    fn only_the_secret_signs() {
+        let key = DeviceKey::new(&[7; 32]).unwrap();
+        let device = key.generate();
+        assert!(key.verify(&device.sealed, "challenge", &sign(&Sha256::digest(device.secret.as_bytes()), "challenge")));
+        assert!(!key.verify(&device.sealed, "challenge", &sign(device.sealed.as_bytes(), "challenge")));
+        assert!(!DeviceKey::new(&[8; 32]).unwrap().verify(&device.sealed, "challenge", &sign(&Sha256::digest(device.secret.as_bytes()), "challenge")));
+    }
+
+    // anyone can ask for challenges & send bad signatures, neither may lock the vehicle out
+    #[test]
+    fn challenges_survive_other_attempts() {
+        let challenges = Challenges::new(Duration::from_secs(60));
+        let challenge = challenges.issue("vid");
+        for _ in 1..OUTSTANDING {
+            challenges.issue("vid");
+        }
+        for _ in 0..10 {
+            challenges.issue("other");
+        }
+        assert!(!challenges.take("vid", &challenge, || false));
+        assert!(!challenges.take("other", &challenge, || true));
+        assert!(challenges.take("vid", &challenge, || true));
+        assert!(!challenges.take("vid", &challenge, || true));
+    }
+
+    // only the latest few are kept for each vehicle, however many get asked for
+    #[test]
+    fn only_the_latest_challenges_stay_out() {
+        let challenges = Challenges::new(Duration::from_secs(60));
+        let issued: Vec<String> = (0..OUTSTANDING + 2).map(|_| challenges.issue("vid")).collect();
+        assert_eq!(challenges.issued.read().unwrap().len(), OUTSTANDING);
+        assert!(!challenges.take("vid", &issued[1], || true));
+        assert!(challenges.take("vid", &issued[2], || true));
+        assert!(challenges.take("vid", &issued[OUTSTANDING + 1], || true));
+    }
+}
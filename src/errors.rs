@@ -0,0 +1,215 @@
+use std::fmt;
+
+use actix_web::{
+    body::{BoxBody, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::{self, HeaderValue}, StatusCode},
+    HttpMessage, HttpResponse, ResponseError,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::requests::FieldError;
+use crate::store::StoreError;
+
+// everything a route can fail with. Each variant maps to a stable `code` clients can match on,
+// the message is for humans & may change. Internal errors only ever show a generic message,
+// the actual cause is logged along with the request id
+#[derive(Debug)]
+pub enum ApiError {
+    // the body couldn't be parsed, along with what serde had to say about it
+    InvalidRequest(String),
+    // the named field isn't a valid object ID
+    InvalidId(&'static str),
+    // the body parsed fine, but some of its fields don't hold up
+    Validation(Vec<FieldError>),
+    Unauthenticated(&'static str),
+    WrongCredentials,
+    DeviceUnverified,
+    NoDeviceSecret,
+    NotPaired,
+    NotOwner,
+    UserNotFound,
+    VehicleNotFound,
+    TripNotFound,
+    LocationUnknown,
+    GeofenceNotFound,
+    RouteNotFound,
+    EmailTaken,
+    UsernameTaken,
+    OwnRoleChange,
+    LastOwner,
+    CodeUnknown,
+    CodeExpired,
+    CodeUsed,
+    TooManyAttempts,
+    Internal(&'static str, String),
+}
+
+impl ApiError {
+    // wraps anything that went wrong on our side. `context` is what the server was doing at the time
+    pub fn internal(context: &'static str, error: impl fmt::Display) -> ApiError {
+        ApiError::Internal(context, error.to_string())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidId(_) => "invalid_id",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Unauthenticated(_) => "unauthenticated",
+            ApiError::WrongCredentials => "wrong_credentials",
+            ApiError::DeviceUnverified => "device_unverified",
+            ApiError::NoDeviceSecret => "no_device_secret",
+            ApiError::NotPaired => "not_paired",
+            ApiError::NotOwner => "not_owner",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::VehicleNotFound => "vehicle_not_found",
+            ApiError::TripNotFound => "trip_not_found",
+            ApiError::LocationUnknown => "location_unknown",
+            ApiError::GeofenceNotFound => "geofence_not_found",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::EmailTaken => "email_taken",
+            ApiError::UsernameTaken => "username_taken",
+            ApiError::OwnRoleChange => "own_role_change",
+            ApiError::LastOwner => "last_owner",
+            ApiError::CodeUnknown => "code_unknown",
+            ApiError::CodeExpired => "code_expired",
+            ApiError::CodeUsed => "code_used",
+            ApiError::TooManyAttempts => "too_many_attempts",
+            ApiError::Internal(..) => "internal",
+        }
+    }
+
+    fn suggestion(&self) -> Option<&'static str> {
+        match self {
+            ApiError::InvalidRequest(_) => Some("Make sure it is a valid JSON payload in the directed format."),
+            ApiError::InvalidId(_) => Some("Object IDs are 24 character hex strings."),
+            ApiError::Validation(_) => Some("Fix the fields listed in `fields` and try again."),
+            ApiError::WrongCredentials => Some("Check the username or email and password."),
+            ApiError::DeviceUnverified => Some("Request a new challenge at /vehicle/challenge/{vid} and sign it with the device secret"),
+            ApiError::NoDeviceSecret => Some("Have the owner issue one at /vehicle/secret/rotate"),
+            ApiError::NotPaired => Some("Securely link the vehicle first using a pairing code."),
+            ApiError::UserNotFound => Some("Sign up the user at /signup"),
+            ApiError::VehicleNotFound => Some("Register the vehicle at /vehicle/register"),
+            ApiError::OwnRoleChange => Some("Make another user an owner first and have them change it"),
+            ApiError::LastOwner => Some("Make another user an owner first"),
+            ApiError::CodeUnknown => Some("Use the code currently shown by the vehicle."),
+            ApiError::CodeExpired | ApiError::CodeUsed => Some("Ask the vehicle for a new code."),
+            ApiError::TooManyAttempts => Some("Wait a few minutes before trying again."),
+            ApiError::Internal(..) => Some("Try again later. If this keeps happening, report it along with the request_id."),
+            _ => None,
+        }
+    }
+
+    // the envelope every failed request gets. request_id is filled in by `tag` once the request is known
+    pub fn body(&self, request_id: Option<&str>) -> String {
+        let mut body = json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        });
+        if let ApiError::Validation(fields) = self {
+            body["fields"] = json!(fields);
+        }
+        if let Some(suggestion) = self.suggestion() {
+            body["suggestion"] = json!(suggestion);
+        }
+        if let Some(request_id) = request_id {
+            body["request_id"] = json!(request_id);
+        }
+        body.to_string()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidRequest(details) => write!(f, "Failed to parse request: {}", details),
+            ApiError::InvalidId(field) => write!(f, "Failed to parse {}. Make sure it is a valid object ID.", field),
+            ApiError::Validation(_) => write!(f, "Some fields of the request are invalid."),
+            ApiError::Unauthenticated(message) => write!(f, "{}", message),
+            ApiError::WrongCredentials => write!(f, "Wrong credentials"),
+            ApiError::DeviceUnverified => write!(f, "The vehicle failed to prove its identity."),
+            ApiError::NoDeviceSecret => write!(f, "This vehicle has no device secret."),
+            ApiError::NotPaired => write!(f, "This user isn't paired to the vehicle."),
+            ApiError::NotOwner => write!(f, "Only the owner of the vehicle can do this."),
+            ApiError::UserNotFound => write!(f, "There is no user with the supplied ID."),
+            ApiError::VehicleNotFound => write!(f, "There is no vehicle with the supplied ID."),
+            ApiError::TripNotFound => write!(f, "There is no trip with the supplied ID."),
+            ApiError::LocationUnknown => write!(f, "The vehicle hasn't reported its location yet."),
+            ApiError::GeofenceNotFound => write!(f, "The vehicle has no geofence with the supplied ID."),
+            ApiError::RouteNotFound => write!(f, "There is no such route on this server."),
+            ApiError::EmailTaken => write!(f, "Another user already exists with this email."),
+            ApiError::UsernameTaken => write!(f, "Another user already exists with this username."),
+            ApiError::OwnRoleChange => write!(f, "Owners can't change their own role."),
+            ApiError::LastOwner => write!(f, "The only owner of a vehicle can't be unpaired from it."),
+            ApiError::CodeUnknown => write!(f, "This code is invalid."),
+            ApiError::CodeExpired => write!(f, "This code has expired."),
+            ApiError::CodeUsed => write!(f, "This code has already been used."),
+            ApiError::TooManyAttempts => write!(f, "Too many failed attempts."),
+            ApiError::Internal(..) => write!(f, "The server ran into an unexpected error."),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_) => StatusCode::NOT_ACCEPTABLE,
+            ApiError::InvalidId(_) => StatusCode::BAD_REQUEST,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthenticated(_) | ApiError::WrongCredentials | ApiError::DeviceUnverified => StatusCode::UNAUTHORIZED,
+            ApiError::NoDeviceSecret | ApiError::NotPaired | ApiError::NotOwner => StatusCode::FORBIDDEN,
+            ApiError::UserNotFound | ApiError::VehicleNotFound | ApiError::TripNotFound | ApiError::LocationUnknown | ApiError::GeofenceNotFound | ApiError::RouteNotFound | ApiError::CodeUnknown => StatusCode::NOT_FOUND,
+            ApiError::EmailTaken | ApiError::UsernameTaken | ApiError::OwnRoleChange | ApiError::LastOwner | ApiError::CodeUsed => StatusCode::CONFLICT,
+            ApiError::CodeExpired => StatusCode::GONE,
+            ApiError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .body(self.body(None))
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(error: StoreError) -> Self {
+        ApiError::internal("querying the database", error)
+    }
+}
+
+// unique per request, sent back in the x-request-id header & in every error body
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+// gives every request an id before it's handled
+pub fn assign(request: &ServiceRequest) -> RequestId {
+    let id = RequestId(Uuid::new_v4().to_string());
+    request.extensions_mut().insert(id.clone());
+    id
+}
+
+// tags the response with the request id. Failed requests get their body re-rendered with the id
+// & internal errors are logged here, the only place the actual cause ever shows up
+pub fn tag<B: MessageBody + 'static>(
+    response: Result<ServiceResponse<B>, actix_web::Error>,
+    id: RequestId,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let mut response = response?;
+    if let Ok(value) = HeaderValue::from_str(&id.0) {
+        response.headers_mut().insert(header::HeaderName::from_static("x-request-id"), value);
+    }
+    let body = match response.response().error().and_then(|error| error.as_error::<ApiError>()) {
+        Some(error) => {
+            if let ApiError::Internal(context, cause) = error {
+                eprintln!("[{}] {} {} failed while {}: {}", id.0, response.request().method(), response.request().path(), context, cause);
+            }
+            error.body(Some(&id.0))
+        }
+        None => return Ok(response.map_into_left_body()),
+    };
+    Ok(response.map_body(|_, _| EitherBody::right(BoxBody::new(body))))
+}
@@ -0,0 +1,509 @@
+// drives the real routes end to end against the in-memory stores, so it runs without MongoDB.
+// HTTP requests go through actix_web::test & websockets through a server listening on a random port
+use std::net::{SocketAddr, TcpListener};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, rt::{self, time::timeout}, test, web, HttpServer};
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use logger::{ingest::Batching, memory::MemoryLogs, Logger};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sockets::{pairing::PairingCodes, sockets::Lobby, ws::Heartbeat};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, handshake::client::Request, Error, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::credentials::Credentials;
+use crate::devices::{Challenges, DeviceKey};
+use crate::store::Stores;
+use crate::tokens::Tokens;
+use crate::{app, Auth, Manager};
+
+type Socket = WebSocketStream<MaybeTlsStream<rt::net::TcpStream>>;
+
+// how long to wait for an event before giving up on it
+const WAIT: Duration = Duration::from_secs(5);
+// how long to wait before deciding an event isn't coming
+const QUIET: Duration = Duration::from_millis(300);
+
+struct Harness {
+    manager: web::Data<Manager>,
+    tokens: Tokens,
+    address: SocketAddr,
+}
+
+impl Harness {
+    async fn start() -> Harness {
+        Harness::with_codes(PairingCodes::default()).await
+    }
+
+    // for codes that run out sooner than the default ones
+    async fn with_codes(codes: PairingCodes) -> Harness {
+        let admins = Arc::new(RwLock::new(HashMap::new()));
+        let sessions = Arc::new(RwLock::new(0));
+        let logger = Logger::new(Arc::new(MemoryLogs::default()), Batching::default(), chrono_tz::UTC, Duration::from_secs(300));
+        let lobby = Lobby::new(Arc::clone(&admins), Arc::clone(&sessions), codes.clone(), logger.clone()).await;
+        let tokens = Tokens::new(b"test-secret-that-is-long-enough!", Duration::from_secs(60), Duration::from_secs(120));
+        let auth = Auth {
+            // hashing with the real parameters makes every signup & login take a while
+            credentials: Credentials::new(64, 1, 1).unwrap(),
+            tokens: tokens.clone(),
+            challenges: Challenges::new(Duration::from_secs(60)),
+            device_key: DeviceKey::random(),
+            codes,
+        };
+        // pings every few seconds would only get in the way of reading events
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(120),
+        };
+        let manager = web::Data::new(Manager::start(Stores::memory(), lobby, logger, admins, sessions, auth, heartbeat));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (data, secret) = (manager.clone(), tokens.clone());
+        let server = HttpServer::new(move || app(data.clone(), secret.clone()))
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+        rt::spawn(server);
+        Harness { manager, tokens, address }
+    }
+
+    async fn post(&self, path: &str, token: Option<&str>, body: Value) -> (StatusCode, Value) {
+        let request = test::TestRequest::post().uri(path).set_json(body);
+        self.call(request, token).await
+    }
+
+    async fn get(&self, path: &str, token: Option<&str>) -> (StatusCode, Value) {
+        self.call(test::TestRequest::get().uri(path), token).await
+    }
+
+    async fn call(&self, mut request: test::TestRequest, token: Option<&str>) -> (StatusCode, Value) {
+        let service = test::init_service(app(self.manager.clone(), self.tokens.clone())).await;
+        if let Some(token) = token {
+            request = request.insert_header(("Authorization", format!("Bearer {}", token)));
+        }
+        let response = test::call_service(&service, request.to_request()).await;
+        let http_status = response.status();
+        let body = test::read_body(response).await;
+        (http_status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn upgrade(&self, path: &str, token: Option<&str>) -> Request {
+        let mut request = format!("ws://{}{}", self.address, path).into_client_request().unwrap();
+        if let Some(token) = token {
+            request.headers_mut().insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+        }
+        request
+    }
+
+    async fn connect(&self, path: &str, token: Option<&str>) -> Socket {
+        let (socket, _) = connect_async(self.upgrade(path, token)).await.unwrap();
+        socket
+    }
+
+    // the error a websocket route answered with instead of upgrading the connection
+    async fn refused(&self, path: &str, token: Option<&str>) -> (u16, Value) {
+        match connect_async(self.upgrade(path, token)).await {
+            Err(Error::Http(response)) => {
+                let body = response.body().as_deref().unwrap_or_default();
+                (response.status().as_u16(), serde_json::from_slice(body).unwrap_or(Value::Null))
+            }
+            Ok(_) => panic!("expected {} to be refused, it was upgraded", path),
+            Err(e) => panic!("expected {} to be refused, got {:?}", path, e),
+        }
+    }
+
+    // signs up & logs in, giving back the uid & an access token
+    async fn account(&self, username: &str) -> (String, String) {
+        let signup = json!({"name": username, "username": username, "email": format!("{}@example.com", username), "password": "correct horse battery 9"});
+        let (http_status, body) = self.post("/signup", None, signup).await;
+        assert_eq!(http_status, StatusCode::OK, "{}", body);
+        let uid = body["uid"]["$oid"].as_str().unwrap().to_string();
+        let (http_status, body) = self.post("/login", None, json!({"username": username, "password": "correct horse battery 9"})).await;
+        assert_eq!(http_status, StatusCode::OK, "{}", body);
+        assert_eq!(body["uid"]["$oid"], uid);
+        (uid, body["access_token"].as_str().unwrap().to_string())
+    }
+
+    // registers a vehicle owned by the user, giving back its vid & device secret
+    async fn register(&self, token: &str) -> (String, String) {
+        let (http_status, body) = self.post("/vehicle/register", Some(token), json!({"company": "Tata", "model": "Nexon"})).await;
+        assert_eq!(http_status, StatusCode::OK, "{}", body);
+        (body["id"]["$oid"].as_str().unwrap().to_string(), body["secret"].as_str().unwrap().to_string())
+    }
+
+    // joins the vehicle's room as the user, giving back the connection & its id once the vehicle knows
+    async fn join(&self, vehicle: &mut Socket, vid: &str, uid: &str, token: &str) -> (Socket, String) {
+        let mut user = self.connect(&format!("/join/user/{}", vid), Some(token)).await;
+        let joined = receive(&mut user).await;
+        let conn_id = joined["client"]["conn_id"].as_str().unwrap().to_string();
+        // users are told the room they joined, not who they are
+        assert_eq!(joined, json!({"event": "connect", "client": {"uid": vid, "conn_id": conn_id}, "message": "Connection successful", "error": ""}));
+        assert_eq!(receive(vehicle).await, json!({"event": "connected", "client": {"uid": uid, "conn_id": conn_id}}));
+        (user, conn_id)
+    }
+
+    // a fresh challenge signed the way a vehicle would
+    async fn sign(&self, vid: &str, secret: &str) -> (String, String) {
+        let (http_status, body) = self.get(&format!("/vehicle/challenge/{}", vid), None).await;
+        assert_eq!(http_status, StatusCode::OK, "{}", body);
+        let challenge = body["challenge"].as_str().unwrap().to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(secret.as_bytes())).unwrap();
+        mac.update(challenge.as_bytes());
+        (challenge, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    async fn host(&self, vid: &str, secret: &str) -> Socket {
+        let (challenge, signature) = self.sign(vid, secret).await;
+        self.connect(&format!("/join/vehicle/{}?challenge={}&signature={}", vid, challenge, signature), None).await
+    }
+
+    // pairs the user with a code the vehicle asks for, the vehicle hears about it too
+    async fn pair(&self, vehicle: &mut Socket, token: &str) -> Value {
+        let code = self.code(vehicle).await;
+        assert_eq!(code["expires_in"], 300);
+        let mut user = self.connect(&format!("/pair/{}", code["message"].as_str().unwrap()), Some(token)).await;
+        let (close_code, result) = closed(&mut user).await;
+        assert_eq!(close_code, 1000);
+        assert_eq!(receive(vehicle).await, result);
+        result
+    }
+
+    // the event with a fresh pairing code for the vehicle
+    async fn code(&self, vehicle: &mut Socket) -> Value {
+        send(vehicle, json!({"mode": "pair"})).await;
+        let code = receive(vehicle).await;
+        assert_eq!(code["event"], "code");
+        code
+    }
+}
+
+async fn send(socket: &mut Socket, value: Value) {
+    socket.send(Message::Text(value.to_string())).await.unwrap();
+}
+
+// the next event, skipping anything that isn't one
+async fn receive(socket: &mut Socket) -> Value {
+    loop {
+        match timeout(WAIT, socket.next()).await.expect("no event arrived") {
+            Some(Ok(Message::Text(text))) => return serde_json::from_str(&text).unwrap(),
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            other => panic!("expected an event, got {:?}", other),
+        }
+    }
+}
+
+// the close code & the reason it came with
+async fn closed(socket: &mut Socket) -> (u16, Value) {
+    match timeout(WAIT, socket.next()).await.expect("the connection wasn't closed") {
+        Some(Ok(Message::Close(Some(frame)))) => (frame.code.into(), serde_json::from_str(&frame.reason).unwrap_or_else(|_| json!(frame.reason))),
+        other => panic!("expected the connection to close, got {:?}", other),
+    }
+}
+
+async fn silent(socket: &mut Socket) {
+    if let Ok(event) = timeout(QUIET, socket.next()).await {
+        panic!("expected nothing, got {:?}", event);
+    }
+}
+
+fn message(mode: &str, vid: &str, conn_id: &str) -> Value {
+    json!({
+        "mode": mode,
+        "vid": vid,
+        "conn_id": conn_id,
+        "status": "driving",
+        "message": {"gear": 3, "rpm": 2100, "speed": 48, "location": null, "temp": 90, "fuel": 40, "odo": 1200, "stressed": false},
+        "attachments": [],
+    })
+}
+
+// a broadcast of the vehicle being at the location
+fn located(vid: &str, latitude: f64, longitude: f64) -> Value {
+    let mut broadcast = message("broadcast", vid, "");
+    broadcast["message"]["location"] = json!({"latitude": latitude, "longitude": longitude});
+    broadcast
+}
+
+#[actix_web::test]
+async fn a_vehicle_and_its_users_talk_through_the_room() {
+    let harness = Harness::start().await;
+    let (alice, alice_token) = harness.account("alice").await;
+    let (bob, bob_token) = harness.account("bob").await;
+
+    let (http_status, body) = harness.post("/vehicle/register", None, json!({"company": "Tata", "model": "Nexon"})).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::UNAUTHORIZED, &json!("unauthenticated")));
+    let (vid, secret) = harness.register(&alice_token).await;
+
+    // nobody else joins before pairing
+    let (http_status, body) = harness.get(&format!("/join/user/{}", vid), Some(&bob_token)).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::FORBIDDEN, &json!("not_paired")));
+
+    let mut vehicle = harness.host(&vid, &secret).await;
+    let connected = receive(&mut vehicle).await;
+    let vehicle_conn = connected["client"]["conn_id"].as_str().unwrap().to_string();
+    assert_eq!(connected, json!({"event": "connect", "client": {"uid": "", "conn_id": vehicle_conn}, "message": "Connection successful", "error": ""}));
+
+    // whoever registered the vehicle owns it, everyone pairing after that watches
+    assert_eq!(harness.pair(&mut vehicle, &bob_token).await, json!({"message": "Pair successful", "uid": bob, "vid": vid, "role": "viewer"}));
+    let (http_status, body) = harness.post("/vehicle/refresh", Some(&alice_token), json!({})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(body["roles"], json!({&vid: "owner"}));
+    assert_eq!(body["vehicles"], json!([{"_id": {"$oid": vid}, "company": "Tata", "model": "Nexon", "owners": [{"$oid": alice}]}]));
+
+    let (mut owner, owner_conn) = harness.join(&mut vehicle, &vid, &alice, &alice_token).await;
+    let (mut viewer, viewer_conn) = harness.join(&mut vehicle, &vid, &bob, &bob_token).await;
+
+    // broadcasts reach every user but not the vehicle itself
+    let broadcast = message("broadcast", &vid, "");
+    send(&mut vehicle, broadcast.clone()).await;
+    assert_eq!(receive(&mut owner).await, broadcast);
+    assert_eq!(receive(&mut viewer).await, broadcast);
+    let day = chrono::Utc::now().format("%Y-%m-%d").to_string();
+
+    let whisper = message("whisper", &vid, &owner_conn);
+    send(&mut vehicle, whisper.clone()).await;
+    assert_eq!(receive(&mut owner).await, whisper);
+    silent(&mut viewer).await;
+
+    let action = message("action", &vid, &owner_conn);
+    send(&mut owner, action.clone()).await;
+    assert_eq!(receive(&mut vehicle).await, action);
+
+    // viewers can ask for data, but not give orders
+    send(&mut viewer, message("action", &vid, &viewer_conn)).await;
+    assert_eq!(
+        receive(&mut viewer).await,
+        json!({"event": "error", "client": {"uid": bob, "conn_id": viewer_conn}, "message": "", "error": "Your role on this vehicle doesn't allow messages in this mode"})
+    );
+    let request = message("request", &vid, &viewer_conn);
+    send(&mut viewer, request.clone()).await;
+    assert_eq!(receive(&mut vehicle).await, request);
+
+    // users' broadcasts reach the room too, but aren't taken for the vehicle's readings
+    let mut forged = message("broadcast", &vid, "");
+    forged["message"]["speed"] = json!(120);
+    forged["message"]["stressed"] = json!(true);
+    send(&mut viewer, forged.clone()).await;
+    assert_eq!(receive(&mut owner).await, forged);
+    assert_eq!(receive(&mut vehicle).await, forged);
+    silent(&mut viewer).await;
+
+    send(&mut viewer, json!({"mode": "shout"})).await;
+    assert_eq!(receive(&mut viewer).await["error"], "This message is not in the specified format");
+
+    // made a driver, the same connection can give orders right away
+    let (http_status, body) = harness.post("/vehicle/role", Some(&alice_token), json!({"vid": vid, "uid": bob, "role": "driver"})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    let action = message("action", &vid, &viewer_conn);
+    send(&mut viewer, action.clone()).await;
+    assert_eq!(receive(&mut vehicle).await, action);
+
+    viewer.close(None).await.unwrap();
+    assert_eq!(receive(&mut vehicle).await, json!({"event": "disconnect", "client": {"uid": bob, "conn_id": viewer_conn}, "message": "A client has disconnected", "error": ""}));
+
+    // the room closes with the vehicle. The event is too long to be the close reason, so it comes first
+    vehicle.close(None).await.unwrap();
+    assert_eq!(
+        receive(&mut owner).await,
+        json!({"event": "disconnect", "client": {"uid": "", "conn_id": owner_conn}, "message": "Vehicle left and the room is being closed", "error": ""})
+    );
+    assert_eq!(closed(&mut owner).await, (1000, json!("")));
+
+    // the broadcast is written once the vehicle's buffer is flushed as it leaves
+    let mut logs = Value::Null;
+    for _ in 0..50 {
+        let (http_status, body) = harness.post("/logs/daily", Some(&alice_token), json!({"vid": vid, "date": day})).await;
+        if http_status == StatusCode::OK {
+            logs = body;
+            break;
+        }
+        rt::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(logs["average_speed"], 48);
+    assert_eq!(logs["stress_count"], 0);
+    assert_eq!(logs["first_odometer"], 1200);
+    assert_eq!(logs["max_speed"]["speed"], 48);
+}
+
+// users who lose access are closed with ACCESS_REVOKED right away, & can't come back
+#[actix_web::test]
+async fn unpaired_users_are_kicked_out_of_the_room() {
+    let harness = Harness::start().await;
+    let (alice, alice_token) = harness.account("alice").await;
+    let (bob, bob_token) = harness.account("bob").await;
+    let (vid, secret) = harness.register(&alice_token).await;
+    let mut vehicle = harness.host(&vid, &secret).await;
+    receive(&mut vehicle).await;
+    harness.pair(&mut vehicle, &bob_token).await;
+    let (mut owner, _) = harness.join(&mut vehicle, &vid, &alice, &alice_token).await;
+    let (mut viewer, viewer_conn) = harness.join(&mut vehicle, &vid, &bob, &bob_token).await;
+
+    // the only owner has to hand the vehicle over before leaving it
+    let (http_status, body) = harness.post("/vehicle/unpair", Some(&alice_token), json!({"vid": vid})).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::CONFLICT, &json!("last_owner")));
+    let (http_status, body) = harness.post("/vehicle/revoke", Some(&alice_token), json!({"vid": vid, "uid": alice})).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::CONFLICT, &json!("last_owner")));
+
+    // a promoted user owns the vehicle alongside them, & can be let go again while someone else does
+    let owners = |body: Value| body["vehicles"][0]["owners"].clone();
+    let (http_status, body) = harness.post("/vehicle/role", Some(&alice_token), json!({"vid": vid, "uid": bob, "role": "owner"})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(owners(harness.post("/vehicle/refresh", Some(&bob_token), json!({})).await.1), json!([{"$oid": alice}, {"$oid": bob}]));
+    let (http_status, body) = harness.post("/vehicle/role", Some(&alice_token), json!({"vid": vid, "uid": bob, "role": "viewer"})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(owners(harness.post("/vehicle/refresh", Some(&bob_token), json!({})).await.1), json!([{"$oid": alice}]));
+
+    let (http_status, body) = harness.post("/vehicle/unpair", Some(&bob_token), json!({"vid": vid})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(
+        receive(&mut viewer).await,
+        json!({"event": "disconnect", "client": {"uid": bob, "conn_id": viewer_conn}, "message": "Access to this vehicle was revoked", "error": ""})
+    );
+    assert_eq!(closed(&mut viewer).await, (4003, json!("")));
+    assert_eq!(
+        receive(&mut vehicle).await,
+        json!({"event": "disconnect", "client": {"uid": bob, "conn_id": viewer_conn}, "message": "A client's access was revoked", "error": ""})
+    );
+    silent(&mut owner).await;
+
+    let (http_status, body) = harness.get(&format!("/join/user/{}", vid), Some(&bob_token)).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::FORBIDDEN, &json!("not_paired")));
+}
+
+#[actix_web::test]
+async fn owners_and_the_vehicle_revoke_other_users() {
+    let harness = Harness::start().await;
+    let (alice, alice_token) = harness.account("alice").await;
+    let (bob, bob_token) = harness.account("bob").await;
+    let (carol, carol_token) = harness.account("carol").await;
+    let (vid, secret) = harness.register(&alice_token).await;
+    let mut vehicle = harness.host(&vid, &secret).await;
+    receive(&mut vehicle).await;
+    for token in [&bob_token, &carol_token] {
+        harness.pair(&mut vehicle, token).await;
+    }
+    let (_owner, _) = harness.join(&mut vehicle, &vid, &alice, &alice_token).await;
+    let (mut viewer, viewer_conn) = harness.join(&mut vehicle, &vid, &bob, &bob_token).await;
+    let (mut other, other_conn) = harness.join(&mut vehicle, &vid, &carol, &carol_token).await;
+
+    let (http_status, body) = harness.post("/vehicle/revoke", Some(&bob_token), json!({"vid": vid, "uid": carol})).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::FORBIDDEN, &json!("not_owner")));
+
+    let (http_status, body) = harness.post("/vehicle/revoke", Some(&alice_token), json!({"vid": vid, "uid": bob})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(body, json!({"success": "The user was unpaired from the vehicle", "uid": bob, "vid": vid}));
+    assert_eq!(receive(&mut viewer).await["message"], "Access to this vehicle was revoked");
+    assert_eq!(closed(&mut viewer).await, (4003, json!("")));
+    assert_eq!(receive(&mut vehicle).await["client"], json!({"uid": bob, "conn_id": viewer_conn}));
+
+    // the vehicle signs for itself instead of sending a token
+    let (challenge, signature) = harness.sign(&vid, &secret).await;
+    let revoke = json!({"vid": vid, "uid": carol, "challenge": challenge, "signature": signature});
+    let (http_status, body) = harness.post("/vehicle/revoke", None, revoke.clone()).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(receive(&mut other).await["message"], "Access to this vehicle was revoked");
+    assert_eq!(closed(&mut other).await, (4003, json!("")));
+    assert_eq!(receive(&mut vehicle).await["client"], json!({"uid": carol, "conn_id": other_conn}));
+    // the challenge is used up
+    let (http_status, body) = harness.post("/vehicle/revoke", None, revoke).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::UNAUTHORIZED, &json!("device_unverified")));
+}
+
+// wrong codes & users who aren't paired are turned away before the connection is upgraded
+#[actix_web::test]
+async fn pairing_mistakes_are_refused_at_the_handshake() {
+    let harness = Harness::start().await;
+    let (alice, alice_token) = harness.account("alice").await;
+    let (_, bob_token) = harness.account("bob").await;
+    let (_, fleet_token) = harness.account("fleet").await;
+    let (vid, secret) = harness.register(&fleet_token).await;
+    let mut vehicle = harness.host(&vid, &secret).await;
+    receive(&mut vehicle).await;
+
+    let (status, body) = harness.refused(&format!("/join/user/{}", vid), Some(&alice_token)).await;
+    assert_eq!((status, &body["code"]), (403, &json!("not_paired")));
+    let (status, body) = harness.refused("/pair/ZZZZZZ", Some(&alice_token)).await;
+    assert_eq!((status, &body["code"]), (404, &json!("code_unknown")));
+    assert!(body["request_id"].is_string());
+
+    let code = harness.code(&mut vehicle).await;
+    let path = format!("/pair/{}", code["message"].as_str().unwrap());
+    let mut user = harness.connect(&path, Some(&alice_token)).await;
+    assert_eq!(closed(&mut user).await.1["uid"], alice);
+    assert_eq!(receive(&mut vehicle).await["message"], "Pair successful");
+    let (status, body) = harness.refused(&path, Some(&bob_token)).await;
+    assert_eq!((status, &body["code"]), (409, &json!("code_used")));
+    let (status, body) = harness.refused(&format!("/join/user/{}", vid), Some(&bob_token)).await;
+    assert_eq!((status, &body["code"]), (403, &json!("not_paired")));
+    // the vehicle only hears about pairs that went through
+    silent(&mut vehicle).await;
+
+    let harness = Harness::with_codes(PairingCodes::new(Duration::from_millis(200), 6, 5, 1000)).await;
+    let (_, fleet_token) = harness.account("fleet").await;
+    let (vid, secret) = harness.register(&fleet_token).await;
+    let (_, carol_token) = harness.account("carol").await;
+    let mut vehicle = harness.host(&vid, &secret).await;
+    receive(&mut vehicle).await;
+    let code = harness.code(&mut vehicle).await;
+    rt::time::sleep(Duration::from_millis(300)).await;
+    let (status, body) = harness.refused(&format!("/pair/{}", code["message"].as_str().unwrap()), Some(&carol_token)).await;
+    assert_eq!((status, &body["code"]), (410, &json!("code_expired")));
+}
+
+#[actix_web::test]
+async fn the_room_hears_when_the_vehicle_crosses_a_geofence() {
+    let harness = Harness::start().await;
+    let (alice, alice_token) = harness.account("alice").await;
+    let (bob, bob_token) = harness.account("bob").await;
+    let (vid, secret) = harness.register(&alice_token).await;
+    let mut vehicle = harness.host(&vid, &secret).await;
+    receive(&mut vehicle).await;
+    harness.pair(&mut vehicle, &bob_token).await;
+    let (mut owner, _) = harness.join(&mut vehicle, &vid, &alice, &alice_token).await;
+    let (mut viewer, _) = harness.join(&mut vehicle, &vid, &bob, &bob_token).await;
+
+    let fence = json!({"vid": vid, "name": "Home", "shape": {"type": "circle", "center": {"latitude": 9.93, "longitude": 76.26}, "radius": 500.0}});
+    let (http_status, body) = harness.post("/geofences/create", Some(&bob_token), fence.clone()).await;
+    assert_eq!((http_status, &body["code"]), (StatusCode::FORBIDDEN, &json!("not_owner")));
+    let (http_status, body) = harness.post("/geofences/create", Some(&alice_token), fence).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    let id = body["id"].clone();
+
+    // the first location only tells which side of the fence the vehicle is on
+    let (outside, inside) = (located(&vid, 9.99, 76.26), located(&vid, 9.931, 76.261));
+    send(&mut vehicle, outside.clone()).await;
+    for user in [&mut owner, &mut viewer] {
+        assert_eq!(receive(user).await, outside);
+        silent(user).await;
+    }
+
+    for (broadcast, message, transition) in [(&inside, "The vehicle entered Home", "enter"), (&outside, "The vehicle left Home", "exit")] {
+        send(&mut vehicle, broadcast.clone()).await;
+        for user in [&mut owner, &mut viewer] {
+            assert_eq!(&receive(user).await, broadcast);
+        }
+        // everyone in the room hears about it, the vehicle included
+        for socket in [&mut owner, &mut viewer, &mut vehicle] {
+            let event = receive(socket).await;
+            assert_eq!((&event["event"], &event["message"], &event["error"]), (&json!("geofence"), &json!(message), &json!("")));
+            assert_eq!((&event["geofence"]["id"], &event["geofence"]["name"], &event["geofence"]["vid"]), (&id, &json!("Home"), &json!(vid)));
+            assert_eq!(event["geofence"]["transition"], transition);
+            assert_eq!(event["geofence"]["location"], broadcast["message"]["location"]);
+        }
+    }
+
+    // both crossings were recorded, latest first
+    let (http_status, body) = harness.post("/geofences/events", Some(&bob_token), json!({"vid": vid})).await;
+    assert_eq!(http_status, StatusCode::OK, "{}", body);
+    assert_eq!(body["count"], 2);
+    assert_eq!((&body["events"][0]["transition"], &body["events"][1]["transition"]), (&json!("exit"), &json!("enter")));
+}
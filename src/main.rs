@@ -1,14 +1,34 @@
+mod config;
+mod credentials;
+mod devices;
+mod errors;
 mod manager;
+mod memory;
+mod requests;
+mod store;
+mod tls;
+mod tokens;
 
-use std::{sync::{Arc, RwLock}, collections::HashMap};
+#[cfg(test)]
+mod integration;
 
-pub use crate::manager::Manager;
-use actix_web::{web::{self, Path}, get, post, App, HttpResponse, HttpRequest, HttpServer, Responder};
+use std::{sync::{Arc, RwLock}, collections::HashMap, time::Duration};
+
+pub use crate::manager::{Auth, Manager};
+use crate::config::{Backend, Config};
+use crate::credentials::Credentials;
+use crate::devices::{Challenges, DeviceKey};
+use crate::errors::ApiError;
+use crate::store::Stores;
+use crate::requests::{ChangeRole, CreateGeofence, DailyLogs, DeleteGeofence, EditVehicle, ExportLogs, GeofenceEvents, HealthReport, Id, Login, OverallLogs, PeriodicLogs, RefreshToken, RegisterVehicle, Revoke, RotateSecret, RouteOptions, Signup, Status, TelemetryRange, TripList, Valid, ValidQuery, VehicleId};
+use crate::tls::CertResolver;
+use crate::tokens::{Authenticated, Tokens};
+use actix_web::{body::MessageBody, dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse}, web::{self, Path}, get, post, App, HttpResponse, HttpRequest, HttpServer, Responder};
 use actix_files as fs;
-use logger::Logger;
+use logger::{ingest::Batching, memory::MemoryLogs, store::{LogStore, MongoLogs}, Logger};
 use mongodb::{Client, options::ClientOptions};
-use serde_json::json;
-use sockets::sockets::Lobby;
+use rand::RngCore;
+use sockets::{pairing::PairingCodes, sockets::Lobby, ws::Heartbeat};
 
 // try to convert all requests to reduce code
 // async fn parse_and_run(request: String, function: &dyn Fn(Value) -> HttpResponse) -> HttpResponse {
@@ -41,131 +61,473 @@ async fn social() -> impl Responder {
     fs::NamedFile::open_async("./html/img/logo.png").await
 }
 
-#[get("/join/vehicle/{uid}")]
-async fn joinvehicle(req: HttpRequest, stream: web::Payload, context: web::Data<Manager>, path: Path<String>) -> impl Responder {
-    context.joinvehicle(path.into_inner(), &req, stream).await
+#[get("/vehicle/challenge/{vid}")]
+async fn vehiclechallenge(context: web::Data<Manager>, path: Path<Id>) -> impl Responder {
+    context.challenge(path.into_inner().0).await
+}
+
+#[get("/join/vehicle/{vid}")]
+async fn joinvehicle(req: HttpRequest, stream: web::Payload, context: web::Data<Manager>, path: Path<Id>, query_params: web::Query<HashMap<String, String>>) -> impl Responder {
+    context.joinvehicle(path.into_inner().0, query_params.into_inner(), &req, stream).await
 }
 
-#[get("/join/user/{vid}/{uid}")]
-async fn joinuser(req: HttpRequest, stream: web::Payload, context: web::Data<Manager>, path: Path<(String, String)>) -> impl Responder {
-    context.joinuser(path.0.clone(), path.1.clone(), &req, stream).await
+#[get("/join/user/{vid}")]
+async fn joinuser(req: HttpRequest, stream: web::Payload, context: web::Data<Manager>, path: Path<Id>, auth: Authenticated) -> impl Responder {
+    context.joinuser(auth.uid, path.into_inner().0, &req, stream).await
 }
 
-#[get("/pair/{vid}/{uid}")]
-async fn pair(req: HttpRequest, stream: web::Payload, context: web::Data<Manager>, path: Path<(String, String)>, query_params: web::Query<HashMap<String, String>>) -> impl Responder {
-    context.pair(path.1.clone(), path.0.clone(), &req, stream, query_params.get("initial").map(|s| s == "true").unwrap_or(false)).await
+#[get("/pair/{code}")]
+async fn pair(req: HttpRequest, stream: web::Payload, context: web::Data<Manager>, path: Path<String>, auth: Authenticated) -> impl Responder {
+    context.pair(auth.uid, path.into_inner(), &req, stream).await
 }
 
 // Account management routes
 
 #[post("/login")]
-async fn login(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    match serde_json::from_str(&req_body) {
-        Ok(data) => context.login(data).await,
-        Err(_) => HttpResponse::NotAcceptable().body(json!({
-            "error": "Failed to parse request. Make sure it is a valid JSON payload."
-        }).to_string())
-    }
+async fn login(context: web::Data<Manager>, body: Valid<Login>) -> impl Responder {
+    context.login(body.into_inner()).await
+}
+
+#[post("/token/refresh")]
+async fn refreshtoken(context: web::Data<Manager>, body: Valid<RefreshToken>) -> impl Responder {
+    context.refresh_token(body.into_inner()).await
 }
 
 #[post("/status")]
-async fn status(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    match serde_json::from_str(&req_body) {
-            Ok(data) => context.status(data),
-            Err(_) => HttpResponse::NotAcceptable().body(json!({
-                "error": "Failed to parse request. Make sure it is a valid JSON payload."
-            }).to_string())
-        }
+async fn status(context: web::Data<Manager>, body: Valid<Status>) -> impl Responder {
+    context.status(body.into_inner())
 }
 
 #[post("/signup")]
-async fn signup(context: web::Data<Manager>, req_body: String) -> impl Responder {
-     match serde_json::from_str(&req_body) {
-        Ok(data) => context.signup(data).await,
-        Err(_) => HttpResponse::NotAcceptable().body(json!({
-            "error": "Failed to parse request. Make sure it is a valid JSON payload."
-        }).to_string())
-    }
+async fn signup(context: web::Data<Manager>, body: Valid<Signup>) -> impl Responder {
+    context.signup(body.into_inner()).await
 }
 
 #[post("/vehicle/register")]
-async fn registervehicle(context: web::Data<Manager>, req_body:String) -> impl Responder {
-    match serde_json::from_str(&req_body) {
-        Ok(data) => context.registervehicle(data).await,
-        Err(_) => HttpResponse::NotAcceptable().body(json!({
-            "error": "Failed to parse request. Make sure it is a valid JSON payload."
-        }).to_string())
-    }
+async fn registervehicle(context: web::Data<Manager>, auth: Authenticated, body: Valid<RegisterVehicle>) -> impl Responder {
+    context.registervehicle(auth.uid, body.into_inner()).await
 }
 
 #[post("/vehicle/refresh")]
-async fn refreshvehicle(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    match serde_json::from_str(&req_body) {
-        Ok(data) => context.refreshvehicles(data).await,
-        Err(_) => HttpResponse::NotAcceptable().body(json!({
-            "error": "Failed to parse request. Make sure it is a valid JSON payload."
-        }).to_string())
-    }
+async fn refreshvehicle(context: web::Data<Manager>, auth: Authenticated) -> impl Responder {
+    context.refreshvehicles(auth.uid).await
 }
 
 #[post("/vehicle/edit")]
-async fn editvehicle(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    context.editvehicle(req_body).await
+async fn editvehicle(context: web::Data<Manager>, auth: Authenticated, body: Valid<EditVehicle>) -> impl Responder {
+    context.editvehicle(auth.uid, body.into_inner()).await
+}
+
+#[post("/vehicle/secret/rotate")]
+async fn rotatesecret(context: web::Data<Manager>, body: Valid<RotateSecret>, auth: Option<Authenticated>) -> impl Responder {
+    context.rotate_secret(auth.map(|auth| auth.uid), body.into_inner()).await
+}
+
+#[post("/vehicle/secret/revoke")]
+async fn revokesecret(context: web::Data<Manager>, auth: Authenticated, body: Valid<VehicleId>) -> impl Responder {
+    context.revoke_secret(auth.uid, body.into_inner()).await
+}
+
+#[post("/vehicle/unpair")]
+async fn unpair(context: web::Data<Manager>, auth: Authenticated, body: Valid<VehicleId>) -> impl Responder {
+    context.unpair(auth.uid, body.into_inner()).await
+}
+
+#[post("/vehicle/revoke")]
+async fn revoke(context: web::Data<Manager>, body: Valid<Revoke>, auth: Option<Authenticated>) -> impl Responder {
+    context.revoke(auth.map(|auth| auth.uid), body.into_inner()).await
+}
+
+#[post("/vehicle/role")]
+async fn changerole(context: web::Data<Manager>, auth: Authenticated, body: Valid<ChangeRole>) -> impl Responder {
+    context.change_role(auth.uid, body.into_inner()).await
 }
 
 // data management routes
 
 #[post("/logs/daily")]
-async fn dailylogs(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    context.dailylogs(req_body).await
+async fn dailylogs(context: web::Data<Manager>, auth: Authenticated, body: Valid<DailyLogs>) -> impl Responder {
+    context.dailylogs(auth.uid, body.into_inner()).await
 }
 
 #[post("/logs/periodic")]
-async fn periodiclogs(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    context.periodiclogs(req_body).await
+async fn periodiclogs(context: web::Data<Manager>, auth: Authenticated, body: Valid<PeriodicLogs>) -> impl Responder {
+    context.periodiclogs(auth.uid, body.into_inner()).await
+}
+
+#[post("/logs/health")]
+async fn health(context: web::Data<Manager>, auth: Authenticated, body: Valid<HealthReport>) -> impl Responder {
+    context.health(auth.uid, body.into_inner()).await
+}
+
+#[get("/logs/export")]
+async fn exportlogs(context: web::Data<Manager>, auth: Authenticated, query: ValidQuery<ExportLogs>, request: HttpRequest) -> impl Responder {
+    let accept = request.headers().get("Accept").and_then(|accept| accept.to_str().ok());
+    let query = query.into_inner();
+    let format = query.format(accept);
+    context.export(auth.uid, query, format).await
+}
+
+#[post("/telemetry/range")]
+async fn telemetryrange(context: web::Data<Manager>, auth: Authenticated, body: Valid<TelemetryRange>) -> impl Responder {
+    context.telemetry_range(auth.uid, body.into_inner()).await
+}
+
+#[post("/trips/list")]
+async fn trips(context: web::Data<Manager>, auth: Authenticated, body: Valid<TripList>) -> impl Responder {
+    context.trips(auth.uid, body.into_inner()).await
+}
+
+#[get("/trips/{tid}")]
+async fn trip(context: web::Data<Manager>, path: Path<Id>, auth: Authenticated) -> impl Responder {
+    context.trip(auth.uid, path.into_inner().0).await
+}
+
+#[get("/trips/{tid}/route")]
+async fn triproute(context: web::Data<Manager>, path: Path<Id>, auth: Authenticated, query: ValidQuery<RouteOptions>) -> impl Responder {
+    context.route(auth.uid, path.into_inner().0, query.into_inner()).await
+}
+
+#[post("/vehicle/location")]
+async fn location(context: web::Data<Manager>, auth: Authenticated, body: Valid<VehicleId>) -> impl Responder {
+    context.location(auth.uid, body.into_inner()).await
+}
+
+#[post("/geofences/create")]
+async fn creategeofence(context: web::Data<Manager>, auth: Authenticated, body: Valid<CreateGeofence>) -> impl Responder {
+    context.create_geofence(auth.uid, body.into_inner()).await
+}
+
+#[post("/geofences/list")]
+async fn geofences(context: web::Data<Manager>, auth: Authenticated, body: Valid<VehicleId>) -> impl Responder {
+    context.geofences(auth.uid, body.into_inner()).await
+}
+
+#[post("/geofences/delete")]
+async fn deletegeofence(context: web::Data<Manager>, auth: Authenticated, body: Valid<DeleteGeofence>) -> impl Responder {
+    context.delete_geofence(auth.uid, body.into_inner()).await
+}
+
+#[post("/geofences/events")]
+async fn geofenceevents(context: web::Data<Manager>, auth: Authenticated, body: Valid<GeofenceEvents>) -> impl Responder {
+    context.geofence_events(auth.uid, body.into_inner()).await
 }
 
 #[post("/logs/overall")]
-async fn overall_logs(context: web::Data<Manager>, req_body: String) -> impl Responder {
-    context.overall_logs(req_body).await
+async fn overall_logs(context: web::Data<Manager>, auth: Authenticated, body: Valid<OverallLogs>) -> impl Responder {
+    context.overall_logs(auth.uid, body.into_inner()).await
+}
+
+// everything the server answers to, along with how bodies & paths are parsed
+fn routes(config: &mut web::ServiceConfig) {
+    config
+        // clients have always sent bodies with whatever content type, so don't insist on JSON
+        .app_data(web::JsonConfig::default().content_type_required(false).content_type(|_| true))
+        .app_data(web::PathConfig::default().error_handler(requests::path_error))
+        .service(hello)
+        .service(logo)
+        .service(icon)
+        .service(social)
+        .service(login)
+        .service(refreshtoken)
+        .service(status)
+        .service(signup)
+        .service(registervehicle)
+        .service(refreshvehicle)
+        .service(editvehicle)
+        .service(rotatesecret)
+        .service(revokesecret)
+        .service(vehiclechallenge)
+        .service(joinvehicle)
+        .service(joinuser)
+        .service(pair)
+        .service(unpair)
+        .service(revoke)
+        .service(changerole)
+        .service(dailylogs)
+        .service(periodiclogs)
+        .service(overall_logs)
+        .service(health)
+        .service(exportlogs)
+        .service(telemetryrange)
+        .service(trips)
+        .service(trip)
+        .service(triproute)
+        .service(location)
+        .service(creategeofence)
+        .service(geofences)
+        .service(deletegeofence)
+        .service(geofenceevents)
+        .default_service(web::to(|| async { Err::<HttpResponse, _>(ApiError::RouteNotFound) }));
+}
+
+// what every worker serves, with each response tagged with the id of its request. The tests build
+// theirs here too
+fn app(
+    manager: web::Data<Manager>,
+    tokens: Tokens,
+) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    App::new()
+        .app_data(manager)
+        .app_data(web::Data::new(tokens))
+        .wrap_fn(|request, service| {
+            let id = errors::assign(&request);
+            let response = service.call(request);
+            async move { errors::tag(response.await, id) }
+        })
+        .configure(routes)
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017/").await.unwrap();
-    client_options.app_name = Some("alpadrive".to_string());
-    let client = Client::with_options(client_options).unwrap();
-    let database = client.database("alpadrive");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("The server couldn't start because of an invalid configuration:");
+            errors.iter().for_each(|error| eprintln!("  - {}", error));
+            std::process::exit(1);
+        }
+    };
+
     let active_vehicles = Arc::new(RwLock::new(HashMap::<String, String>::new()));
     let active_sessions = Arc::new(RwLock::new(0));
     let av_copy = Arc::clone(&active_vehicles);
     let sessions_copy = Arc::clone(&active_sessions);
 
-    let lobby = Lobby::new(active_vehicles, active_sessions).await;
-    let logger = Logger::new().await;
+    let codes = PairingCodes::new(Duration::from_secs(config.pairing.ttl), config.pairing.length, config.pairing.max_attempts, config.pairing.max_guesses);
+    let batching = Batching {
+        interval: Duration::from_secs(config.telemetry.flush_interval),
+        size: config.telemetry.batch_size
+    };
+    // already validated along with the rest of the config
+    let timezone = config.telemetry.timezone.parse().unwrap_or(chrono_tz::UTC);
+    let idle_gap = Duration::from_secs(config.trips.idle_gap);
+    let retention = Duration::from_secs(config.telemetry.retention_days * 24 * 60 * 60);
+    let (stores, logs): (Stores, Arc<dyn LogStore>) = match config.database.backend {
+        Backend::Mongodb => {
+            let mut client_options = match ClientOptions::parse(&config.database.uri).await {
+                Ok(options) => options,
+                Err(e) => {
+                    eprintln!("The server couldn't start because database.uri is invalid: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            client_options.app_name = Some(config.database.app_name.clone());
+            let client = match Client::with_options(client_options) {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("The server couldn't set up a MongoDB client: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let logs = MongoLogs::new(client.database(&config.database.logs), retention, timezone).await;
+            (Stores::mongodb(client.database(&config.database.name)), Arc::new(logs))
+        }
+        Backend::Memory => {
+            println!("database.backend is memory. Nothing is written to disk & everything is gone once the server stops.");
+            (Stores::memory(), Arc::new(MemoryLogs::new(retention)))
+        }
+    };
+    match stores.claim_owners().await {
+        Ok(0) => (),
+        Ok(claimed) => println!("Claimed owners for {} vehicles paired before ownership was tracked.", claimed),
+        Err(e) => println!("Failed to claim the owners of older vehicles: {}", e),
+    }
+    let logger = Logger::new(logs, batching, timezone, idle_gap);
+    let ingestion = logger.clone();
+    let lobby = Lobby::new(active_vehicles, active_sessions, codes.clone(), logger.clone()).await;
+    let secret = match &config.auth.token_secret {
+        Some(secret) => secret.clone().into_bytes(),
+        None => {
+            println!("auth.token_secret is not set. Using a random secret, so issued tokens won't survive a restart.");
+            let mut secret = vec![0u8; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            secret
+        }
+    };
+    let tokens = Tokens::new(&secret, Duration::from_secs(config.auth.access_ttl), Duration::from_secs(config.auth.refresh_ttl));
+    // already validated along with the rest of the config
+    let device_key = match config.auth.device_key.as_deref().and_then(|key| DeviceKey::new(&hex::decode(key).ok()?)) {
+        Some(key) => key,
+        None => {
+            println!("auth.device_key is not set. Using a random key, so vehicles registered now have to rotate their secret after a restart.");
+            DeviceKey::random()
+        }
+    };
+    let auth = Auth {
+        // already validated along with the rest of the config
+        credentials: Credentials::new(config.credentials.memory_cost, config.credentials.iterations, config.credentials.parallelism).unwrap_or_default(),
+        tokens: tokens.clone(),
+        challenges: Challenges::new(Duration::from_secs(config.auth.challenge_ttl)),
+        device_key,
+        codes
+    };
+    let heartbeat = Heartbeat {
+        interval: Duration::from_secs(config.sockets.heartbeat_interval),
+        timeout: Duration::from_secs(config.sockets.client_timeout)
+    };
     // let lobby = Lobby::default().start();
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(Manager::start(database.clone(), lobby.clone(), logger.clone(), Arc::clone(&av_copy), Arc::clone(&sessions_copy))))
-            .service(hello)
-            .service(logo)
-            .service(icon)
-            .service(social)
-            .service(login)
-            .service(status)
-            .service(signup)
-            .service(registervehicle)
-            .service(refreshvehicle)
-            .service(editvehicle)
-            .service(joinvehicle)
-            .service(joinuser)
-            .service(pair)
-            .service(dailylogs)
-            .service(periodiclogs)
-            .service(overall_logs)
-    })
-    .bind(("127.0.0.1", 7878))?
-    .run()
-    .await
-}
\ No newline at end of file
+    let server = HttpServer::new(move || {
+        let manager = Manager::start(stores.clone(), lobby.clone(), logger.clone(), Arc::clone(&av_copy), Arc::clone(&sessions_copy), auth.clone(), heartbeat);
+        app(web::Data::new(manager), tokens.clone())
+    });
+
+    // the servers stop on ctrl-c, telemetry that's still buffered is written before exiting
+    let result = async {
+        let address = (config.server.host.as_str(), config.server.port);
+        let (cert, key) = match (&config.tls.cert, &config.tls.key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => return server.bind(address)?.run().await,
+        };
+        let resolver = match CertResolver::new(&cert, &key) {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                eprintln!("The server couldn't load its TLS certificate: {}", e);
+                std::process::exit(1);
+            }
+        };
+        resolver.watch(cert, key, Duration::from_secs(config.tls.reload_interval));
+        let server = server.bind_rustls(address, resolver.server_config())?.run();
+        if !config.tls.redirect {
+            return server.await;
+        }
+
+        let port = config.server.port;
+        let redirect = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(port))
+                .default_service(web::to(tls::redirect))
+        })
+        .bind((config.server.host.as_str(), config.tls.redirect_port))?
+        .run();
+        futures::try_join!(server, redirect).map(|_| ())
+    }
+    .await;
+    ingestion.shutdown().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::Value;
+
+    const GARBAGE: [&str; 10] = [
+        "abc",
+        "zzzzzzzzzzzzzzzzzzzzzzzz",
+        "63307b31d0c18856548cef9",
+        "63307b31d0c18856548cef9d0",
+        "%22%22",
+        "%00",
+        "%F0%9F%98%80",
+        "..%2F..",
+        "null",
+        "6330%207b31d0c18856548cef9d",
+    ];
+
+    // nothing listens on the database port, so any route that gets past its extractors fails fast
+    async fn manager(tokens: Tokens) -> Manager {
+        let options = ClientOptions::parse("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100").await.unwrap();
+        let database = Client::with_options(options).unwrap().database("alpadrive-test");
+        let admins = Arc::new(RwLock::new(HashMap::new()));
+        let sessions = Arc::new(RwLock::new(0));
+        let codes = PairingCodes::default();
+        let lobby = Lobby::new(Arc::clone(&admins), Arc::clone(&sessions), codes.clone(), Logger::default()).await;
+        let auth = Auth {
+            credentials: Credentials::default(),
+            tokens,
+            challenges: Challenges::new(Duration::from_secs(60)),
+            device_key: DeviceKey::random(),
+            codes,
+        };
+        Manager::start(Stores::mongodb(database), lobby, Logger::default(), admins, sessions, auth, Heartbeat::default())
+    }
+
+    async fn call(path: &str) -> (StatusCode, Value) {
+        let tokens = Tokens::new(b"test-secret-that-is-long-enough!", Duration::from_secs(60), Duration::from_secs(120));
+        let access = tokens.issue(&ObjectId::new()).unwrap().access_token;
+        let service = test::init_service(app(web::Data::new(manager(tokens.clone()).await), tokens)).await;
+        let request = test::TestRequest::get()
+            .uri(path)
+            .insert_header(("Authorization", format!("Bearer {}", access)))
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        let http_status = response.status();
+        let body = test::read_body(response).await;
+        (http_status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[actix_web::test]
+    async fn garbage_ids_are_rejected_on_every_id_route() {
+        for route in ["/vehicle/challenge", "/join/vehicle", "/join/user"] {
+            for id in GARBAGE {
+                let (http_status, body) = call(&format!("{}/{}", route, id)).await;
+                assert_eq!(http_status, StatusCode::BAD_REQUEST, "{}/{}", route, id);
+                assert_eq!(body["code"], "invalid_id", "{}/{}", route, id);
+                assert_eq!(body["error"], "Failed to parse vid. Make sure it is a valid object ID.");
+                assert!(body["request_id"].is_string());
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn garbage_trip_ids_are_rejected() {
+        for id in GARBAGE {
+            let (http_status, body) = call(&format!("/trips/{}", id)).await;
+            assert_eq!(http_status, StatusCode::BAD_REQUEST, "/trips/{}", id);
+            assert_eq!(body["error"], "Failed to parse tid. Make sure it is a valid object ID.");
+            let (http_status, _) = call(&format!("/trips/{}/route", id)).await;
+            assert_eq!(http_status, StatusCode::BAD_REQUEST, "/trips/{}/route", id);
+        }
+    }
+
+    #[actix_web::test]
+    async fn route_tolerances_are_checked_before_the_trip_is_looked_up() {
+        for tolerance in ["-1", "1001", "NaN", "far"] {
+            let (http_status, body) = call(&format!("/trips/63307b31d0c18856548cef9d/route?tolerance={}", tolerance)).await;
+            assert!(http_status.is_client_error(), "{}", tolerance);
+            assert_ne!(body["code"], "internal", "{}", tolerance);
+        }
+    }
+
+    #[actix_web::test]
+    async fn export_queries_are_checked_before_anything_is_streamed() {
+        let queries = [
+            "vid=garbage",
+            "vid=63307b31d0c18856548cef9d&format=xml",
+            "vid=63307b31d0c18856548cef9d&data=weekly",
+            "vid=63307b31d0c18856548cef9d&start=yesterday",
+            "vid=63307b31d0c18856548cef9d&start=2023-04-02&end=2023-04-01",
+        ];
+        for query in queries {
+            let (http_status, body) = call(&format!("/logs/export?{}", query)).await;
+            assert_eq!(http_status, StatusCode::UNPROCESSABLE_ENTITY, "{}", query);
+            assert_eq!(body["code"], "validation_failed", "{}", query);
+        }
+        let (http_status, body) = call("/logs/export?vid=63307b31d0c18856548cef9d&data=telemetry&format=ndjson&start=2023-04-01").await;
+        assert_ne!(body["code"], "validation_failed");
+        assert_eq!(http_status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn quoted_ids_from_older_clients_still_parse() {
+        let (http_status, body) = call("/vehicle/challenge/%2263307b31d0c18856548cef9d%22").await;
+        assert_ne!(body["code"], "invalid_id");
+        // gets as far as the database, which isn't there
+        assert_eq!(http_status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn repeated_garbage_ids_dont_panic() {
+        for _ in 0..50 {
+            let (http_status, _) = call("/join/vehicle/not-an-id").await;
+            assert_eq!(http_status, StatusCode::BAD_REQUEST);
+        }
+        let (http_status, body) = call("/join/vehicle/63307b31d0c18856548cef9d").await;
+        assert_ne!(body["code"], "invalid_id");
+        assert_eq!(http_status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -1,432 +1,651 @@
 extern crate sockets;
 extern crate types;
 
+use crate::credentials::{Credentials, Verification};
+use crate::devices::{Challenges, DeviceKey};
+use crate::errors::ApiError;
+use crate::requests::{ChangeRole, CreateGeofence, DailyLogs, DeleteGeofence, EditVehicle, ExportLogs, GeofenceEvents, HealthReport, Login, OverallLogs, PeriodicLogs, RefreshToken, RegisterVehicle, Revoke, RotateSecret, RouteOptions, Signup, Status, TelemetryRange, TripList, VehicleId, DEFAULT_EVENTS, DEFAULT_TRIPS, MAX_POINTS};
+use crate::tokens::{TokenKind, Tokens};
 use actix::Addr;
-use actix_web::{web::Payload, HttpRequest, HttpResponse};
+use actix_web::{web::{self, Payload}, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use logger::Logger;
-use mongodb::{
-    bson::{doc, oid::ObjectId},
-    Database,
-};
-use serde_json::{json, Value};
-use serde::Deserialize;
+use chrono_tz::Tz;
+use futures::StreamExt;
+use logger::{export::{Export, Format}, telemetry::Range, Logger};
+use crate::store::{self, Stores};
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
 use sockets::{
+    messages::{self, RoleChange},
+    pairing::{PairingCodes, Redemption},
     sockets::Lobby,
-    ws::{Sender, WsConn},
+    ws::{Heartbeat, Sender, WsConn},
 };
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use sysinfo::SystemExt;
-use types::actors::{users::User, vehicles::Vehicle};
+use types::actors::{users::{Role, User}, vehicles::Vehicle};
 use uuid::Uuid;
 
+// everything needed to authenticate users & vehicles. Built once at startup & shared by all workers
+#[derive(Clone)]
+pub struct Auth {
+    pub credentials: Credentials,
+    pub tokens: Tokens,
+    pub challenges: Challenges,
+    pub device_key: DeviceKey,
+    pub codes: PairingCodes
+}
+
 pub struct Manager {
-    db: Database,
+    stores: Stores,
     lobby: Addr<Lobby>,
     admins: Arc<RwLock<HashMap<String, String>>>,
     sessions: Arc<RwLock<usize>>,
-    logger: Logger
+    logger: Logger,
+    credentials: Credentials,
+    tokens: Tokens,
+    challenges: Challenges,
+    device_key: DeviceKey,
+    codes: PairingCodes,
+    heartbeat: Heartbeat
 }
 
 impl Manager {
-    pub fn start(database: Database, lobby: Addr<Lobby>, logger: Logger, admins: Arc<RwLock<HashMap<String, String>>>, sessions: Arc<RwLock<usize>>) -> Manager {
+    pub fn start(stores: Stores, lobby: Addr<Lobby>, logger: Logger, admins: Arc<RwLock<HashMap<String, String>>>, sessions: Arc<RwLock<usize>>, auth: Auth, heartbeat: Heartbeat) -> Manager {
         Manager {
-            db: database,
+            stores,
             lobby,
             admins,
             sessions,
-            logger
-        }
-    }
-
-    pub fn status(&self, data: Value) -> HttpResponse {
-        let response = match serde_json::from_value(data["systemstat"].clone()) {
-            Ok(data) => {
-                let vehicles = self.admins.read().unwrap().len();
-                let sessions = self.sessions.read().unwrap();
-                let response = match data {
-                    true => {
-                        let mut system = sysinfo::System::new();
-                        system.refresh_all();
-                        json!({
-                            "active_users": *sessions - vehicles,
-                            "active_vehicles": vehicles,
-                            "active_sessions": *sessions,
-                            "memory_available": format!("{:.2} GB", system.get_total_memory() as f64 * 0.000001),
-                            "memory_used": format!("{:.2} GB", system.get_used_memory() as f64 * 0.000001),
-                            "total_swap": format!("{:.2} GB", system.get_total_swap() as f64 * 0.000001),
-                            "swap_used": format!("{:.2} GB", system.get_used_swap() as f64 * 0.000001)
-                        }).to_string()
-                    },
-                    false => json!({
-                        "active_users": *sessions - vehicles,
-                        "active_vehicles": vehicles,
-                        "active_sessions": *sessions
-                    }).to_string()
-                };
-                HttpResponse::Ok().body(response)
+            logger,
+            credentials: auth.credentials,
+            tokens: auth.tokens,
+            challenges: auth.challenges,
+            device_key: auth.device_key,
+            codes: auth.codes,
+            heartbeat
+        }
+    }
+
+
+    pub fn status(&self, data: Status) -> Result<HttpResponse, ApiError> {
+        let vehicles = self.admins.read().unwrap().len();
+        let sessions = self.sessions.read().unwrap();
+        let response = match data.systemstat {
+            true => {
+                let mut system = sysinfo::System::new();
+                system.refresh_all();
+                json!({
+                    "active_users": *sessions - vehicles,
+                    "active_vehicles": vehicles,
+                    "active_sessions": *sessions,
+                    "memory_available": format!("{:.2} GB", system.get_total_memory() as f64 * 0.000001),
+                    "memory_used": format!("{:.2} GB", system.get_used_memory() as f64 * 0.000001),
+                    "total_swap": format!("{:.2} GB", system.get_total_swap() as f64 * 0.000001),
+                    "swap_used": format!("{:.2} GB", system.get_used_swap() as f64 * 0.000001)
+                }).to_string()
             },
-            Err(_) => HttpResponse::BadRequest().body(json!({"error": "Request is not in a supported format. Make sure you have included the flag for systemstat."}).to_string())
+            false => json!({
+                "active_users": *sessions - vehicles,
+                "active_vehicles": vehicles,
+                "active_sessions": *sessions
+            }).to_string()
         };
-        response
+        Ok(HttpResponse::Ok().body(response))
+    }
+
+    // checks that the user is paired to the vehicle before letting them see or change anything about it
+    async fn authorize(&self, uid: &ObjectId, vid: &str) -> Result<(ObjectId, Role), ApiError> {
+        let vid = object_id("vid", vid)?;
+        let user = self.stores.users.get(uid).await?;
+        match user.and_then(|user| user.role(&vid)) {
+            Some(role) => Ok((vid, role)),
+            None => Err(ApiError::NotPaired)
+        }
     }
 
-    async fn is_vehicle_used_by_user(&self, vehicle_id: ObjectId) -> mongodb::error::Result<bool> {
-        let query = doc! {
-            "vehicles": {
-                "$elemMatch": {
-                    "$eq": vehicle_id
-                }
+    // same as authorize, but only lets the owner of the vehicle through
+    async fn authorize_owner(&self, uid: &ObjectId, vid: &str) -> Result<ObjectId, ApiError> {
+        match self.authorize(uid, vid).await? {
+            (vid, Role::Owner) => Ok(vid),
+            _ => Err(ApiError::NotOwner)
+        }
+    }
+
+    // the first user to pair a vehicle claims it as its owner, everyone after that starts as a viewer
+    async fn initial_role(&self, vid: &ObjectId, uid: &ObjectId) -> store::Result<Role> {
+        Ok(if self.stores.vehicles.claim(vid, uid).await? { Role::Owner } else { Role::Viewer })
+    }
+
+    // makes sure the vehicle signed a challenge we issued with its device secret
+    async fn verify_device(&self, vid: &ObjectId, challenge: Option<&String>, signature: Option<&String>) -> Result<Vehicle, ApiError> {
+        let vehicle = match self.stores.vehicles.get(vid).await? {
+            Some(vehicle) => vehicle,
+            None => return Err(ApiError::VehicleNotFound)
+        };
+        let secret = match &vehicle.secret {
+            Some(secret) => secret,
+            None => return Err(ApiError::NoDeviceSecret)
+        };
+        match (challenge, signature) {
+            (Some(challenge), Some(signature)) if self.challenges.take(&vid.to_hex(), challenge, || self.device_key.verify(secret, challenge, signature)) => Ok(vehicle),
+            _ => Err(ApiError::DeviceUnverified)
+        }
+    }
+
+    // the timezone the vehicle's days are counted in
+    async fn timezone(&self, vid: &ObjectId) -> Result<Tz, ApiError> {
+        match self.stores.vehicles.get(vid).await? {
+            Some(vehicle) => Ok(self.logger.timezone(vehicle.timezone.as_deref())),
+            None => Err(ApiError::VehicleNotFound)
+        }
+    }
+
+    // hashing is deliberately slow, so keep it off the async workers
+    async fn hash_password(&self, password: String) -> Result<String, ApiError> {
+        let credentials = self.credentials.clone();
+        match web::block(move || credentials.hash(&password)).await {
+            Ok(Ok(hash)) => Ok(hash),
+            Ok(Err(e)) => Err(ApiError::internal("hashing a password", e)),
+            Err(e) => Err(ApiError::internal("hashing a password", e))
+        }
+    }
+
+    // upgrades legacy plaintext passwords & hashes made with outdated parameters after a successful login.
+    // accounts stored JSON encoded get their quotes stripped along the way
+    async fn upgrade_account(&self, user: &User, password: String) {
+        let uid = match user._id {
+            Some(uid) => uid,
+            None => return
+        };
+        let password = match self.hash_password(password).await {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                eprintln!("Failed to rehash password for user {:?}: {:?}", user._id, e);
+                None
             }
         };
-    
-        let collection = self.db.collection::<User>("users");
-        let result = collection.count_documents(query, None).await?;
-    
-        Ok(result > 0)
+        if let Err(e) = self.stores.users.update_account(&uid, unquote(&user.name), unquote(&user.username), unquote(&user.email), password).await {
+            eprintln!("Failed to upgrade account {:?}: {}", user._id, e);
+        }
     }
 
     // Lobby management
 
+    pub async fn challenge(&self, vid: ObjectId) -> Result<HttpResponse, ApiError> {
+        if self.stores.vehicles.get(&vid).await?.is_none() {
+            return Err(ApiError::VehicleNotFound);
+        }
+        Ok(HttpResponse::Ok().body(json!({
+            "challenge": self.challenges.issue(&vid.to_hex()),
+            "expires_in": self.challenges.ttl().as_secs()
+        }).to_string()))
+    }
+
     pub async fn joinvehicle(
         &self,
-        uid: String,
+        vid: ObjectId,
+        proof: HashMap<String, String>,
         request: &HttpRequest,
         stream: Payload,
-    ) -> HttpResponse {
-        let collection = self.db.collection::<Vehicle>("vehicles");
-        match collection.find_one(doc! {"_id": ObjectId::from_str(&uid.to_string().replace('"', "")).unwrap()}, None).await {
-                Ok(data) => match data {
-                    Some(data) => {
-                        let ws = WsConn::new(data._id.to_hex(), Uuid::new_v4().to_string(), self.lobby.clone(), Sender::Admin);
-                        match ws::start(ws, request, stream) {
-                            Ok(response) => response,
-                            Err(e) => HttpResponse::InternalServerError().body(json!({"error": "The server faced an internal error trying to create a room.", "stacktrace": format!("{:#?}", e)}).to_string())
-                        }
-                    },
-                    None => HttpResponse::NotFound().body(json!({"error": "There is no vehicle with the supplied ID. Consider registering it first at /vehicle/register."}).to_string())          
-                },
-                Err(_) => HttpResponse::InternalServerError().body(json!({"error": "The server had an error trying to execute mongodb::Collection.insert_one()"}).to_string())
-            }
+    ) -> Result<HttpResponse, ApiError> {
+        let vehicle = self.verify_device(&vid, proof.get("challenge"), proof.get("signature")).await?;
+        self.logger.set_timezone(&vehicle._id.to_hex(), vehicle.timezone.as_deref());
+        self.logger.watch_fences(&vehicle._id.to_hex()).await;
+        let ws = WsConn::new(vehicle._id.to_hex(), Uuid::new_v4().to_string(), self.lobby.clone(), Sender::Admin, self.heartbeat);
+        ws::start(ws, request, stream).map_err(|e| ApiError::internal("creating a room", e))
     }
 
     pub async fn joinuser(
         &self,
-        uid: String,
-        vid: String,
+        uid: ObjectId,
+        vid: ObjectId,
         request: &HttpRequest,
         stream: Payload,
-    ) -> HttpResponse {
-        let users = self.db.collection::<User>("users");
-        let vehicles = self.db.collection::<Vehicle>("vehicles");
-        match users.find_one(doc! {"_id": ObjectId::from_str(&uid.to_string().replace('"', "")).unwrap()}, None).await {
-            Ok(res) => match res {
-                Some(user) => {
-                   match vehicles.find_one(doc! {"_id": ObjectId::from_str(&vid.to_string().replace('"', "")).unwrap()}, None).await {
-                        Ok(res) => match res {
-                            Some(vehicle) => {
-                                if user.vehicles.contains(&vehicle._id) {
-                                    let ws = WsConn::new(vid, Uuid::new_v4().to_string(), self.lobby.clone(), Sender::Client(uid));
-                                    let response = match ws::start(ws, request, stream) {
-                                        Ok(response) => response,
-                                        Err(e) => HttpResponse::InternalServerError().body(json!({"error": "The server faced an internal error trying to create a room.", "stacktrace": format!("{:#?}", e)}).to_string())
-                                    };
-                                    response
-                                }
-                                else {
-                                    HttpResponse::Unauthorized().body(json!({"error": "This user has no access to the vehicle. Securely link it first."}).to_string())
-                                }
-                            },
-                            None => HttpResponse::NotFound().body(json!({"error": "There is no vehicle with the supplied ID. Consider registering it first."}).to_string())
-                        },
-                        Err(_) => HttpResponse::InternalServerError().body(json!({"error": "The server had an error trying to execute mongodb::Collection.find_one()"}).to_string())
-                   }
-                },
-                None => HttpResponse::NotFound().body(json!({"error": "There is no user with the supplied ID. Consider signing up first."}).to_string())          
+    ) -> Result<HttpResponse, ApiError> {
+        let user = match self.stores.users.get(&uid).await? {
+            Some(user) => user,
+            None => return Err(ApiError::UserNotFound)
+        };
+        let vehicle = match self.stores.vehicles.get(&vid).await? {
+            Some(vehicle) => vehicle,
+            None => return Err(ApiError::VehicleNotFound)
+        };
+        match user.role(&vehicle._id) {
+            Some(role) => {
+                let ws = WsConn::new(vid.to_hex(), Uuid::new_v4().to_string(), self.lobby.clone(), Sender::Client(uid.to_hex(), role), self.heartbeat);
+                ws::start(ws, request, stream).map_err(|e| ApiError::internal("creating a room", e))
             },
-            Err(_) => HttpResponse::InternalServerError().body(json!({"error": "The server had an error trying to execute mongodb::Collection.find_one()"}).to_string())
+            None => Err(ApiError::NotPaired)
         }
     }
 
     // Account management
 
-    pub async fn signup(&self, request: Value) -> HttpResponse {
-        let user = User::parse_request(request);
-        let collection = self.db.collection::<User>("users");
-        // this processing can be further sped up by directly using doc!{"$or": [{"username": user.username}, {"email": user.email}]}
-        // but it would take away from the amount of detail which can be provided to the client
-        // compromising on it currently as sign up is a one time operation
-        match collection.find_one(doc! {"email": &user.email}, None).await {
-            Ok(data) => match data {
-                Some(data) => HttpResponse::Conflict().body(
-                    json!({
-                        "error":
-                            format!("Another user already exists with the email {}", data.email)
-                    })
-                    .to_string(),
-                ),
-                None => match collection
-                    .find_one(doc! {"username": &user.username}, None)
-                    .await
-                {
-                    Ok(data) => match data {
-                        Some(data) => HttpResponse::Conflict().body(
-                            json!({
-                                "error":
-                                    format!(
-                                        "Another user already exists with the username {}",
-                                        data.username
-                                    )
-                            })
-                            .to_string(),
-                        ),
-                        None => {
-                            match collection.insert_one(User {
-                                _id: Some(ObjectId::new()),
-                                name: user.name,
-                                username: user.username,
-                                password: user.password,
-                                email: user.email,
-                                vehicles: Vec::new()
-                            }, None).await {
-                                Ok(data) => HttpResponse::Ok().body(json!({"success": "Successfully signed up user", "uid": data.inserted_id}).to_string()),
-                                Err(_) => HttpResponse::InternalServerError().body(json!({"error": "The server had an error trying to execute mongodb::Collection.insert_one()"}).to_string())
-                            }
-                        }
-                    },
-                    Err(_) => HttpResponse::InternalServerError()
-                        .body("Error when trying to execute mongodb::Collection.find_one()"),
-                },
-            },
-            Err(_) => HttpResponse::InternalServerError()
-                .body("Error when trying to execute mongodb::Collection.find_one()"),
-        }
-    }
-
-    pub async fn login(&self, request: Value) -> HttpResponse {
-        let user = User::parse_request(request);
-
-        let collection = self.db.collection::<User>("users");
-        match collection.find_one(doc!{"$or": [{"username": user.username}, {"email": user.email}]}, None).await {
-            Ok(data) => match data {
-                Some(data) => {
-                    if data.password != user.password { HttpResponse::Unauthorized().body(json!({"error": "Wrong credentials"}).to_string()) }
-                    else {
-                        let mut vehicles: Vec<Vehicle> = vec![]; 
-                        if let Ok(mut cursor) = self.db.collection::<Vehicle>("vehicles").find(doc! {"_id": {"$in": data.vehicles}}, None).await {
-                            let mut flag = true;
-                            while flag {
-                                if let Ok(remains) = cursor.advance().await {
-                                    if !remains { flag = false; }
-                                    else {
-                                        if let Ok(vehicle) = cursor.deserialize_current() { vehicles.push(vehicle) }
-                                    }
-                                }
-                            }
-                        };
-                        HttpResponse::Ok().body(json!({
-                            "uid": match data._id {
-                                Some(id) => id,
-                                None => ObjectId::new()
-                            },
-                            "name": data.name,
-                            "username": data.username,
-                            "email": data.email,
-                            "vehicles": vehicles
-                        }).to_string())
-                     }
-                },
-                None => HttpResponse::NotFound().body(json!({"error": "User with this username wasn't found on this server"}).to_string())
-            },
-            Err(_) => HttpResponse::InternalServerError().body(json!({"error": "There was an error when trying to execute mongodb::collection.find_one()"}).to_string())
-        }
-    }
-
-    pub async fn refreshvehicles(&self, request: Value) -> HttpResponse {
-        let id = User::parse_id(request);
-        match self.db.collection::<User>("users").find_one(doc! {"_id": id}, None).await {
-            Ok(user) => match user {
-                Some(user) => {
-                    let mut vehicles: Vec<Vehicle> = vec![]; 
-                        if let Ok(mut cursor) = self.db.collection::<Vehicle>("vehicles").find(doc! {"_id": {"$in": user.vehicles}}, None).await {
-                            let mut flag = true;
-                            while flag {
-                                if let Ok(remains) = cursor.advance().await {
-                                    if !remains { flag = false; }
-                                    else {
-                                        if let Ok(vehicle) = cursor.deserialize_current() { vehicles.push(vehicle) }
-                                    }
-                                }
-                            }
-                        };
-                    HttpResponse::Ok().body(json!({
-                        "count": vehicles.len(),
-                        "vehicles": vehicles
-                    }).to_string())
+    pub async fn signup(&self, user: Signup) -> Result<HttpResponse, ApiError> {
+        // looking both up at once would be quicker, but it couldn't tell the client which one is taken.
+        // Sign up is a one time operation, so that's the better trade
+        if self.stores.users.by_email(&[user.email.clone(), legacy(&user.email)]).await?.is_some() {
+            return Err(ApiError::EmailTaken);
+        }
+        if self.stores.users.by_username(&[user.username.clone(), legacy(&user.username)]).await?.is_some() {
+            return Err(ApiError::UsernameTaken);
+        }
+        let password = self.hash_password(user.password).await?;
+        let uid = ObjectId::new();
+        self.stores.users.insert(User {
+            _id: Some(uid),
+            name: user.name,
+            username: user.username,
+            password,
+            email: user.email,
+            vehicles: Vec::new(),
+            roles: HashMap::new()
+        }).await?;
+        Ok(HttpResponse::Ok().body(json!({"success": "Successfully signed up user", "uid": uid}).to_string()))
+    }
+
+    pub async fn login(&self, user: Login) -> Result<HttpResponse, ApiError> {
+        let mut found = None;
+        if let Some(username) = &user.username {
+            found = self.stores.users.by_username(&[username.clone(), legacy(username)]).await?;
+        }
+        if let (None, Some(email)) = (&found, &user.email) {
+            found = self.stores.users.by_email(&[email.clone(), legacy(email)]).await?;
+        }
+        // users that don't exist are checked against a decoy hash & fail like a wrong password, so
+        // logins can't be used to find out who has an account, not even by timing them
+        let credentials = self.credentials.clone();
+        let (password, stored) = (user.password.clone(), found.as_ref().map(|data| data.password.clone()));
+        let verification = web::block(move || {
+            let stored = stored.as_deref().unwrap_or_else(|| credentials.decoy());
+            match credentials.verify(&password, stored) {
+                // passwords from before typed requests were hashed or stored with their quotes
+                Verification::Invalid => match credentials.verify(&legacy(&password), stored) {
+                    Verification::Invalid => Verification::Invalid,
+                    _ => Verification::Rehash
                 },
-                None => HttpResponse::NotFound().body(json!({"error": "User with this ID wasn't found on this server"}).to_string())
-            },
-            Err(_) => HttpResponse::InternalServerError().body(json!({"error": "There was an error when trying to execute mongodb::collection.find_one()"}).to_string())
+                verification => verification
+            }
+        }).await.unwrap_or(Verification::Invalid);
+        let data = match (found, &verification) {
+            (Some(data), Verification::Valid | Verification::Rehash) => data,
+            _ => return Err(ApiError::WrongCredentials)
+        };
+        if verification == Verification::Rehash || data.username.starts_with('"') {
+            self.upgrade_account(&data, user.password).await;
+        }
+        let roles = data.vehicle_roles();
+        let vehicles: Vec<Vehicle> = self.stores.vehicles.list(&data.vehicles).await.unwrap_or_default();
+        let uid = match data._id {
+            Some(id) => id,
+            None => ObjectId::new()
+        };
+        let tokens = self.tokens.issue(&uid).map_err(|e| ApiError::internal("issuing access tokens", e))?;
+        Ok(HttpResponse::Ok().body(json!({
+            "uid": uid,
+            "name": unquote(&data.name),
+            "username": unquote(&data.username),
+            "email": unquote(&data.email),
+            "vehicles": vehicles,
+            "roles": roles,
+            "access_token": tokens.access_token,
+            "refresh_token": tokens.refresh_token,
+            "expires_in": tokens.expires_in
+        }).to_string()))
+    }
+
+    pub async fn refresh_token(&self, request: RefreshToken) -> Result<HttpResponse, ApiError> {
+        let uid = match self.tokens.validate(&request.refresh_token, TokenKind::Refresh) {
+            Some(uid) => uid,
+            None => return Err(ApiError::Unauthenticated("The refresh token is invalid or has expired. Log in again."))
+        };
+        if self.stores.users.get(&uid).await?.is_none() {
+            return Err(ApiError::Unauthenticated("The user this token was issued to no longer exists"));
         }
+        let tokens = self.tokens.issue(&uid).map_err(|e| ApiError::internal("issuing access tokens", e))?;
+        Ok(HttpResponse::Ok().body(json!(tokens).to_string()))
+    }
+
+    pub async fn refreshvehicles(&self, uid: ObjectId) -> Result<HttpResponse, ApiError> {
+        let user = match self.stores.users.get(&uid).await? {
+            Some(user) => user,
+            None => return Err(ApiError::UserNotFound)
+        };
+        let roles = user.vehicle_roles();
+        let vehicles: Vec<Vehicle> = self.stores.vehicles.list(&user.vehicles).await.unwrap_or_default();
+        Ok(HttpResponse::Ok().body(json!({
+            "count": vehicles.len(),
+            "vehicles": vehicles,
+            "roles": roles
+        }).to_string()))
     }
 
-    pub async fn registervehicle(&self, request: Value) -> HttpResponse {
-        let vehicle = Vehicle::parse_request(request);
-        let collection = self.db.collection::<Vehicle>("vehicles");
-        match collection.insert_one(vehicle, None).await {
-                Ok(data) => HttpResponse::Ok().body(json!({"success": "Vehicle was registered", "id": data.inserted_id}).to_string()),
-                Err(e) => HttpResponse::InternalServerError().body(json!({"error": "There was an error trying to execute mongodb::collection.insert_one()", "stacktrace": e.to_string()}).to_string())
+    // whoever registers the vehicle owns it & is paired to it right away
+    pub async fn registervehicle(&self, uid: ObjectId, request: RegisterVehicle) -> Result<HttpResponse, ApiError> {
+        let secret = self.device_key.generate();
+        let vid = ObjectId::new();
+        let vehicle = Vehicle { _id: vid, company: request.company, model: request.model, secret: Some(secret.sealed), timezone: request.timezone, owners: vec![uid] };
+        self.stores.vehicles.insert(vehicle).await?;
+        self.stores.users.pair(&uid, &vid, Role::Owner).await?;
+        Ok(HttpResponse::Ok().body(json!({"success": "Vehicle was registered", "id": vid, "secret": secret.secret}).to_string()))
+    }
+
+    // either the vehicle proves it holds the current secret, or a paired user vouches for it
+    // (needed when the secret was lost or revoked)
+    pub async fn rotate_secret(&self, uid: Option<ObjectId>, data: RotateSecret) -> Result<HttpResponse, ApiError> {
+        let vid = match uid {
+            Some(uid) => self.authorize_owner(&uid, &data.vid).await?,
+            None => {
+                let vid = object_id("vid", &data.vid)?;
+                self.verify_device(&vid, data.challenge.as_ref(), data.signature.as_ref()).await?;
+                vid
             }
+        };
+        let secret = self.device_key.generate();
+        if !self.stores.vehicles.set_secret(&vid, Some(secret.sealed)).await? {
+            return Err(ApiError::VehicleNotFound);
+        }
+        Ok(HttpResponse::Ok().body(json!({"success": "A new device secret was issued. The previous one no longer works.", "secret": secret.secret}).to_string()))
     }
 
-    pub async fn editvehicle(&self, request: String) -> HttpResponse {
-        #[derive(Deserialize)]
-        struct Format {
-            vid: String,
-            company: String,
-            model: String
-        }
-        let collection = self.db.collection::<Vehicle>("vehicles");
-        match serde_json::from_str::<Format>(&request) {
-            Ok(data) => match ObjectId::from_str(&data.vid.clone()) {
-                Ok(vid) => match collection.update_one(doc!{"_id": vid}, doc!{"$set": {"company": data.company.clone(), "model": data.model.clone()}}, None).await {
-                    Ok(result) => {
-                        if result.matched_count <= 0 {
-                            HttpResponse::NotFound().body(json!({"error": "There is no vehicle with the specified vid", "suggestion": "Consider registering it first"}).to_string())
-                        } else {
-                            HttpResponse::Ok().body(json!({
-                                "success": "The vehicle was updated",
-                                "document": {
-                                    "id": {
-                                        "$oid": data.vid
-                                    },
-                                    "company": data.company,
-                                    "model": data.model
-                                }
-                            }).to_string())
-                        }
-                    },
-                    Err(e) => HttpResponse::InternalServerError().body(json!({
-                        "error": "Oops! The server ran into an issue. Failed to update vehicle details.",
-                        "stacktrace": format!("{:?}", e)
-                    }).to_string())
+    pub async fn revoke_secret(&self, uid: ObjectId, data: VehicleId) -> Result<HttpResponse, ApiError> {
+        let vid = self.authorize_owner(&uid, &data.vid).await?;
+        self.stores.vehicles.set_secret(&vid, None).await?;
+        Ok(HttpResponse::Ok().body(json!({"success": "The device secret was revoked. The vehicle can't host a room until a new one is issued."}).to_string()))
+    }
+
+    pub async fn editvehicle(&self, uid: ObjectId, data: EditVehicle) -> Result<HttpResponse, ApiError> {
+        let vid = self.authorize_owner(&uid, &data.vid).await?;
+        if !self.stores.vehicles.update(&vid, data.company.clone(), data.model.clone(), data.timezone.clone()).await? {
+            return Err(ApiError::VehicleNotFound);
+        }
+        if data.timezone.is_some() {
+            self.logger.set_timezone(&vid.to_hex(), data.timezone.as_deref());
+        }
+        Ok(HttpResponse::Ok().body(json!({
+            "success": "The vehicle was updated",
+            "document": {
+                "id": {
+                    "$oid": data.vid
                 },
-                Err(e) => HttpResponse::NotAcceptable().body(json!({
-                    "error": "Failed to parse vid. Make sure it is a valid object ID.",
-                    "stacktrace": format!("{:?}", e)
-                }).to_string())
-            },
-            Err(e) => HttpResponse::NotAcceptable().body(json!({
-                "error": "Failed to parse request. Make sure it is a valid JSON payload in the directed format.",
-                "stacktrace": format!("{:?}", e)
-            }).to_string())
-        }
-    }
-
-    pub async fn pair(&self, uid: String, vid: String, request: &HttpRequest, stream: Payload, initial: bool) -> HttpResponse {
-        let users = self.db.collection::<User>("users");
-        let vehicles = self.db.collection::<Vehicle>("vehicles");
-
-        match users.find_one(doc! {"_id": ObjectId::from_str(&uid.to_string().replace('"', "")).unwrap()}, None).await {
-            Ok(result) => {
-                match result {
-                    Some(user) => {
-                        match vehicles.find_one(doc! {"_id": ObjectId::from_str(&vid.to_string().replace('"', "")).unwrap()}, None).await {
-                            Ok(result) => {
-                                let auth = match self.is_vehicle_used_by_user(ObjectId::from_str(&vid.to_string().replace('"', "")).unwrap()).await {
-                                    Ok(result) => result,
-                                    Err(_) => true
-                                };
-                                // if this is the first time but it's already been paired
-                                if initial && auth {
-                                    HttpResponse::Unauthorized().body(json!({"error": "This code has expired", "suggestion": "Use the code generated by the app."}).to_string())
-                                } else {
-                                    match result {
-                                        Some(vehicle) => {
-                                            let mut list = user.vehicles.clone();
-                                            list.insert(00, vehicle._id);
-                                            let message = match users.update_one(user.document(), doc! {"$set": {"vehicles": list}}, None).await {
-                                                Ok(result) => {
-                                                    if result.modified_count > 0 { String::from("Pair successful") }
-                                                    else { String::from("Database had an unknown error") }
-                                                },
-                                                Err(e) => format!("Database reported an error: {:#?}", e)
-                                            };
-                                            let ws = WsConn::new(vid.clone(), Uuid::new_v4().to_string(), self.lobby.clone(), Sender::Pair(json!({"message": message.clone(), "uid": uid.clone(), "vid": vid.clone()}).to_string()));
-                                            match ws::start(ws, request, stream) {
-                                                Ok(response) => response,
-                                                Err(e) => HttpResponse::InternalServerError().body(json!({"error": "The server faced an internal error trying to create a room.", "stacktrace": format!("{:#?}", e)}).to_string())
-                                            }
-                                        },
-                                        None => HttpResponse::NotFound().body(json!({"error": "There is no vehicle with the specified ID.", "suggestion": "Register the vehicle at /vehicle/register"}).to_string())
-                                    }
-                                }
-                            },
-                            Err(e) => HttpResponse::InternalServerError().body(json!({"error": "There was an error trying to execute mongodb::collection.find_one()", "stacktrace": format!("{:#?}", e)}).to_string())
-                        }
-                    },
-                    None => HttpResponse::NotFound().body(json!({"error": "There is no user with the specified ID.", "suggestion": "Sign up the user at /signup"}).to_string())
-                }
-            },
-            Err(e) => HttpResponse::InternalServerError().body(json!({"error": "There was an error trying to execute mongodb::collection.find_one()", "stacktrace": format!("{:#?}", e)}).to_string())
+                "company": data.company,
+                "model": data.model,
+                "timezone": data.timezone
+            }
+        }).to_string()))
+    }
+
+    pub async fn pair(&self, uid: ObjectId, code: String, request: &HttpRequest, stream: Payload) -> Result<HttpResponse, ApiError> {
+        // the peer itself, forwarded-for headers are up to the client
+        let source = request.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+        let vid = match self.codes.check(&uid.to_hex(), &source, &code) {
+            Redemption::Valid(vid) => vid,
+            Redemption::Unknown => return Err(ApiError::CodeUnknown),
+            Redemption::Expired => return Err(ApiError::CodeExpired),
+            Redemption::Used => return Err(ApiError::CodeUsed),
+            Redemption::TooManyAttempts => return Err(ApiError::TooManyAttempts)
+        };
+
+        if self.stores.users.get(&uid).await?.is_none() {
+            return Err(ApiError::UserNotFound);
+        }
+        // codes are only ever issued for rooms hosted by registered vehicles
+        let vehicle = match self.stores.vehicles.get(&object_id("vid", &vid)?).await? {
+            Some(vehicle) => vehicle,
+            None => return Err(ApiError::VehicleNotFound)
+        };
+        // only now that pairing can go through, so a failed check doesn't cost the user the code
+        if !self.codes.redeem(&code) {
+            return Err(ApiError::CodeUsed);
+        }
+        let role = self.initial_role(&vehicle._id, &uid).await?;
+        // newest pairs go first, and pairing twice doesn't duplicate the vehicle
+        let message = match self.stores.users.pair(&uid, &vehicle._id, role).await? {
+            true => "Pair successful",
+            false => "The user is already paired to this vehicle"
+        };
+        let ws = WsConn::new(vid.clone(), Uuid::new_v4().to_string(), self.lobby.clone(), Sender::Pair(json!({"message": message, "uid": uid.to_hex(), "vid": vid.clone(), "role": role}).to_string()), self.heartbeat);
+        ws::start(ws, request, stream).map_err(|e| ApiError::internal("creating a room", e))
+    }
+
+    // takes the user off the vehicle's owners, unless they're the last one. The check is part of
+    // the write, so two owners dropping each other at once can't both go
+    async fn release_owner(&self, vid: &ObjectId, uid: &ObjectId) -> Result<(), ApiError> {
+        if self.stores.vehicles.release(vid, uid).await? {
+            return Ok(());
         }
+        match self.stores.vehicles.get(vid).await? {
+            Some(vehicle) if vehicle.owners.contains(uid) => Err(ApiError::LastOwner),
+            _ => Ok(())
+        }
+    }
+
+    // removes the pair & kicks any live connection of the user out of the vehicle's room
+    async fn remove_pair(&self, uid: ObjectId, vid: ObjectId) -> Result<HttpResponse, ApiError> {
+        self.release_owner(&vid, &uid).await?;
+        if !self.stores.users.unpair(&uid, &vid).await? {
+            return Err(ApiError::NotPaired);
+        }
+        self.lobby.do_send(messages::Revoke { room_id: vid.to_hex(), uid: uid.to_hex() });
+        Ok(HttpResponse::Ok().body(json!({"success": "The user was unpaired from the vehicle", "uid": uid.to_hex(), "vid": vid.to_hex()}).to_string()))
+    }
+
+    pub async fn unpair(&self, uid: ObjectId, data: VehicleId) -> Result<HttpResponse, ApiError> {
+        self.remove_pair(uid, object_id("vid", &data.vid)?).await
+    }
+
+    // lets the owner or the vehicle itself drop a user. The vehicle proves itself with a signed
+    // challenge like it does to host a room
+    pub async fn revoke(&self, owner: Option<ObjectId>, data: Revoke) -> Result<HttpResponse, ApiError> {
+        let (vid, uid) = (object_id("vid", &data.vid)?, object_id("uid", &data.uid)?);
+        match owner {
+            Some(owner) => { self.authorize_owner(&owner, &data.vid).await?; },
+            None => { self.verify_device(&vid, data.challenge.as_ref(), data.signature.as_ref()).await?; }
+        };
+        self.remove_pair(uid, vid).await
+    }
+
+    pub async fn change_role(&self, owner: ObjectId, data: ChangeRole) -> Result<HttpResponse, ApiError> {
+        let vid = self.authorize_owner(&owner, &data.vid).await?;
+        let uid = object_id("uid", &data.uid)?;
+        // keeps a vehicle from ending up without an owner
+        if uid == owner {
+            return Err(ApiError::OwnRoleChange);
+        }
+        // a demoted owner leaves the vehicle's owners first, a promoted user joins them once the
+        // role is set
+        if data.role != Role::Owner {
+            self.release_owner(&vid, &uid).await?;
+        }
+        if !self.stores.users.set_role(&uid, &vid, data.role).await? {
+            return Err(ApiError::NotPaired);
+        }
+        if data.role == Role::Owner {
+            self.stores.vehicles.add_owner(&vid, &uid).await?;
+        }
+        self.lobby.do_send(RoleChange { room_id: vid.to_hex(), uid: uid.to_hex(), role: data.role });
+        Ok(HttpResponse::Ok().body(json!({"success": "The role was updated", "uid": uid.to_hex(), "vid": vid.to_hex(), "role": data.role}).to_string()))
     }
 
     // Data management
 
-    pub async fn dailylogs(&self, request: String) -> HttpResponse {
-        #[derive(Deserialize)]
-        struct Format {
-            vid: String,
-            date: String
+    pub async fn dailylogs(&self, uid: ObjectId, data: DailyLogs) -> Result<HttpResponse, ApiError> {
+        let (vid, _) = self.authorize(&uid, &data.vid).await?;
+        let timezone = self.timezone(&vid).await?;
+        match self.logger.dailylogs(data.vid.clone(), data.day(), timezone).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching daily logs", e))
         }
-        match serde_json::from_str::<Format>(&request) {
-            Ok(data) => match self.logger.dailylogs(data.date, data.vid).await {
-                Ok(result) => HttpResponse::Ok().body(result),
-                Err(e) => HttpResponse::InternalServerError().body(json!({"error": "Something unexpected happened when trying to fetch logs", "stacktrace": format!("{}", e)}).to_string())
-            },
-            Err(e) => HttpResponse::NotAcceptable().body(json!({
-                "error": "Failed to parse request. Make sure it is a valid JSON payload in the directed format.",
-                "stacktrace": format!("{:?}", e)
-            }).to_string())
+    }
+
+    pub async fn periodiclogs(&self, uid: ObjectId, data: PeriodicLogs) -> Result<HttpResponse, ApiError> {
+        let (vid, _) = self.authorize(&uid, &data.vid).await?;
+        let timezone = self.timezone(&vid).await?;
+        let (start, end) = data.days();
+        let bucket = data.bucket();
+        match self.logger.periodiclogs(data.vid, start, end, timezone, bucket).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching periodic logs", e))
         }
     }
 
-    pub async fn periodiclogs(&self, request: String) -> HttpResponse {
-        #[derive(Deserialize)]
-        struct Format {
-            vid: String,
-            start: String,
-            end: String
+    pub async fn health(&self, uid: ObjectId, data: HealthReport) -> Result<HttpResponse, ApiError> {
+        let (vid, _) = self.authorize(&uid, &data.vid).await?;
+        let timezone = self.timezone(&vid).await?;
+        let (start, end) = data.days();
+        match self.logger.health(data.vid, start, end, timezone).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("rating vehicle health", e))
         }
-        match serde_json::from_str::<Format>(&request) {
-            Ok(data) => match self.logger.periodiclogs(data.vid, data.start, data.end).await {
-                Ok(result) => HttpResponse::Ok().body(result),
-                Err(e) => HttpResponse::InternalServerError().body(json!({"error": "Something unexpected happened when trying to fetch logs", "stacktrace": format!("{}", e)}).to_string())
-            },
-            Err(e) => HttpResponse::NotAcceptable().body(json!({
-                "error": "Failed to parse request. Make sure it is a valid JSON payload in the directed format.",
-                "stacktrace": format!("{:?}", e)
-            }).to_string())
+    }
+
+    pub async fn overall_logs(&self, uid: ObjectId, data: OverallLogs) -> Result<HttpResponse, ApiError> {
+        let (vid, _) = self.authorize(&uid, &data.vid).await?;
+        let timezone = self.timezone(&vid).await?;
+        let bucket = data.bucket();
+        match self.logger.overall_logs(data.vid, timezone, bucket).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching overall logs", e))
         }
     }
 
-    pub async fn overall_logs(&self, request: String) -> HttpResponse {
-        #[derive(Deserialize)]
-        struct Format {
-            vid: String
+    pub async fn export(&self, uid: ObjectId, data: ExportLogs, format: Format) -> Result<HttpResponse, ApiError> {
+        let (vid, _) = self.authorize(&uid, &data.vid).await?;
+        let timezone = self.timezone(&vid).await?;
+        let (start, end) = data.days();
+        let export = Export { vid: data.vid.clone(), data: data.data(), format, start, end, timezone };
+        let filename = format!("{}-{}.{}", data.vid, export.data.name(), format.extension());
+        // the status is already out by the time a row fails, so all that's left is to cut the download short
+        let rows = match self.logger.export(export).await {
+            Ok(rows) => rows.map(move |chunk| chunk.map(web::Bytes::from).map_err(|e| {
+                eprintln!("Exporting logs of {} failed midway: {}", vid, e);
+                std::io::Error::other(e)
+            })),
+            Err(e) => return Err(ApiError::internal("exporting logs", e))
+        };
+        Ok(HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+            .streaming(rows))
+    }
+
+    pub async fn telemetry_range(&self, uid: ObjectId, data: TelemetryRange) -> Result<HttpResponse, ApiError> {
+        self.authorize(&uid, &data.vid).await?;
+        let (start, end) = data.bounds();
+        let range = Range {
+            vid: data.vid,
+            start: start.timestamp_millis(),
+            end: end.timestamp_millis(),
+            interval: data.interval,
+            limit: data.limit.unwrap_or(MAX_POINTS)
+        };
+        match self.logger.telemetry(range).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching telemetry", e))
         }
-        match serde_json::from_str::<Format>(&request) {
-            Ok(data) => match self.logger.overall_logs(data.vid).await {
-                Ok(result) => HttpResponse::Ok().body(result),
-                Err(e) => HttpResponse::InternalServerError().body(json!({"error": "Something unexpected happened when trying to fetch logs", "stacktrace": format!("{}", e)}).to_string())
-            },
-            Err(e) => HttpResponse::NotAcceptable().body(json!({
-                "error": "Failed to parse request. Make sure it is a valid JSON payload in the directed format.",
-                "stacktrace": format!("{:?}", e)
-            }).to_string())
+    }
+
+    pub async fn trips(&self, uid: ObjectId, data: TripList) -> Result<HttpResponse, ApiError> {
+        self.authorize(&uid, &data.vid).await?;
+        let before = data.before().map(|before| before.timestamp_millis());
+        match self.logger.trips(data.vid, data.limit.unwrap_or(DEFAULT_TRIPS), before).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching trips", e))
+        }
+    }
+
+    // trips of vehicles the user isn't paired to look the same as ones that don't exist
+    pub async fn trip(&self, uid: ObjectId, id: ObjectId) -> Result<HttpResponse, ApiError> {
+        let (vid, trip) = match self.logger.trip(id).await {
+            Ok(Some(trip)) => trip,
+            Ok(None) => return Err(ApiError::TripNotFound),
+            Err(e) => return Err(ApiError::internal("fetching a trip", e))
+        };
+        match self.authorize(&uid, &vid).await {
+            Ok(_) => Ok(HttpResponse::Ok().body(trip)),
+            Err(ApiError::NotPaired) => Err(ApiError::TripNotFound),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub async fn route(&self, uid: ObjectId, id: ObjectId, options: RouteOptions) -> Result<HttpResponse, ApiError> {
+        let vid = match self.logger.trip(id).await {
+            Ok(Some((vid, _))) => vid,
+            Ok(None) => return Err(ApiError::TripNotFound),
+            Err(e) => return Err(ApiError::internal("fetching a trip", e))
+        };
+        match self.authorize(&uid, &vid).await {
+            Err(ApiError::NotPaired) => return Err(ApiError::TripNotFound),
+            Err(e) => return Err(e),
+            Ok(_) => ()
+        }
+        match self.logger.route(id, options.tolerance).await {
+            Ok(result) => Ok(HttpResponse::Ok().content_type("application/geo+json").body(result)),
+            Err(e) => Err(ApiError::internal("fetching a route", e))
+        }
+    }
+
+    pub async fn location(&self, uid: ObjectId, data: VehicleId) -> Result<HttpResponse, ApiError> {
+        self.authorize(&uid, &data.vid).await?;
+        match self.logger.location(data.vid).await {
+            Ok(Some(result)) => Ok(HttpResponse::Ok().body(result)),
+            Ok(None) => Err(ApiError::LocationUnknown),
+            Err(e) => Err(ApiError::internal("fetching a location", e))
+        }
+    }
+
+    // Geofences
+
+    pub async fn create_geofence(&self, uid: ObjectId, data: CreateGeofence) -> Result<HttpResponse, ApiError> {
+        self.authorize_owner(&uid, &data.vid).await?;
+        match self.logger.add_fence(data.vid, data.name.trim().to_string(), data.shape).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("creating a geofence", e))
         }
     }
+
+    pub async fn geofences(&self, uid: ObjectId, data: VehicleId) -> Result<HttpResponse, ApiError> {
+        self.authorize(&uid, &data.vid).await?;
+        match self.logger.fences(data.vid).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching geofences", e))
+        }
+    }
+
+    pub async fn delete_geofence(&self, uid: ObjectId, data: DeleteGeofence) -> Result<HttpResponse, ApiError> {
+        self.authorize_owner(&uid, &data.vid).await?;
+        match self.logger.remove_fence(data.vid, object_id("fid", &data.fid)?).await {
+            Ok(true) => Ok(HttpResponse::Ok().body(json!({"success": "Geofence was deleted"}).to_string())),
+            Ok(false) => Err(ApiError::GeofenceNotFound),
+            Err(e) => Err(ApiError::internal("deleting a geofence", e))
+        }
+    }
+
+    pub async fn geofence_events(&self, uid: ObjectId, data: GeofenceEvents) -> Result<HttpResponse, ApiError> {
+        self.authorize(&uid, &data.vid).await?;
+        let before = data.before().map(|before| before.timestamp_millis());
+        match self.logger.fence_events(data.vid, data.limit.unwrap_or(DEFAULT_EVENTS), before).await {
+            Ok(result) => Ok(HttpResponse::Ok().body(result)),
+            Err(e) => Err(ApiError::internal("fetching geofence events", e))
+        }
+    }
+}
+
+fn object_id(field: &'static str, value: &str) -> Result<ObjectId, ApiError> {
+    ObjectId::from_str(value).map_err(|_| ApiError::InvalidId(field))
+}
+
+// accounts from before request bodies were typed have every string stored JSON encoded, quotes & all
+fn legacy(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn unquote(value: &str) -> String {
+    match value.starts_with('"') {
+        true => serde_json::from_str(value).unwrap_or_else(|_| value.to_string()),
+        false => value.to_string()
+    }
 }
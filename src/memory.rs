@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use types::actors::{users::{Role, User}, vehicles::Vehicle};
+
+use crate::store::{Result, UserStore, VehicleStore};
+
+// users in the order they signed up, nothing survives a restart
+#[derive(Default)]
+pub struct MemoryUsers {
+    users: RwLock<Vec<User>>,
+}
+
+impl MemoryUsers {
+    // runs `change` on the user, false when there's no such user
+    fn modify(&self, uid: &ObjectId, change: impl FnOnce(&mut User) -> bool) -> bool {
+        let mut users = self.users.write().unwrap();
+        match users.iter_mut().find(|user| user._id.as_ref() == Some(uid)) {
+            Some(user) => change(user),
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUsers {
+    async fn get(&self, uid: &ObjectId) -> Result<Option<User>> {
+        Ok(self.users.read().unwrap().iter().find(|user| user._id.as_ref() == Some(uid)).cloned())
+    }
+
+    async fn by_username(&self, usernames: &[String]) -> Result<Option<User>> {
+        Ok(self.users.read().unwrap().iter().find(|user| usernames.contains(&user.username)).cloned())
+    }
+
+    async fn by_email(&self, emails: &[String]) -> Result<Option<User>> {
+        Ok(self.users.read().unwrap().iter().find(|user| emails.contains(&user.email)).cloned())
+    }
+
+    async fn insert(&self, user: User) -> Result<()> {
+        self.users.write().unwrap().push(user);
+        Ok(())
+    }
+
+    async fn update_account(&self, uid: &ObjectId, name: String, username: String, email: String, password: Option<String>) -> Result<()> {
+        self.modify(uid, |user| {
+            (user.name, user.username, user.email) = (name, username, email);
+            if let Some(password) = password {
+                user.password = password;
+            }
+            true
+        });
+        Ok(())
+    }
+
+    async fn paired(&self, vid: &ObjectId) -> Result<Vec<User>> {
+        let mut users: Vec<User> = self.users.read().unwrap().iter().filter(|user| user.vehicles.contains(vid)).cloned().collect();
+        users.sort_by_key(|user| user._id);
+        Ok(users)
+    }
+
+    async fn pair(&self, uid: &ObjectId, vid: &ObjectId, role: Role) -> Result<bool> {
+        Ok(self.modify(uid, |user| {
+            if user.vehicles.contains(vid) {
+                return false;
+            }
+            user.vehicles.insert(0, *vid);
+            user.roles.insert(vid.to_hex(), role);
+            true
+        }))
+    }
+
+    async fn unpair(&self, uid: &ObjectId, vid: &ObjectId) -> Result<bool> {
+        Ok(self.modify(uid, |user| {
+            let count = user.vehicles.len();
+            user.vehicles.retain(|paired| paired != vid);
+            let role = user.roles.remove(&vid.to_hex());
+            user.vehicles.len() < count || role.is_some()
+        }))
+    }
+
+    async fn set_role(&self, uid: &ObjectId, vid: &ObjectId, role: Role) -> Result<bool> {
+        Ok(self.modify(uid, |user| {
+            if !user.vehicles.contains(vid) {
+                return false;
+            }
+            user.roles.insert(vid.to_hex(), role);
+            true
+        }))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryVehicles {
+    vehicles: RwLock<HashMap<ObjectId, Vehicle>>,
+}
+
+#[async_trait]
+impl VehicleStore for MemoryVehicles {
+    async fn get(&self, vid: &ObjectId) -> Result<Option<Vehicle>> {
+        Ok(self.vehicles.read().unwrap().get(vid).cloned())
+    }
+
+    async fn list(&self, vids: &[ObjectId]) -> Result<Vec<Vehicle>> {
+        let vehicles = self.vehicles.read().unwrap();
+        Ok(vids
+            .iter()
+            .filter_map(|vid| vehicles.get(vid))
+            .map(|vehicle| Vehicle { secret: None, ..vehicle.clone() })
+            .collect())
+    }
+
+    async fn insert(&self, vehicle: Vehicle) -> Result<()> {
+        self.vehicles.write().unwrap().insert(vehicle._id, vehicle);
+        Ok(())
+    }
+
+    async fn claim(&self, vid: &ObjectId, uid: &ObjectId) -> Result<bool> {
+        match self.vehicles.write().unwrap().get_mut(vid) {
+            Some(vehicle) if vehicle.owners.is_empty() => {
+                vehicle.owners.push(*uid);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn add_owner(&self, vid: &ObjectId, uid: &ObjectId) -> Result<()> {
+        if let Some(vehicle) = self.vehicles.write().unwrap().get_mut(vid) {
+            if !vehicle.owners.contains(uid) {
+                vehicle.owners.push(*uid);
+            }
+        }
+        Ok(())
+    }
+
+    async fn release(&self, vid: &ObjectId, uid: &ObjectId) -> Result<bool> {
+        match self.vehicles.write().unwrap().get_mut(vid) {
+            Some(vehicle) if vehicle.owners.len() > 1 && vehicle.owners.contains(uid) => {
+                vehicle.owners.retain(|owner| owner != uid);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn unclaimed(&self) -> Result<Vec<ObjectId>> {
+        let vehicles = self.vehicles.read().unwrap();
+        Ok(vehicles.values().filter(|vehicle| vehicle.owners.is_empty()).map(|vehicle| vehicle._id).collect())
+    }
+
+    async fn set_secret(&self, vid: &ObjectId, digest: Option<String>) -> Result<bool> {
+        match self.vehicles.write().unwrap().get_mut(vid) {
+            Some(vehicle) => {
+                vehicle.secret = digest;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn update(&self, vid: &ObjectId, company: String, model: String, timezone: Option<String>) -> Result<bool> {
+        match self.vehicles.write().unwrap().get_mut(vid) {
+            Some(vehicle) => {
+                (vehicle.company, vehicle.model) = (company, model);
+                if timezone.is_some() {
+                    vehicle.timezone = timezone;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
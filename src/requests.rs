@@ -0,0 +1,724 @@
+use std::str::FromStr;
+
+use actix_web::{dev::Payload, error::PathError, web, FromRequest, HttpRequest};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use logger::{export::{Data, Format}, fences::Shape, reports::Bucket, Location};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::oid::ObjectId;
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize};
+use types::actors::users::Role;
+
+use crate::errors::ApiError;
+
+// a single problem with a single field of a request body
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+// collects every problem with a request instead of stopping at the first one
+#[derive(Default)]
+pub struct Violations(Vec<FieldError>);
+
+impl Violations {
+    fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.push(FieldError { field, message: message.into() });
+    }
+
+    fn text(&mut self, field: &'static str, value: &str, max: usize) {
+        if value.trim().is_empty() {
+            self.add(field, "can't be empty");
+        } else if value.chars().count() > max {
+            self.add(field, format!("can't be longer than {} characters", max));
+        }
+    }
+
+    fn object_id(&mut self, field: &'static str, value: &str) {
+        if ObjectId::from_str(value).is_err() {
+            self.add(field, "has to be a valid object ID");
+        }
+    }
+
+    fn username(&mut self, value: &str) {
+        let length = value.chars().count();
+        if !(3..=32).contains(&length) {
+            self.add("username", "has to be between 3 and 32 characters long");
+        }
+        if !value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')) {
+            self.add("username", "can only contain letters, digits, '_', '.' and '-'");
+        }
+    }
+
+    // deliberately loose, the only real check for an email address is sending something to it
+    fn email(&mut self, value: &str) {
+        let valid = match value.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+            }
+            None => false,
+        };
+        if !valid || value.len() > 254 || value.chars().any(char::is_whitespace) {
+            self.add("email", "has to be a valid email address");
+        }
+    }
+
+    fn password(&mut self, value: &str) {
+        let length = value.chars().count();
+        if !(8..=128).contains(&length) {
+            self.add("password", "has to be between 8 and 128 characters long");
+        }
+        if !value.chars().any(char::is_alphabetic) || !value.chars().any(|c| c.is_ascii_digit()) {
+            self.add("password", "has to contain at least one letter and one digit");
+        }
+    }
+
+    fn timezone(&mut self, value: &Option<String>) {
+        if matches!(value, Some(value) if value.parse::<Tz>().is_err()) {
+            self.add("timezone", "has to be an IANA timezone like Asia/Kolkata");
+        }
+    }
+
+    fn day(&mut self, field: &'static str, value: &str) -> Option<NaiveDate> {
+        let day = day(value);
+        if day.is_none() {
+            self.add(field, "has to be a date like 2023-04-01");
+        }
+        day
+    }
+
+    fn limit(&mut self, value: Option<i64>, max: i64) {
+        if matches!(value, Some(limit) if !(1..=max).contains(&limit)) {
+            self.add("limit", format!("has to be between 1 and {}", max));
+        }
+    }
+
+    fn bucket(&mut self, value: &Option<String>) {
+        if matches!(value, Some(value) if value.parse::<Bucket>().is_err()) {
+            self.add("group_by", "has to be one of day, week or month");
+        }
+    }
+
+    fn timestamp(&mut self, field: &'static str, value: Option<&String>) {
+        if matches!(value, Some(value) if DateTime::parse_from_rfc3339(value).is_err()) {
+            self.add(field, "has to be an RFC 3339 timestamp");
+        }
+    }
+
+    // latitude & longitude that are actually somewhere on earth
+    fn location(&mut self, field: &'static str, value: &Location) {
+        if !(-90.0..=90.0).contains(&value.latitude) || !(-180.0..=180.0).contains(&value.longitude) {
+            self.add(field, "has to have a latitude between -90 & 90 and a longitude between -180 & 180");
+        }
+    }
+
+    fn finish(self) -> Result<(), ApiError> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(ApiError::Validation(self.0)),
+        }
+    }
+}
+
+// dates are YYYY-MM-DD. Older clients send D-M-YYYY, which is still understood
+fn day(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%d-%m-%Y"))
+        .ok()
+}
+
+// request bodies check their own fields once they're parsed
+pub trait Validate {
+    fn validate(&self) -> Result<(), ApiError>;
+}
+
+// extractor for JSON bodies that are parsed & validated before the route ever sees them
+pub struct Valid<T>(pub T);
+
+impl<T> Valid<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for Valid<T> {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let body = web::Json::<T>::from_request(request, payload);
+        Box::pin(async move {
+            let body = body.await.map_err(|e| ApiError::InvalidRequest(e.to_string()))?.into_inner();
+            body.validate()?;
+            Ok(Valid(body))
+        })
+    }
+}
+
+// same as `Valid`, for query strings
+pub struct ValidQuery<T>(pub T);
+
+impl<T> ValidQuery<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromRequest for ValidQuery<T> {
+    type Error = ApiError;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let query = web::Query::<T>::from_query(request.query_string())
+            .map_err(|e| ApiError::InvalidRequest(e.to_string()))
+            .and_then(|query| {
+                query.validate()?;
+                Ok(ValidQuery(query.into_inner()))
+            });
+        std::future::ready(query)
+    }
+}
+
+// an object ID taken from the URL, used as `Path<Id>`. Older clients wrap ids in quotes, those are
+// dropped before parsing. Anything else that isn't an object ID is rejected by `path_error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Id(pub ObjectId);
+
+impl Id {
+    fn parse(segment: &str) -> Option<ObjectId> {
+        ObjectId::from_str(segment.trim_matches('"')).ok()
+    }
+}
+
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let segment = String::deserialize(deserializer)?;
+        match Id::parse(&segment) {
+            Some(id) => Ok(Id(id)),
+            None => Err(de::Error::custom(format!("{} isn't a valid object ID", segment))),
+        }
+    }
+}
+
+// segments routes use for object IDs, so errors can point at the one that was wrong
+const ID_SEGMENTS: [&str; 3] = ["vid", "uid", "tid"];
+
+// turns a path that failed to extract into an `invalid_id` error instead of actix's plain text one
+pub fn path_error(error: PathError, request: &HttpRequest) -> actix_web::Error {
+    let segment = ID_SEGMENTS
+        .into_iter()
+        .find(|name| matches!(request.match_info().get(name), Some(segment) if Id::parse(segment).is_none()));
+    match segment {
+        Some(segment) => ApiError::InvalidId(segment).into(),
+        None => ApiError::InvalidRequest(error.to_string()).into(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Signup {
+    pub name: String,
+    pub username: String,
+    pub password: String,
+    pub email: String,
+}
+
+impl Validate for Signup {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.text("name", &self.name, 64);
+        violations.username(&self.username);
+        violations.password(&self.password);
+        violations.email(&self.email);
+        violations.finish()
+    }
+}
+
+// either one of username or email identifies the user. The password isn't held to the policy,
+// accounts from before it existed have to be able to log in too
+#[derive(Deserialize)]
+pub struct Login {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub password: String,
+}
+
+impl Validate for Login {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        if self.username.is_none() && self.email.is_none() {
+            violations.add("username", "either username or email is required");
+        }
+        if self.password.is_empty() {
+            violations.add("password", "can't be empty");
+        }
+        violations.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+impl Validate for RefreshToken {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.text("refresh_token", &self.refresh_token, 4096);
+        violations.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Status {
+    pub systemstat: bool,
+}
+
+impl Validate for Status {
+    fn validate(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RegisterVehicle {
+    pub company: String,
+    pub model: String,
+    pub timezone: Option<String>,
+}
+
+impl Validate for RegisterVehicle {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.text("company", &self.company, 64);
+        violations.text("model", &self.model, 64);
+        violations.timezone(&self.timezone);
+        violations.finish()
+    }
+}
+
+// timezone is left as it is when it isn't sent
+#[derive(Deserialize)]
+pub struct EditVehicle {
+    pub vid: String,
+    pub company: String,
+    pub model: String,
+    pub timezone: Option<String>,
+}
+
+impl Validate for EditVehicle {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.text("company", &self.company, 64);
+        violations.text("model", &self.model, 64);
+        violations.timezone(&self.timezone);
+        violations.finish()
+    }
+}
+
+// any route that only needs to know which vehicle it's about
+#[derive(Deserialize)]
+pub struct VehicleId {
+    pub vid: String,
+}
+
+impl Validate for VehicleId {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.finish()
+    }
+}
+
+// the vehicle either proves itself with a signed challenge, or the owner sends their access token instead
+#[derive(Deserialize)]
+pub struct RotateSecret {
+    pub vid: String,
+    pub challenge: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl Validate for RotateSecret {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Revoke {
+    pub vid: String,
+    pub uid: String,
+    pub challenge: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl Validate for Revoke {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.object_id("uid", &self.uid);
+        violations.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangeRole {
+    pub vid: String,
+    pub uid: String,
+    pub role: Role,
+}
+
+impl Validate for ChangeRole {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.object_id("uid", &self.uid);
+        violations.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DailyLogs {
+    pub vid: String,
+    pub date: String,
+}
+
+impl Validate for DailyLogs {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.day("date", &self.date);
+        violations.finish()
+    }
+}
+
+impl DailyLogs {
+    // only call this once the request is validated
+    pub fn day(&self) -> NaiveDate {
+        day(&self.date).unwrap_or_default()
+    }
+}
+
+// group_by buckets the days in the report's series, by day unless it says otherwise
+#[derive(Deserialize)]
+pub struct PeriodicLogs {
+    pub vid: String,
+    pub start: String,
+    pub end: String,
+    pub group_by: Option<String>,
+}
+
+impl Validate for PeriodicLogs {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        let start = violations.day("start", &self.start);
+        let end = violations.day("end", &self.end);
+        if let (Some(start), Some(end)) = (start, end) {
+            if end < start {
+                violations.add("end", "can't be before start");
+            }
+        }
+        violations.bucket(&self.group_by);
+        violations.finish()
+    }
+}
+
+impl PeriodicLogs {
+    // only call this once the request is validated. Both days are included
+    pub fn days(&self) -> (NaiveDate, NaiveDate) {
+        (day(&self.start).unwrap_or_default(), day(&self.end).unwrap_or_default())
+    }
+
+    pub fn bucket(&self) -> Bucket {
+        self.group_by.as_deref().and_then(|bucket| bucket.parse().ok()).unwrap_or(Bucket::Day)
+    }
+}
+
+// everything the vehicle ever logged, bucketed by month unless group_by says otherwise
+#[derive(Deserialize)]
+pub struct OverallLogs {
+    pub vid: String,
+    pub group_by: Option<String>,
+}
+
+impl Validate for OverallLogs {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.bucket(&self.group_by);
+        violations.finish()
+    }
+}
+
+impl OverallLogs {
+    pub fn bucket(&self) -> Bucket {
+        self.group_by.as_deref().and_then(|bucket| bucket.parse().ok()).unwrap_or(Bucket::Month)
+    }
+}
+
+// both days are optional. Without a start the trend goes back to the first day that was logged
+#[derive(Deserialize)]
+pub struct HealthReport {
+    pub vid: String,
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+impl Validate for HealthReport {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        let start = self.start.as_ref().and_then(|start| violations.day("start", start));
+        let end = self.end.as_ref().and_then(|end| violations.day("end", end));
+        if let (Some(start), Some(end)) = (start, end) {
+            if end < start {
+                violations.add("end", "can't be before start");
+            }
+        }
+        violations.finish()
+    }
+}
+
+impl HealthReport {
+    // only call this once the request is validated
+    pub fn days(&self) -> (Option<NaiveDate>, Option<NaiveDate>) {
+        (self.start.as_deref().and_then(day), self.end.as_deref().and_then(day))
+    }
+}
+
+// a query string, since exports are plain downloads. Both days are optional & included, data is
+// daily unless it says otherwise. Without a format the Accept header picks one, CSV if it doesn't either
+#[derive(Deserialize)]
+pub struct ExportLogs {
+    pub vid: String,
+    pub data: Option<String>,
+    pub format: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+impl Validate for ExportLogs {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        if matches!(&self.data, Some(data) if data.parse::<Data>().is_err()) {
+            violations.add("data", "has to be one of daily or telemetry");
+        }
+        if matches!(&self.format, Some(format) if format.parse::<Format>().is_err()) {
+            violations.add("format", "has to be one of csv or ndjson");
+        }
+        let start = self.start.as_ref().and_then(|start| violations.day("start", start));
+        let end = self.end.as_ref().and_then(|end| violations.day("end", end));
+        if let (Some(start), Some(end)) = (start, end) {
+            if end < start {
+                violations.add("end", "can't be before start");
+            }
+        }
+        violations.finish()
+    }
+}
+
+impl ExportLogs {
+    // only call these once the request is validated
+    pub fn days(&self) -> (Option<NaiveDate>, Option<NaiveDate>) {
+        (self.start.as_deref().and_then(day), self.end.as_deref().and_then(day))
+    }
+
+    pub fn data(&self) -> Data {
+        self.data.as_deref().and_then(|data| data.parse().ok()).unwrap_or(Data::Daily)
+    }
+
+    pub fn format(&self, accept: Option<&str>) -> Format {
+        match (self.format.as_deref(), accept) {
+            (Some(format), _) => format.parse().unwrap_or(Format::Csv),
+            (None, Some(accept)) if accept.contains("ndjson") => Format::Ndjson,
+            _ => Format::Csv,
+        }
+    }
+}
+
+// the most points /telemetry/range hands out in one go
+pub const MAX_POINTS: i64 = 10000;
+
+// start & end are RFC 3339 timestamps, like 2023-04-01T09:30:00Z
+#[derive(Deserialize)]
+pub struct TelemetryRange {
+    pub vid: String,
+    pub start: String,
+    pub end: String,
+    pub interval: Option<u64>,
+    pub limit: Option<i64>,
+}
+
+impl TelemetryRange {
+    // only call this once the request is validated
+    pub fn bounds(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        let parse = |value: &str| DateTime::parse_from_rfc3339(value).map(|time| time.with_timezone(&Utc)).unwrap_or_default();
+        (parse(&self.start), parse(&self.end))
+    }
+}
+
+impl Validate for TelemetryRange {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        let start = DateTime::parse_from_rfc3339(&self.start);
+        let end = DateTime::parse_from_rfc3339(&self.end);
+        if start.is_err() {
+            violations.add("start", "has to be an RFC 3339 timestamp");
+        }
+        if end.is_err() {
+            violations.add("end", "has to be an RFC 3339 timestamp");
+        }
+        if let (Ok(start), Ok(end)) = (start, end) {
+            if end <= start {
+                violations.add("end", "has to be after start");
+            }
+        }
+        if let Some(interval) = self.interval {
+            if !(1..=24 * 60 * 60).contains(&interval) {
+                violations.add("interval", "has to be between 1 second and 1 day");
+            }
+        }
+        violations.limit(self.limit, MAX_POINTS);
+        violations.finish()
+    }
+}
+
+// how many trips /trips/list hands out when it isn't told, & the most it does in one go
+pub const DEFAULT_TRIPS: i64 = 20;
+pub const MAX_TRIPS: i64 = 100;
+
+// before is an RFC 3339 timestamp, only trips that started earlier are listed
+#[derive(Deserialize)]
+pub struct TripList {
+    pub vid: String,
+    pub limit: Option<i64>,
+    pub before: Option<String>,
+}
+
+impl TripList {
+    // only call this once the request is validated
+    pub fn before(&self) -> Option<DateTime<Utc>> {
+        let before = DateTime::parse_from_rfc3339(self.before.as_ref()?).ok()?;
+        Some(before.with_timezone(&Utc))
+    }
+}
+
+impl Validate for TripList {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.limit(self.limit, MAX_TRIPS);
+        violations.timestamp("before", self.before.as_ref());
+        violations.finish()
+    }
+}
+
+// the most a route can be simplified by, in meters
+pub const MAX_TOLERANCE: f64 = 1000.0;
+
+// tolerance is in meters, points closer than that to the simplified line are dropped
+#[derive(Deserialize)]
+pub struct RouteOptions {
+    pub tolerance: Option<f64>,
+}
+
+impl Validate for RouteOptions {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        if let Some(tolerance) = self.tolerance {
+            if !(0.0..=MAX_TOLERANCE).contains(&tolerance) {
+                violations.add("tolerance", format!("has to be between 0 and {} meters", MAX_TOLERANCE));
+            }
+        }
+        violations.finish()
+    }
+}
+
+// how big a circular geofence can be, in meters, & how many corners a polygon can have
+pub const MAX_RADIUS: f64 = 100_000.0;
+pub const MAX_CORNERS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct CreateGeofence {
+    pub vid: String,
+    pub name: String,
+    pub shape: Shape,
+}
+
+impl Validate for CreateGeofence {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.text("name", &self.name, 64);
+        match &self.shape {
+            Shape::Circle { center, radius } => {
+                violations.location("shape.center", center);
+                if !(1.0..=MAX_RADIUS).contains(radius) {
+                    violations.add("shape.radius", format!("has to be between 1 and {} meters", MAX_RADIUS));
+                }
+            }
+            Shape::Polygon { corners } => {
+                if !(3..=MAX_CORNERS).contains(&corners.len()) {
+                    violations.add("shape.corners", format!("has to have between 3 and {} corners", MAX_CORNERS));
+                }
+                corners.iter().for_each(|corner| violations.location("shape.corners", corner));
+            }
+        }
+        violations.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteGeofence {
+    pub vid: String,
+    pub fid: String,
+}
+
+impl Validate for DeleteGeofence {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.object_id("fid", &self.fid);
+        violations.finish()
+    }
+}
+
+// how many geofence events /geofences/events hands out when it isn't told, & the most it does in one go
+pub const DEFAULT_EVENTS: i64 = 50;
+pub const MAX_EVENTS: i64 = 500;
+
+// before is an RFC 3339 timestamp, only events from before it are listed
+#[derive(Deserialize)]
+pub struct GeofenceEvents {
+    pub vid: String,
+    pub limit: Option<i64>,
+    pub before: Option<String>,
+}
+
+impl GeofenceEvents {
+    // only call this once the request is validated
+    pub fn before(&self) -> Option<DateTime<Utc>> {
+        let before = DateTime::parse_from_rfc3339(self.before.as_ref()?).ok()?;
+        Some(before.with_timezone(&Utc))
+    }
+}
+
+impl Validate for GeofenceEvents {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        violations.object_id("vid", &self.vid);
+        violations.limit(self.limit, MAX_EVENTS);
+        violations.timestamp("before", self.before.as_ref());
+        violations.finish()
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, Bson, Document},
+    options::FindOptions,
+    Database,
+};
+use types::actors::{users::{Role, User}, vehicles::Vehicle};
+
+use crate::memory::{MemoryUsers, MemoryVehicles};
+
+// whatever went wrong in the store, as text. It only ever ends up in the logs
+#[derive(Debug)]
+pub struct StoreError(pub String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<mongodb::error::Error> for StoreError {
+    fn from(error: mongodb::error::Error) -> Self {
+        StoreError(error.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn get(&self, uid: &ObjectId) -> Result<Option<User>>;
+    // the first user with any of these usernames
+    async fn by_username(&self, usernames: &[String]) -> Result<Option<User>>;
+    // the first user with any of these emails
+    async fn by_email(&self, emails: &[String]) -> Result<Option<User>>;
+    async fn insert(&self, user: User) -> Result<()>;
+    // the password is left alone when there's none
+    async fn update_account(&self, uid: &ObjectId, name: String, username: String, email: String, password: Option<String>) -> Result<()>;
+    // everyone paired to the vehicle, oldest account first
+    async fn paired(&self, vid: &ObjectId) -> Result<Vec<User>>;
+    // newest pairs go first. False when the user was already paired, their role is left alone then
+    async fn pair(&self, uid: &ObjectId, vid: &ObjectId, role: Role) -> Result<bool>;
+    // whether there was a pair to remove
+    async fn unpair(&self, uid: &ObjectId, vid: &ObjectId) -> Result<bool>;
+    // false when the user isn't paired to the vehicle
+    async fn set_role(&self, uid: &ObjectId, vid: &ObjectId, role: Role) -> Result<bool>;
+}
+
+#[async_trait]
+pub trait VehicleStore: Send + Sync {
+    async fn get(&self, vid: &ObjectId) -> Result<Option<Vehicle>>;
+    // without their secrets, vehicles that don't exist are left out
+    async fn list(&self, vids: &[ObjectId]) -> Result<Vec<Vehicle>>;
+    async fn insert(&self, vehicle: Vehicle) -> Result<()>;
+    // makes the user the vehicle's first owner unless someone got there first, in a single write so
+    // two users pairing at once can't both end up owning it. Whether it was theirs to claim
+    async fn claim(&self, vid: &ObjectId, uid: &ObjectId) -> Result<bool>;
+    // vehicles nobody claimed yet
+    async fn unclaimed(&self) -> Result<Vec<ObjectId>>;
+    async fn add_owner(&self, vid: &ObjectId, uid: &ObjectId) -> Result<()>;
+    // takes the user off the vehicle's owners as long as another one stays, in a single write so
+    // owners dropping each other at once can't leave it without any. False when they weren't let go
+    async fn release(&self, vid: &ObjectId, uid: &ObjectId) -> Result<bool>;
+    // none revokes it. False when there's no such vehicle
+    async fn set_secret(&self, vid: &ObjectId, digest: Option<String>) -> Result<bool>;
+    // the timezone is left alone when there's none. False when there's no such vehicle
+    async fn update(&self, vid: &ObjectId, company: String, model: String, timezone: Option<String>) -> Result<bool>;
+}
+
+// the stores the manager works with, picked by database.backend
+#[derive(Clone)]
+pub struct Stores {
+    pub users: Arc<dyn UserStore>,
+    pub vehicles: Arc<dyn VehicleStore>,
+}
+
+impl Stores {
+    pub fn mongodb(database: Database) -> Stores {
+        Stores {
+            users: Arc::new(MongoUsers { database: database.clone() }),
+            vehicles: Arc::new(MongoVehicles { database }),
+        }
+    }
+
+    pub fn memory() -> Stores {
+        Stores {
+            users: Arc::new(MemoryUsers::default()),
+            vehicles: Arc::new(MemoryVehicles::default()),
+        }
+    }
+
+    // vehicles paired before they were claimed go to the owners they already have, or else their
+    // oldest paired account. Ones nobody paired are left for whoever pairs first. How many were claimed
+    pub async fn claim_owners(&self) -> Result<usize> {
+        let mut claimed = 0;
+        for vid in self.vehicles.unclaimed().await? {
+            let users = self.users.paired(&vid).await?;
+            let mut owners: Vec<ObjectId> = users.iter().filter(|user| user.role(&vid) == Some(Role::Owner)).filter_map(|user| user._id).collect();
+            if owners.is_empty() {
+                owners.extend(users.first().and_then(|user| user._id));
+            }
+            let Some((first, rest)) = owners.split_first() else { continue };
+            if self.vehicles.claim(&vid, first).await? {
+                self.users.set_role(first, &vid, Role::Owner).await?;
+                for uid in rest {
+                    self.vehicles.add_owner(&vid, uid).await?;
+                }
+                claimed += 1;
+            }
+        }
+        Ok(claimed)
+    }
+}
+
+pub struct MongoUsers {
+    database: Database,
+}
+
+impl MongoUsers {
+    fn collection(&self) -> mongodb::Collection<User> {
+        self.database.collection::<User>("users")
+    }
+}
+
+#[async_trait]
+impl UserStore for MongoUsers {
+    async fn get(&self, uid: &ObjectId) -> Result<Option<User>> {
+        Ok(self.collection().find_one(doc! {"_id": uid}, None).await?)
+    }
+
+    async fn by_username(&self, usernames: &[String]) -> Result<Option<User>> {
+        Ok(self.collection().find_one(doc! {"username": {"$in": usernames}}, None).await?)
+    }
+
+    async fn by_email(&self, emails: &[String]) -> Result<Option<User>> {
+        Ok(self.collection().find_one(doc! {"email": {"$in": emails}}, None).await?)
+    }
+
+    async fn insert(&self, user: User) -> Result<()> {
+        self.collection().insert_one(user, None).await?;
+        Ok(())
+    }
+
+    async fn update_account(&self, uid: &ObjectId, name: String, username: String, email: String, password: Option<String>) -> Result<()> {
+        let mut update = doc! {"name": name, "username": username, "email": email};
+        if let Some(password) = password {
+            update.insert("password", password);
+        }
+        self.collection().update_one(doc! {"_id": uid}, doc! {"$set": update}, None).await?;
+        Ok(())
+    }
+
+    async fn paired(&self, vid: &ObjectId) -> Result<Vec<User>> {
+        let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+        let mut cursor = self.collection().find(doc! {"vehicles": vid}, options).await?;
+        let mut users = Vec::new();
+        while let Some(user) = cursor.next().await {
+            users.push(user?);
+        }
+        Ok(users)
+    }
+
+    async fn pair(&self, uid: &ObjectId, vid: &ObjectId, role: Role) -> Result<bool> {
+        let result = self.collection().update_one(doc! {"_id": uid, "vehicles": {"$ne": vid}}, doc! {"$push": {"vehicles": {"$each": [vid], "$position": 0}}, "$set": {format!("roles.{}", vid.to_hex()): to_bson(&role).unwrap_or(Bson::Null)}}, None).await?;
+        Ok(result.modified_count > 0)
+    }
+
+    async fn unpair(&self, uid: &ObjectId, vid: &ObjectId) -> Result<bool> {
+        let result = self.collection().update_one(doc! {"_id": uid}, doc! {"$pull": {"vehicles": vid}, "$unset": {format!("roles.{}", vid.to_hex()): ""}}, None).await?;
+        Ok(result.modified_count > 0)
+    }
+
+    async fn set_role(&self, uid: &ObjectId, vid: &ObjectId, role: Role) -> Result<bool> {
+        let result = self.collection().update_one(doc! {"_id": uid, "vehicles": vid}, doc! {"$set": {format!("roles.{}", vid.to_hex()): to_bson(&role).unwrap_or(Bson::Null)}}, None).await?;
+        Ok(result.matched_count > 0)
+    }
+}
+
+pub struct MongoVehicles {
+    database: Database,
+}
+
+impl MongoVehicles {
+    fn collection(&self) -> mongodb::Collection<Vehicle> {
+        self.database.collection::<Vehicle>("vehicles")
+    }
+}
+
+#[async_trait]
+impl VehicleStore for MongoVehicles {
+    async fn get(&self, vid: &ObjectId) -> Result<Option<Vehicle>> {
+        Ok(self.collection().find_one(doc! {"_id": vid}, None).await?)
+    }
+
+    async fn list(&self, vids: &[ObjectId]) -> Result<Vec<Vehicle>> {
+        let options = FindOptions::builder().projection(doc! {"secret": 0}).build();
+        let mut cursor = self.collection().find(doc! {"_id": {"$in": vids}}, options).await?;
+        let mut vehicles = Vec::new();
+        // one vehicle that doesn't parse shouldn't hide the rest
+        while let Some(vehicle) = cursor.next().await {
+            if let Ok(vehicle) = vehicle {
+                vehicles.push(vehicle);
+            }
+        }
+        Ok(vehicles)
+    }
+
+    async fn insert(&self, vehicle: Vehicle) -> Result<()> {
+        self.collection().insert_one(vehicle, None).await?;
+        Ok(())
+    }
+
+    async fn claim(&self, vid: &ObjectId, uid: &ObjectId) -> Result<bool> {
+        let result = self.collection().update_one(doc! {"_id": vid, "owners.0": {"$exists": false}}, doc! {"$set": {"owners": [uid]}}, None).await?;
+        Ok(result.modified_count > 0)
+    }
+
+    async fn add_owner(&self, vid: &ObjectId, uid: &ObjectId) -> Result<()> {
+        self.collection().update_one(doc! {"_id": vid}, doc! {"$addToSet": {"owners": uid}}, None).await?;
+        Ok(())
+    }
+
+    async fn release(&self, vid: &ObjectId, uid: &ObjectId) -> Result<bool> {
+        let result = self.collection().update_one(doc! {"_id": vid, "owners": uid, "owners.1": {"$exists": true}}, doc! {"$pull": {"owners": uid}}, None).await?;
+        Ok(result.modified_count > 0)
+    }
+
+    async fn unclaimed(&self) -> Result<Vec<ObjectId>> {
+        let options = FindOptions::builder().projection(doc! {"_id": 1}).build();
+        let mut cursor = self.database.collection::<Document>("vehicles").find(doc! {"owners.0": {"$exists": false}}, options).await?;
+        let mut vids = Vec::new();
+        while let Some(vehicle) = cursor.next().await {
+            if let Ok(vid) = vehicle?.get_object_id("_id") {
+                vids.push(vid);
+            }
+        }
+        Ok(vids)
+    }
+
+    async fn set_secret(&self, vid: &ObjectId, digest: Option<String>) -> Result<bool> {
+        let update = match digest {
+            Some(digest) => doc! {"$set": {"secret": digest}},
+            None => doc! {"$unset": {"secret": ""}},
+        };
+        let result = self.collection().update_one(doc! {"_id": vid}, update, None).await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn update(&self, vid: &ObjectId, company: String, model: String, timezone: Option<String>) -> Result<bool> {
+        let mut update = doc! {"company": company, "model": model};
+        if let Some(timezone) = timezone {
+            update.insert("timezone", timezone);
+        }
+        let result = self.collection().update_one(doc! {"_id": vid}, doc! {"$set": update}, None).await?;
+        Ok(result.matched_count > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn user(vehicles: Vec<ObjectId>) -> User {
+        User {
+            _id: Some(ObjectId::new()),
+            name: String::from("name"),
+            username: String::from("username"),
+            password: String::new(),
+            email: String::from("user@example.com"),
+            vehicles,
+            roles: HashMap::new(),
+        }
+    }
+
+    fn vehicle() -> Vehicle {
+        Vehicle { _id: ObjectId::new(), company: String::from("Tata"), model: String::from("Nexon"), secret: None, timezone: None, owners: Vec::new() }
+    }
+
+    #[actix_web::test]
+    async fn only_one_of_many_claims_wins() {
+        let stores = Stores::memory();
+        let vehicle = vehicle();
+        stores.vehicles.insert(vehicle.clone()).await.unwrap();
+        let claims = (0..20).map(|_| {
+            let vehicles = Arc::clone(&stores.vehicles);
+            actix_web::rt::spawn(async move { vehicles.claim(&vehicle._id, &ObjectId::new()).await.unwrap() })
+        });
+        let won = futures::future::join_all(claims).await.into_iter().filter(|claimed| *claimed.as_ref().unwrap()).count();
+        assert_eq!(won, 1);
+    }
+
+    // two owners letting each other go at once still leave one of them owning the vehicle
+    #[actix_web::test]
+    async fn the_last_owner_cant_be_released() {
+        let stores = Stores::memory();
+        let vehicle = vehicle();
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        stores.vehicles.insert(vehicle.clone()).await.unwrap();
+        assert!(stores.vehicles.claim(&vehicle._id, &first).await.unwrap());
+        stores.vehicles.add_owner(&vehicle._id, &second).await.unwrap();
+        let releases = [first, second].map(|uid| {
+            let vehicles = Arc::clone(&stores.vehicles);
+            actix_web::rt::spawn(async move { vehicles.release(&vehicle._id, &uid).await.unwrap() })
+        });
+        let released = futures::future::join_all(releases).await.into_iter().filter(|released| *released.as_ref().unwrap()).count();
+        assert_eq!(released, 1);
+        assert_eq!(stores.vehicles.get(&vehicle._id).await.unwrap().unwrap().owners.len(), 1);
+    }
+
+    // pairs from before roles all act as drivers, one of them has to own the vehicle
+    #[actix_web::test]
+    async fn the_oldest_legacy_pair_becomes_the_owner() {
+        let stores = Stores::memory();
+        let (paired, unpaired) = (vehicle(), vehicle());
+        for vehicle in [&paired, &unpaired] {
+            stores.vehicles.insert(vehicle.clone()).await.unwrap();
+        }
+        let (first, second) = (user(vec![paired._id]), user(vec![paired._id]));
+        for user in [&first, &second] {
+            stores.users.insert(user.clone()).await.unwrap();
+        }
+
+        assert_eq!(stores.claim_owners().await.unwrap(), 1);
+        assert_eq!(stores.vehicles.get(&paired._id).await.unwrap().unwrap().owners, vec![first._id.unwrap()]);
+        assert_eq!(stores.users.get(&first._id.unwrap()).await.unwrap().unwrap().role(&paired._id), Some(Role::Owner));
+        assert_eq!(stores.users.get(&second._id.unwrap()).await.unwrap().unwrap().role(&paired._id), Some(Role::Driver));
+        assert!(stores.vehicles.unclaimed().await.unwrap() == vec![unpaired._id]);
+        assert_eq!(stores.claim_owners().await.unwrap(), 0);
+    }
+}
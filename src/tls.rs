@@ -0,0 +1,109 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn load(cert: &str, key: &str) -> io::Result<CertifiedKey> {
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(invalid(format!("{} doesn't contain any PEM encoded certificates", cert)));
+    }
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let key_der = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(der)) | Some(Item::RSAKey(der)) | Some(Item::ECKey(der)) => break der,
+            Some(_) => continue,
+            None => return Err(invalid(format!("{} doesn't contain a PEM encoded private key", key))),
+        }
+    };
+    let signing_key = sign::any_supported_type(&PrivateKey(key_der))
+        .map_err(|_| invalid(format!("{} isn't an RSA, ECDSA or Ed25519 key rustls can use", key)))?;
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+fn modified(cert: &str, key: &str) -> Option<(SystemTime, SystemTime)> {
+    Some((fs::metadata(cert).ok()?.modified().ok()?, fs::metadata(key).ok()?.modified().ok()?))
+}
+
+// hands every handshake whatever certificate was loaded last, so renewed certificates are picked up
+// by new connections while existing ones carry on with the old one
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+impl CertResolver {
+    // fails if the certificate or key can't be loaded, so a broken setup is caught on startup
+    pub fn new(cert: &str, key: &str) -> io::Result<Arc<CertResolver>> {
+        Ok(Arc::new(CertResolver {
+            current: RwLock::new(Arc::new(load(cert, key)?)),
+        }))
+    }
+
+    // polls both files & swaps in the new certificate when either of them changes. A renewal that
+    // fails to load keeps the old certificate around, it'll be retried when the files change again
+    pub fn watch(self: &Arc<Self>, cert: String, key: String, interval: Duration) {
+        let resolver = Arc::clone(self);
+        let mut last = modified(&cert, &key);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let current = modified(&cert, &key);
+            if current.is_none() || current == last {
+                continue;
+            }
+            last = current;
+            match load(&cert, &key) {
+                Ok(certified) => {
+                    *resolver.current.write().unwrap() = Arc::new(certified);
+                    println!("Reloaded the TLS certificate from {}", cert);
+                }
+                Err(e) => eprintln!("Failed to reload the TLS certificate, still using the previous one: {}", e),
+            }
+        });
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>)
+    }
+}
+
+// answers everything on the plain HTTP listener with a permanent redirect to the same path over HTTPS
+pub async fn redirect(request: HttpRequest, port: web::Data<u16>) -> HttpResponse {
+    let info = request.connection_info();
+    // drop the port of the plain listener, if the client sent one
+    let host = match info.host().rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) && (!host.starts_with('[') || host.ends_with(']')) => host,
+        _ => info.host(),
+    };
+    let authority = match **port {
+        443 => host.to_string(),
+        port => format!("{}:{}", host, port),
+    };
+    let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    HttpResponse::PermanentRedirect()
+        .insert_header(("Location", format!("https://{}{}", authority, path)))
+        .finish()
+}
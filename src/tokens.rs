@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use jsonwebtoken::{decode, encode, errors::Error, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ApiError;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Access,
+    Refresh,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    iat: u64,
+    exp: u64,
+    kind: TokenKind,
+}
+
+#[derive(Serialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: u64,
+}
+
+// issues & validates the HS256 signed tokens handed out at login.
+// access tokens are short lived and sent with every request, refresh tokens only buy a new pair
+#[derive(Clone)]
+pub struct Tokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+}
+
+impl Tokens {
+    pub fn new(secret: &[u8], access_ttl: Duration, refresh_ttl: Duration) -> Tokens {
+        Tokens {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            access_ttl,
+            refresh_ttl,
+        }
+    }
+
+    fn sign(&self, uid: &ObjectId, kind: TokenKind, ttl: Duration) -> Result<String, Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let claims = Claims {
+            sub: uid.to_hex(),
+            iat: now,
+            exp: now + ttl.as_secs(),
+            kind,
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+    }
+
+    pub fn issue(&self, uid: &ObjectId) -> Result<TokenPair, Error> {
+        Ok(TokenPair {
+            access_token: self.sign(uid, TokenKind::Access, self.access_ttl)?,
+            refresh_token: self.sign(uid, TokenKind::Refresh, self.refresh_ttl)?,
+            expires_in: self.access_ttl.as_secs(),
+        })
+    }
+
+    // returns the uid the token was issued to, as long as it is intact, unexpired & of the expected kind
+    pub fn validate(&self, token: &str, kind: TokenKind) -> Option<ObjectId> {
+        let data = decode::<Claims>(token, &self.decoding, &Validation::new(Algorithm::HS256)).ok()?;
+        if data.claims.kind != kind {
+            return None;
+        }
+        ObjectId::from_str(&data.claims.sub).ok()
+    }
+}
+
+// extractor for routes that require a logged in user. The access token is read from the
+// Authorization header, or from the access_token query parameter for websocket upgrades
+// since browsers can't set headers on those
+pub struct Authenticated {
+    pub uid: ObjectId,
+}
+
+fn bearer(request: &HttpRequest) -> Option<String> {
+    if let Some(header) = request.headers().get("Authorization") {
+        return header.to_str().ok()?.strip_prefix("Bearer ").map(|token| token.trim().to_string());
+    }
+    web::Query::<HashMap<String, String>>::from_query(request.query_string())
+        .ok()?
+        .get("access_token")
+        .cloned()
+}
+
+impl FromRequest for Authenticated {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let tokens = match request.app_data::<web::Data<Tokens>>() {
+            Some(tokens) => tokens,
+            None => return ready(Err(ApiError::internal("validating an access token", "Tokens are missing from app_data"))),
+        };
+        ready(match bearer(request) {
+            Some(token) => match tokens.validate(&token, TokenKind::Access) {
+                Some(uid) => Ok(Authenticated { uid }),
+                None => Err(ApiError::Unauthenticated("The access token is invalid or has expired. Log in again or refresh it at /token/refresh")),
+            },
+            None => Err(ApiError::Unauthenticated("This route requires an access token. Log in first at /login")),
+        })
+    }
+}
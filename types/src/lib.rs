@@ -2,38 +2,38 @@ pub mod actors {
     pub mod vehicles {
         use mongodb::bson::oid::ObjectId;
         use serde::{Deserialize, Serialize};
-        use serde_json::Value;
 
         #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
         pub struct Vehicle {
             pub _id: ObjectId,
             pub company: String,
-            pub model: String
-        }
-
-        impl Vehicle {
-            pub fn parse_request(request: Value) -> Vehicle {
-                let expected_fields = vec!["model", "company"];
-                let mut values = Vec::new();
-
-                for each in expected_fields {
-                    let value = match serde_json::from_value::<Value>(request[each].clone()) {
-                        Ok(data) => data.to_string(),
-                        Err(_) => "".to_string()
-                    };
-                    values.push(value);
-                }
-
-                Vehicle { _id: ObjectId::new(), company: values[1].clone(), model: values[0].clone() }
-            }
+            pub model: String,
+            // SHA-256 digest of the device secret, sealed with the server's device key. Never sent to clients
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub secret: Option<String>,
+            // IANA name like Asia/Kolkata, the vehicle's logs are split into days by it
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub timezone: Option<String>,
+            // the users who own the vehicle, starting with the one who registered or first paired it.
+            // Everyone pairing after that starts as a viewer. Never emptied once it's claimed
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub owners: Vec<ObjectId>
         }
     }
 
     pub mod users {
-        use std::str::FromStr;
-        use mongodb::bson::{oid::ObjectId, Document, doc};
+        use std::collections::HashMap;
+        use mongodb::bson::{oid::ObjectId, Bson, Document, doc, to_bson};
         use serde::{Deserialize, Serialize};
-        use serde_json::Value;
+
+        // what a paired user is allowed to do with a vehicle
+        #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+        #[serde(rename_all = "lowercase")]
+        pub enum Role {
+            Owner,  // full control, including managing other users' access
+            Driver, // can order the vehicle to perform actions
+            Viewer, // can only watch & request data
+        }
 
         #[derive(Debug, Serialize, Deserialize, Clone)]
         pub struct User {
@@ -43,29 +43,21 @@ pub mod actors {
             pub password: String,
             pub email: String,
             pub vehicles: Vec<ObjectId>,
+            // role on each paired vehicle, keyed by the vid in hex
+            #[serde(default)]
+            pub roles: HashMap<String, Role>,
         }
 
         impl User {
-            pub fn parse_request(request: Value) -> User {
-                let expected_fields = vec!["name", "username", "password", "email"];
-                let mut values = Vec::new();
-
-                for each in expected_fields {
-                    let value = match serde_json::from_value::<Value>(request[each].clone()) {
-                        Ok(data) => data.to_string(),
-                        Err(_) => "".to_string()
-                    };
-                    values.push(value);
-                }
-
-                User {
-                    _id: None,
-                    name: values[0].clone(),
-                    username: values[1].clone(),
-                    password: values[2].clone(),
-                    email: values[3].clone(),
-                    vehicles: Vec::new()
+            // pairs made before roles existed have no entry and keep acting as drivers
+            pub fn role(&self, vid: &ObjectId) -> Option<Role> {
+                if !self.vehicles.contains(vid) {
+                    return None;
                 }
+                Some(*self.roles.get(&vid.to_hex()).unwrap_or(&Role::Driver))
+            }
+            pub fn vehicle_roles(&self) -> HashMap<String, Role> {
+                self.vehicles.iter().filter_map(|vid| self.role(vid).map(|role| (vid.to_hex(), role))).collect()
             }
             pub fn document(self) -> Document {
                 doc!{
@@ -74,17 +66,8 @@ pub mod actors {
                     "username": self.username,
                     "password": self.password,
                     "email": self.email,
-                    "vehicles": self.vehicles
-                }
-            }
-            pub fn parse_id(request: Value) -> ObjectId {
-                match serde_json::from_value::<Value>(request["uid"].clone()) {
-                    Ok(data) => {
-                        if let Some(uid) = data.as_str() {
-                            ObjectId::from_str(uid).unwrap()
-                        } else { ObjectId::new() }
-                    },
-                    Err(_) => ObjectId::new()
+                    "vehicles": self.vehicles,
+                    "roles": to_bson(&self.roles).unwrap_or(Bson::Null)
                 }
             }
         }